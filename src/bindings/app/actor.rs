@@ -0,0 +1,324 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::PathBuf,
+    sync::{mpsc, RwLock},
+    thread,
+    time::Duration,
+};
+use actix_lua::LuaMessage;
+use futures::Future;
+use rlua::prelude::*;
+
+use crate::{hook_message, AppState, LuaAddr};
+
+/// Registry value holding the name of the actor a VM runs in, if it was spawned from Lua
+const SELF_NAME: &str = "actor_name";
+
+const DEFAULT_CALL_TIMEOUT: f64 = 5.0;
+
+#[derive(Clone)]
+enum Target {
+    /// An actor spawned from Lua, whose script gets the messages in an envelope, apart from hooks
+    Script(LuaAddr),
+    /// The web server actor, which gets messages through its `torchbear.on_message` hook
+    App(LuaAddr),
+    /// A name reserved by `spawn` while the VM of the actor is being created
+    Starting,
+}
+
+impl Target {
+    /// The address to send `message` to and the message as the actor expects it,
+    /// `None` while the actor is still starting
+    fn message (&self, message: LuaMessage) -> Option<(&LuaAddr, LuaMessage)> {
+        match self {
+            Target::Script(addr) => {
+                let mut envelope = HashMap::new();
+                envelope.insert("message".to_owned(), message);
+                Some((addr, LuaMessage::Table(envelope)))
+            },
+            Target::App(addr) => Some((addr, hook_message("torchbear.on_message", message))),
+            Target::Starting => None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref ACTORS: RwLock<HashMap<String, Target>> = RwLock::new(HashMap::new());
+    static ref TOPICS: RwLock<HashMap<String, Vec<String>>> = RwLock::new(HashMap::new());
+    /// The app new actors are created from, with their script in place of `init.lua`
+    static ref TEMPLATE: RwLock<Option<AppState>> = RwLock::new(None);
+}
+
+/// Sets the app whose settings and bindings actors spawned from Lua get
+pub fn set_template (state: &AppState) {
+    let mut template = state.clone();
+    template.lua = None;
+    *TEMPLATE.write().unwrap() = Some(template);
+}
+
+/// Makes an app actor reachable from Lua under `name`, through its `torchbear.on_message` hook
+pub fn register_app (name: &str, addr: LuaAddr) {
+    ACTORS.write().unwrap().insert(name.to_owned(), Target::App(addr));
+}
+
+/// Makes `actor.name()` return `name` in `vm`, which also stops the VM from calling itself
+pub fn set_name (vm: &Lua, name: &str) -> crate::Result<()> {
+    vm.context(|lua| lua.set_named_registry_value(SELF_NAME, name))?;
+    Ok(())
+}
+
+fn find (name: &str) -> LuaResult<Target> {
+    match ACTORS.read().unwrap().get(name) {
+        Some(Target::Starting) => Err(LuaError::external(format_err!("actor {} is still starting", name))),
+        Some(target) => Ok(target.clone()),
+        None => Err(LuaError::external(format_err!("no actor named {}", name))),
+    }
+}
+
+fn spawn (name: &str, script: &str) -> crate::Result<()> {
+    // The name is taken right away, creating the VM takes a while
+    match ACTORS.write().unwrap().entry(name.to_owned()) {
+        Entry::Occupied(_) => return Err(format_err!("an actor named {} already exists", name)),
+        Entry::Vacant(entry) => entry.insert(Target::Starting),
+    };
+
+    let addr = start_script(name, script);
+
+    let mut actors = ACTORS.write().unwrap();
+    let reserved = match actors.get(name) {
+        Some(Target::Starting) => true,
+        _ => false,
+    };
+    match (reserved, addr) {
+        (true, Ok(addr)) => {
+            actors.insert(name.to_owned(), Target::Script(addr));
+            Ok(())
+        },
+        (true, Err(err)) => {
+            actors.remove(name);
+            Err(err)
+        },
+        // Stopped while it was starting, dropping the address stops it again
+        (false, addr) => addr.and(Err(format_err!("actor {} was stopped while starting", name))),
+    }
+}
+
+fn start_script (name: &str, script: &str) -> crate::Result<LuaAddr> {
+    let mut state = TEMPLATE.read().unwrap().clone()
+        .ok_or_else(|| format_err!("actors can only be spawned while the web server runs"))?;
+    state.init_path = PathBuf::from(script);
+    if !state.init_path.is_file() {
+        return Err(format_err!("actor script {:?} not found", state.init_path));
+    }
+
+    let vm = state.create_vm()?;
+    set_name(&vm, name)?;
+    Ok(AppState::start_actor_with(vm, include_str!("../../handlers/actor.lua")))
+}
+
+/// Sends `message` and waits for the reply, for at most `timeout`
+///
+/// The reply is awaited on a helper thread, as the event loop of the
+/// calling VM is blocked for as long as the call lasts.
+fn call (target: &Target, message: LuaMessage, timeout: Duration) -> crate::Result<LuaMessage> {
+    let (addr, message) = target.message(message)
+        .ok_or_else(|| format_err!("actor is still starting"))?;
+    let request = addr.send(message);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(request.wait());
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(reply)) => Ok(reply),
+        Ok(Err(err)) => Err(format_err!("actor call failed: {}", err)),
+        Err(_) => Err(format_err!("actor call timed out after {:?}", timeout)),
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        // actor.spawn("mailer", "actors/mailer.lua"), the script returning the function handling messages
+        module.set("spawn", lua.create_function(|_, (name, script): (String, String)| {
+            spawn(&name, &script).map_err(LuaError::external)
+        })?)?;
+
+        module.set("stop", lua.create_function(|_, name: String| {
+            let removed = ACTORS.write().unwrap().remove(&name).is_some();
+            for subscribers in TOPICS.write().unwrap().values_mut() {
+                subscribers.retain(|subscriber| subscriber != &name);
+            }
+            Ok(removed)
+        })?)?;
+
+        module.set("exists", lua.create_function(|_, name: String| {
+            Ok(ACTORS.read().unwrap().contains_key(&name))
+        })?)?;
+
+        module.set("list", lua.create_function(|_, ()| {
+            let mut names: Vec<String> = ACTORS.read().unwrap().keys().cloned().collect();
+            names.sort();
+            Ok(names)
+        })?)?;
+
+        // The name of the actor running this code, nil outside of spawned actors
+        module.set("name", lua.create_function(|lua, ()| {
+            lua.named_registry_value::<_, Option<String>>(SELF_NAME)
+        })?)?;
+
+        module.set("send", lua.create_function(|_, (name, message): (String, LuaMessage)| {
+            if let Some((addr, message)) = find(&name)?.message(message) {
+                addr.do_send(message);
+            }
+            Ok(())
+        })?)?;
+
+        // actor.call("mailer", { to = "..." }, 2) returns the reply, waiting 2 seconds at most
+        module.set("call", lua.create_function(|lua, (name, message, timeout): (String, LuaMessage, Option<f64>)| {
+            if lua.named_registry_value::<_, Option<String>>(SELF_NAME)?.as_ref() == Some(&name) {
+                return Err(LuaError::external(format_err!("actor {} can't call itself", name)));
+            }
+            let timeout = Duration::from_millis((timeout.unwrap_or(DEFAULT_CALL_TIMEOUT).max(0.0) * 1000.0) as u64);
+            call(&find(&name)?, message, timeout).map_err(LuaError::external)
+        })?)?;
+
+        module.set("subscribe", lua.create_function(|_, (topic, name): (String, String)| {
+            find(&name)?;
+            let mut topics = TOPICS.write().unwrap();
+            let subscribers = topics.entry(topic).or_insert_with(Vec::new);
+            if !subscribers.contains(&name) {
+                subscribers.push(name);
+            }
+            Ok(())
+        })?)?;
+
+        module.set("unsubscribe", lua.create_function(|_, (topic, name): (String, String)| {
+            if let Some(subscribers) = TOPICS.write().unwrap().get_mut(&topic) {
+                subscribers.retain(|subscriber| subscriber != &name);
+            }
+            Ok(())
+        })?)?;
+
+        // Subscribers get { topic = topic, message = message }, returns how many there were
+        module.set("publish", lua.create_function(|_, (topic, message): (String, LuaMessage)| {
+            let subscribers = TOPICS.read().unwrap().get(&topic).cloned().unwrap_or_default();
+            let actors = ACTORS.read().unwrap();
+            let mut sent = 0;
+            for name in subscribers.iter() {
+                if let Some(target) = actors.get(name) {
+                    let mut event = HashMap::new();
+                    event.insert("topic".to_owned(), LuaMessage::String(topic.clone()));
+                    event.insert("message".to_owned(), message.clone());
+                    if let Some((addr, event)) = target.message(LuaMessage::Table(event)) {
+                        addr.do_send(event);
+                        sent += 1;
+                    }
+                }
+            }
+            Ok(sent)
+        })?)?;
+
+        lua.globals().set("actor", module)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, sync::Arc};
+    use serde_json::Value;
+
+    #[test]
+    fn lua_actor_errors () {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                assert(actor.name() == nil)
+                assert(not actor.exists("nobody"))
+                assert(not pcall(actor.send, "nobody", "hello"))
+                assert(not pcall(actor.subscribe, "news", "nobody"))
+                assert(actor.publish("news", "nothing") == 0)
+                assert(not pcall(actor.spawn, "worker", "missing.lua"))
+                -- A failed spawn gives its name back
+                assert(not actor.exists("worker"))
+            "#).exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn self_calls_fail_fast () {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        set_name(&lua, "web").unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                assert(actor.name() == "web")
+                local ok, err = pcall(actor.call, "web", "hello", 60)
+                assert(not ok and tostring(err):find("itself"))
+            "#).exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn lua_actor_round_trip () {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("echo.lua");
+        fs::write(&script, r#"
+            local last
+            return function (message)
+                if type(message) == "table" and message.topic then
+                    last = message.topic .. ": " .. tostring(message.message)
+                elseif message == "last" then
+                    return last
+                elseif message == "name" then
+                    return actor.name()
+                else
+                    return { echo = message }
+                end
+            end
+        "#).unwrap();
+
+        let _system = actix::System::new("actors");
+        set_template(&AppState {
+            lua: None,
+            init_path: PathBuf::new(),
+            init_args: vec![],
+            package_path: None,
+            settings: Value::Null,
+            app_settings: None,
+            sites: Arc::new(vec![]),
+        });
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("script", script.to_str().unwrap()).unwrap();
+            lua.load(r#"
+                actor.spawn("echo", script)
+                assert(actor.exists("echo"))
+                assert(not pcall(actor.spawn, "echo", script))
+                assert(actor.call("echo", "name") == "echo")
+
+                -- Messages are handed over as they are, even with a hook key or no table at all
+                local reply = actor.call("echo", { hook = "os.exit", n = 1 })
+                assert(reply.echo.hook == "os.exit" and reply.echo.n == 1)
+                assert(actor.call("echo", 5).echo == 5)
+                assert(actor.call("echo", true).echo == true)
+
+                actor.send("echo", false)
+                actor.subscribe("news", "echo")
+                assert(actor.publish("news", "hello") == 1)
+                assert(actor.call("echo", "last") == "news: hello")
+
+                assert(actor.stop("echo"))
+                assert(not actor.exists("echo"))
+                assert(actor.publish("news", "gone") == 0)
+            "#).exec().unwrap();
+        });
+    }
+}
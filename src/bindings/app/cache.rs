@@ -0,0 +1,392 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use rlua::prelude::*;
+use serde_json::Value;
+
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Registry key of the namespace of the keys of a VM, so that sites don't share entries
+const NAMESPACE: &str = "cache_namespace";
+
+struct Entry {
+    value: Value,
+    expires: Option<Instant>,
+    /// Position in the recently used order
+    used: u64,
+}
+
+impl Entry {
+    fn is_expired (&self, now: Instant) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+}
+
+/// A key-value store holding at most `capacity` entries, dropping the
+/// least recently used ones beyond that
+pub struct Cache {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    capacity: usize,
+    tick: u64,
+}
+
+lazy_static! {
+    /// The cache of the `cache` Lua module, shared by every VM
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::new(DEFAULT_CAPACITY));
+}
+
+/// Sets how many entries the shared cache holds, from the `cache_size` setting
+pub fn set_capacity (capacity: usize) {
+    CACHE.lock().unwrap().set_capacity(capacity);
+}
+
+/// Makes the `cache` module of `lua` use keys of its own, from the `cache_namespace` setting
+/// which every site gets by default
+pub fn set_namespace (lua: &Lua, namespace: &str) -> crate::Result<()> {
+    lua.context(|lua| lua.set_named_registry_value(NAMESPACE, namespace))?;
+    Ok(())
+}
+
+/// The prefix of the keys of the VM, empty for the main app
+fn prefix (lua: LuaContext) -> LuaResult<String> {
+    let namespace: Option<String> = lua.named_registry_value(NAMESPACE)?;
+    Ok(format!("{}\u{0}", namespace.unwrap_or_default()))
+}
+
+impl Cache {
+    pub fn new (capacity: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            capacity: capacity.max(1),
+            tick: 0,
+        }
+    }
+
+    pub fn set_capacity (&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    fn next_tick (&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// The live entry at `key`, marked as just used
+    fn entry (&mut self, key: &str) -> Option<&mut Entry> {
+        let now = Instant::now();
+        if self.entries.get(key).map_or(false, |entry| entry.is_expired(now)) {
+            self.delete(key);
+            return None;
+        }
+
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.used);
+        self.order.insert(tick, key.to_owned());
+        entry.used = tick;
+        Some(entry)
+    }
+
+    fn evict (&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn get (&mut self, key: &str) -> Option<Value> {
+        self.entry(key).map(|entry| entry.value.clone())
+    }
+
+    pub fn set (&mut self, key: &str, value: Value, ttl: Option<Duration>) {
+        self.delete(key);
+        let tick = self.next_tick();
+        self.entries.insert(key.to_owned(), Entry {
+            value,
+            expires: ttl.map(|ttl| Instant::now() + ttl),
+            used: tick,
+        });
+        self.order.insert(tick, key.to_owned());
+        self.evict();
+    }
+
+    pub fn delete (&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.order.remove(&entry.used);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Adds `by` to the number at `key`, starting from 0 with `ttl` when missing
+    pub fn incr (&mut self, key: &str, by: f64, ttl: Option<Duration>) -> Result<f64, String> {
+        if let Some(entry) = self.entry(key) {
+            let current = entry.value.as_f64().ok_or_else(|| format!("cache value at {} is not a number", key))?;
+            let value = current + by;
+            entry.value = number(value);
+            return Ok(value);
+        }
+        self.set(key, number(by), ttl);
+        Ok(by)
+    }
+
+    /// Whether `key` holds `expected`, `None` meaning missing
+    pub fn holds (&mut self, key: &str, expected: Option<&Value>) -> bool {
+        match (self.get(key), expected) {
+            (Some(current), Some(expected)) => same(&current, expected),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Sets `key` to `new` only if it holds `expected`, `None` meaning missing
+    pub fn compare_and_swap (&mut self, key: &str, expected: Option<&Value>, new: Value, ttl: Option<Duration>) -> bool {
+        if !self.holds(key, expected) {
+            return false;
+        }
+        self.set(key, new, ttl);
+        true
+    }
+
+    /// Time until `key` expires, `None` when it is missing or never expires
+    pub fn ttl (&mut self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        self.entry(key)?.expires.map(|expires| expires.duration_since(now))
+    }
+
+    pub fn len (&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The number of keys starting with `prefix`
+    pub fn count (&self, prefix: &str) -> usize {
+        self.entries.keys().filter(|key| key.starts_with(prefix)).count()
+    }
+
+    /// Deletes the keys starting with `prefix`
+    pub fn clear (&mut self, prefix: &str) {
+        let order = &mut self.order;
+        self.entries.retain(|key, entry| {
+            if key.starts_with(prefix) {
+                order.remove(&entry.used);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Compares values with numbers by value, as Lua does, so that 6 and 6.0 are the same
+fn same (a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b)),
+        (Value::Object(a), Value::Object(b)) => a.len() == b.len()
+            && a.iter().all(|(key, a)| b.get(key).map_or(false, |b| same(a, b))),
+        (a, b) => a == b,
+    }
+}
+
+/// Keeps whole numbers as integers, so they come back to Lua as integers
+fn number (value: f64) -> Value {
+    if value.fract() == 0.0 && value.abs() < 9_007_199_254_740_992.0 {
+        Value::from(value as i64)
+    } else {
+        Value::from(value)
+    }
+}
+
+fn ttl_from_lua (ttl: Option<f64>) -> Option<Duration> {
+    ttl.filter(|ttl| *ttl > 0.0).map(|ttl| Duration::from_millis((ttl * 1000.0) as u64))
+}
+
+fn value_from_lua (value: LuaValue) -> LuaResult<Option<Value>> {
+    match value {
+        LuaValue::Nil => Ok(None),
+        value => Ok(Some(rlua_serde::from_value(value)?)),
+    }
+}
+
+fn value_to_lua<'lua> (lua: LuaContext<'lua>, value: Option<Value>) -> LuaResult<LuaValue<'lua>> {
+    match value {
+        Some(value) => Ok(rlua_serde::to_value(lua, &value)?),
+        None => Ok(LuaValue::Nil),
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        module.set("get", lua.create_function(|lua, key: String| {
+            let key = prefix(lua)? + &key;
+            let value = CACHE.lock().unwrap().get(&key);
+            value_to_lua(lua, value)
+        })?)?;
+
+        // cache.set(key, value, ttl) with `ttl` in seconds, setting nil deletes the key
+        module.set("set", lua.create_function(|lua, (key, value, ttl): (String, LuaValue, Option<f64>)| {
+            let key = prefix(lua)? + &key;
+            let value = value_from_lua(value)?;
+            let mut cache = CACHE.lock().unwrap();
+            match value {
+                Some(value) => cache.set(&key, value, ttl_from_lua(ttl)),
+                None => { cache.delete(&key); },
+            }
+            Ok(())
+        })?)?;
+
+        module.set("delete", lua.create_function(|lua, key: String| {
+            let key = prefix(lua)? + &key;
+            Ok(CACHE.lock().unwrap().delete(&key))
+        })?)?;
+
+        module.set("incr", lua.create_function(|lua, (key, by, ttl): (String, Option<f64>, Option<f64>)| {
+            let key = prefix(lua)? + &key;
+            CACHE.lock().unwrap()
+                .incr(&key, by.unwrap_or(1.0), ttl_from_lua(ttl))
+                .map_err(|err| LuaError::external(format_err!("{}", err)))
+        })?)?;
+
+        // cache.cas(key, expected, new, ttl) returns whether `key` held `expected` and was swapped
+        module.set("cas", lua.create_function(|lua, (key, expected, new, ttl): (String, LuaValue, LuaValue, Option<f64>)| {
+            let key = prefix(lua)? + &key;
+            let expected = value_from_lua(expected)?;
+            let new = value_from_lua(new)?;
+            let mut cache = CACHE.lock().unwrap();
+            match new {
+                Some(new) => Ok(cache.compare_and_swap(&key, expected.as_ref(), new, ttl_from_lua(ttl))),
+                None => {
+                    let matches = cache.holds(&key, expected.as_ref());
+                    if matches {
+                        cache.delete(&key);
+                    }
+                    Ok(matches)
+                },
+            }
+        })?)?;
+
+        module.set("ttl", lua.create_function(|lua, key: String| {
+            let key = prefix(lua)? + &key;
+            let ttl = CACHE.lock().unwrap().ttl(&key);
+            Ok(ttl.map(|ttl| ttl.as_secs() as f64 + f64::from(ttl.subsec_millis()) / 1000.0))
+        })?)?;
+
+        module.set("size", lua.create_function(|lua, ()| {
+            let prefix = prefix(lua)?;
+            Ok(CACHE.lock().unwrap().count(&prefix))
+        })?)?;
+
+        module.set("clear", lua.create_function(|lua, ()| {
+            let prefix = prefix(lua)?;
+            CACHE.lock().unwrap().clear(&prefix);
+            Ok(())
+        })?)?;
+
+        lua.globals().set("cache", module)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn least_recently_used () {
+        let mut cache = Cache::new(2);
+        cache.set("a", json!(1), None);
+        cache.set("b", json!(2), None);
+        cache.get("a");
+        cache.set("c", json!(3), None);
+
+        assert_eq!(cache.get("a"), Some(json!(1)));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), Some(json!(3)));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn expiry_and_atomics () {
+        let mut cache = Cache::new(10);
+        cache.set("gone", json!("x"), Some(Duration::from_millis(0)));
+        assert_eq!(cache.get("gone"), None);
+
+        assert_eq!(cache.incr("hits", 1.0, None), Ok(1.0));
+        assert_eq!(cache.incr("hits", 2.0, None), Ok(3.0));
+        assert_eq!(cache.get("hits"), Some(json!(3)));
+        cache.set("name", json!("x"), None);
+        assert!(cache.incr("name", 1.0, None).is_err());
+
+        assert!(!cache.compare_and_swap("hits", Some(&json!(2)), json!(10), None));
+        assert!(cache.compare_and_swap("hits", Some(&json!(3)), json!(10), None));
+        assert!(cache.compare_and_swap("new", None, json!(true), None));
+        assert!(!cache.compare_and_swap("new", None, json!(false), None));
+
+        assert!(cache.compare_and_swap("hits", Some(&json!(10.0)), json!({ "n": 1.0 }), None));
+        assert!(cache.compare_and_swap("hits", Some(&json!({ "n": 1 })), json!([2]), None));
+        assert!(!cache.compare_and_swap("hits", Some(&json!([2.5])), json!(0), None));
+    }
+
+    #[test]
+    fn namespaces () {
+        let site = Lua::new();
+        init(&site).unwrap();
+        set_namespace(&site, "example.com").unwrap();
+        let other = Lua::new();
+        init(&other).unwrap();
+        set_namespace(&other, "other.com").unwrap();
+        let main = Lua::new();
+        init(&main).unwrap();
+
+        site.context(|lua| lua.load(r#"cache.set("ns_user", "site")"#).exec()).unwrap();
+        main.context(|lua| lua.load(r#"assert(cache.get("ns_user") == nil)"#).exec()).unwrap();
+        other.context(|lua| lua.load(r#"
+            assert(cache.get("ns_user") == nil)
+            cache.set("ns_user", "other")
+            cache.clear()
+        "#).exec()).unwrap();
+        site.context(|lua| lua.load(r#"
+            assert(cache.get("ns_user") == "site")
+            assert(cache.size() == 1)
+        "#).exec()).unwrap();
+    }
+
+    #[test]
+    fn lua_cache () {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                cache.set("lua_user", { name = "ana", roles = { "admin" } }, 60)
+                local user = cache.get("lua_user")
+                assert(user.name == "ana" and user.roles[1] == "admin")
+                assert(cache.ttl("lua_user") > 59)
+
+                assert(cache.incr("lua_count") == 1)
+                assert(cache.incr("lua_count", 5) == 6)
+                assert(cache.cas("lua_count", 6, 7))
+                assert(not cache.cas("lua_count", 6, 8))
+                assert(cache.get("lua_count") == 7)
+
+                assert(cache.delete("lua_user"))
+                assert(cache.get("lua_user") == nil)
+            "#).exec().unwrap();
+        });
+    }
+}
@@ -1,6 +1,14 @@
 use rlua::Lua;
 #[cfg(not(target_os = "android"))]
 use git2;
+#[cfg(not(target_os = "android"))]
+pub mod remote;
+#[cfg(not(target_os = "android"))]
+pub mod repo;
+#[cfg(not(target_os = "android"))]
+pub mod signing;
+#[cfg(not(target_os = "android"))]
+pub mod store;
 use rlua::prelude::LuaError;
 
 #[cfg(target_os = "android")]
@@ -70,12 +78,6 @@ fn git_commit(repo: &str, message: &str, _sig: Option<(String, String)>) -> crat
     Ok(())
 }
 
-#[cfg(not(target_os = "android"))]
-fn git_clone(url: &str, into: &str) -> crate::Result<()> {
-    git2::Repository::clone(url, into)?;
-    Ok(())
-}
-
 #[cfg(target_os = "android")]
 fn git_clone(url: &str, into: &str) -> crate::Result<()> {
     Command::new("git")
@@ -87,9 +89,11 @@ fn git_clone(url: &str, into: &str) -> crate::Result<()> {
 #[cfg(not(target_os = "android"))]
 fn git_pull(path: &str, remote_name: &str, branch_name: &str) -> crate::Result<()> {
     let repo = git2::Repository::open(&path)?;
-    repo.find_remote(remote_name)?
-        .fetch(&[branch_name], None, None)?;
-    Ok(())
+    let credentials = remote::Credentials::default();
+    match remote::pull(&repo, remote_name, Some(branch_name), false, &credentials, None)? {
+        remote::Pull::Conflict(paths) => Err(format_err!("merge conflicts in {}", paths.join(", "))),
+        _ => Ok(()),
+    }
 }
 
 #[cfg(target_os = "android")]
@@ -150,19 +154,36 @@ fn git_init(repo: &str) -> crate::Result<()> {
     Ok(())
 }
 
+/// Clones `url` into `into` with the `branch`, `bare` and `credentials` options of `git.clone`
+#[cfg(not(target_os = "android"))]
+fn clone_with_options(url: &str, into: &str, options: Option<&rlua::Table>) -> rlua::Result<git2::Repository> {
+    let credentials = remote::Credentials::from_options(options)?;
+    let (branch, bare) = match options {
+        Some(options) => (
+            options.get::<_, Option<String>>("branch")?,
+            options.get::<_, Option<bool>>("bare")?.unwrap_or(false),
+        ),
+        None => (None, false),
+    };
+    remote::clone(url, std::path::Path::new(into), branch.as_ref().map(String::as_str), bare, &credentials)
+        .map_err(LuaError::external)
+}
+
 pub fn init(lua: &Lua) -> crate::Result<()> {
     lua.context(|lua| {
         let git = lua.create_table()?;
 
         git.set(
             "init",
-            lua.create_function(|_, path: String| Ok(git_init(&path).is_ok()))?,
+            lua.create_function(|_, path: String| {
+                git_init(&path).map(|_| true).map_err(LuaError::external)
+            })?,
         )?;
 
         git.set(
             "add",
             lua.create_function(|_, (repo, paths): (String, Vec<String>)| {
-                Ok(git_add(&repo, &paths).is_ok())
+                git_add(&repo, &paths).map(|_| true).map_err(LuaError::external)
             })?,
         )?;
 
@@ -175,7 +196,7 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
                     } else {
                         None
                     };
-                    Ok(git_commit(&repo, &message, sig).is_ok())
+                    git_commit(&repo, &message, sig).map(|_| true).map_err(LuaError::external)
                 },
             )?,
         )?;
@@ -185,36 +206,25 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
             git.set(
             "log",
             lua.create_function(|lua, repo: String| {
-                let repo = match git2::Repository::open(&repo) {
-                    Ok(repo) => repo,
-                    Err(_) => return Ok(None),
-                };
-                let mut walk = match repo.revwalk() {
-                    Ok(walk) => walk,
-                    Err(_) => return Ok(None),
-                };
-                if walk.push_head().is_ok() {
-                    let walk = walk.filter_map(|x| match x {
-                        Err(_) => None,
-                        Ok(id) => match repo.find_commit(id) {
-                            Err(_) => None,
-                            Ok(commit) => {
-                                let table = lua.create_table().unwrap();
-                                table.set("id", format!("{}", commit.id())).unwrap();
-                                table
-                                    .set("message", commit.message().unwrap_or(""))
-                                    .unwrap();
-                                Some(table)
-                            }
-                        },
-                    });
-                    Ok(Some(walk.collect::<Vec<_>>()))
-                } else {
-                    Ok(Some(Vec::new()))
+                let repo = git2::Repository::open(&repo).map_err(LuaError::external)?;
+                let mut walk = repo.revwalk().map_err(LuaError::external)?;
+                if walk.push_head().is_err() {
+                    // No commits yet
+                    return Ok(Vec::new());
                 }
+                let mut log = Vec::new();
+                for id in walk {
+                    let commit = id.and_then(|id| repo.find_commit(id)).map_err(LuaError::external)?;
+                    let table = lua.create_table()?;
+                    table.set("id", format!("{}", commit.id()))?;
+                    table.set("message", commit.message().unwrap_or(""))?;
+                    log.push(table);
+                }
+                Ok(log)
             })?,
         )?;
 
+        #[cfg(target_os = "android")]
         git.set(
             "clone",
             lua.create_function(|_, (url, into): (String, String)| {
@@ -222,6 +232,24 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
             })?,
         )?;
 
+        // git.clone(url, into, { branch = "main", bare = false, credentials = { ... } })
+        #[cfg(not(target_os = "android"))]
+        git.set(
+            "clone",
+            lua.create_function(|_, (url, into, options): (String, String, Option<rlua::Table>)| {
+                clone_with_options(&url, &into, options.as_ref()).map(|_| ())
+            })?,
+        )?;
+
+        // Like git.clone but returns the repository object
+        #[cfg(not(target_os = "android"))]
+        git.set(
+            "clone_repo",
+            lua.create_function(|_, (url, into, options): (String, String, Option<rlua::Table>)| {
+                clone_with_options(&url, &into, options.as_ref()).map(repo::LuaRepository)
+            })?,
+        )?;
+
         git.set(
             "pull",
             lua.create_function(|_, (path, remote_name, branch_name): (String, String, String)| {
@@ -236,6 +264,13 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
             })?,
         )?;
 
+        #[cfg(not(target_os = "android"))]
+        repo::register(lua, &git)?;
+        #[cfg(not(target_os = "android"))]
+        store::register(lua, &git)?;
+        #[cfg(not(target_os = "android"))]
+        signing::register(lua, &git)?;
+
         let globals = lua.globals();
         globals.set("git", git)?;
         Ok(())
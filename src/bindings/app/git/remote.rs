@@ -0,0 +1,473 @@
+use std::{
+    cell::{Cell, RefCell},
+    path::Path,
+};
+use git2::{
+    self, AnnotatedCommit, Cred, CredentialType, FetchOptions, Oid, PushOptions, RemoteCallbacks,
+    Repository, ResetType, Signature, Status, StatusOptions,
+};
+use rlua::prelude::*;
+
+use super::repo::{find_commit, signature_from_lua, LuaRepository};
+
+/// How many times a remote may ask for credentials before giving up,
+/// as libgit2 keeps asking for as long as the credentials are refused
+const MAX_CREDENTIAL_ATTEMPTS: usize = 3;
+
+/// Credentials for remotes, from the `credentials` option of the remote functions:
+/// `{ username, ssh_key, ssh_public_key, passphrase, token, password, callback }`
+#[derive(Default)]
+pub struct Credentials<'lua> {
+    username: Option<String>,
+    ssh_key: Option<String>,
+    ssh_public_key: Option<String>,
+    passphrase: Option<String>,
+    token: Option<String>,
+    password: Option<String>,
+    /// Called with the url, the username from the url and the allowed kinds of
+    /// credentials, returning a table with the fields above
+    callback: Option<LuaFunction<'lua>>,
+}
+
+impl<'lua> Credentials<'lua> {
+    fn from_table (table: &LuaTable<'lua>) -> LuaResult<Self> {
+        Ok(Credentials {
+            username: table.get("username")?,
+            ssh_key: table.get("ssh_key")?,
+            ssh_public_key: table.get("ssh_public_key")?,
+            passphrase: table.get("passphrase")?,
+            token: table.get("token")?,
+            password: table.get("password")?,
+            callback: table.get("callback")?,
+        })
+    }
+
+    /// Reads the `credentials` field of `options`
+    pub fn from_options (options: Option<&LuaTable<'lua>>) -> LuaResult<Self> {
+        match options {
+            Some(options) => match options.get::<_, Option<LuaTable>>("credentials")? {
+                Some(table) => Credentials::from_table(&table),
+                None => Ok(Credentials::default()),
+            },
+            None => Ok(Credentials::default()),
+        }
+    }
+
+    fn cred (&self, url: &str, username: Option<&str>, allowed: CredentialType) -> Result<Cred, git2::Error> {
+        if let Some(ref callback) = self.callback {
+            let mut kinds = vec![];
+            if allowed.contains(CredentialType::SSH_KEY) { kinds.push("ssh_key") }
+            if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) { kinds.push("password") }
+            if allowed.contains(CredentialType::DEFAULT) { kinds.push("default") }
+            let table: LuaTable = callback.call((url, username, kinds))
+                .map_err(|err| git2::Error::from_str(&format!("credentials callback failed: {}", err)))?;
+            let credentials = Credentials::from_table(&table)
+                .map_err(|err| git2::Error::from_str(&format!("invalid credentials: {}", err)))?;
+            return credentials.cred(url, username, allowed);
+        }
+
+        let username = self.username.as_ref().map(String::as_str).or(username).unwrap_or("git");
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            return match self.ssh_key {
+                Some(ref key) => Cred::ssh_key(
+                    username,
+                    self.ssh_public_key.as_ref().map(Path::new),
+                    Path::new(key),
+                    self.passphrase.as_ref().map(String::as_str),
+                ),
+                None => Cred::ssh_key_from_agent(username),
+            };
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(secret) = self.token.as_ref().or_else(|| self.password.as_ref()) {
+                return Cred::userpass_plaintext(username, secret);
+            }
+        }
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+        Err(git2::Error::from_str(&format!("no credentials for {}", url)))
+    }
+
+    pub fn callbacks<'a> (&'a self) -> RemoteCallbacks<'a> {
+        let attempts = Cell::new(0);
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |url, username, allowed| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() > MAX_CREDENTIAL_ATTEMPTS {
+                return Err(git2::Error::from_str(&format!("authentication failed for {}", url)));
+            }
+            self.cred(url, username, allowed)
+        });
+        callbacks
+    }
+
+    fn fetch_options<'a> (&'a self) -> FetchOptions<'a> {
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(self.callbacks());
+        options
+    }
+}
+
+pub enum Pull {
+    UpToDate,
+    FastForward(Oid),
+    Merged(Oid),
+    Rebased(Oid),
+    Conflict(Vec<String>),
+}
+
+/// The branch HEAD points at, even before its first commit
+pub fn current_branch (repo: &Repository) -> Result<String, git2::Error> {
+    let head = repo.find_reference("HEAD")?;
+    head.symbolic_target()
+        .and_then(|target| target.get("refs/heads/".len()..))
+        .map(String::from)
+        .ok_or_else(|| git2::Error::from_str("HEAD is not on a branch"))
+}
+
+fn conflicts (repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses.iter()
+        .filter(|entry| entry.status().contains(Status::CONFLICTED))
+        .filter_map(|entry| entry.path().map(String::from))
+        .collect())
+}
+
+pub fn fetch (repo: &Repository, remote: &str, refspecs: &[&str], credentials: &Credentials) -> Result<(), git2::Error> {
+    repo.find_remote(remote)?.fetch(refspecs, Some(&mut credentials.fetch_options()), None)
+}
+
+/// Moves `branch` to `theirs`, refusing like git does when that would overwrite local changes
+fn fast_forward (repo: &Repository, branch: &str, theirs: &AnnotatedCommit) -> Result<Oid, git2::Error> {
+    let name = format!("refs/heads/{}", branch);
+    let message = format!("pull: fast-forward to {}", theirs.id());
+
+    // The work tree is updated first, so the branch stays put if it can't be
+    let target = repo.find_commit(theirs.id())?;
+    repo.checkout_tree(target.as_object(), Some(git2::build::CheckoutBuilder::new().safe()))
+        .map_err(|err| match err.code() {
+            git2::ErrorCode::Conflict => git2::Error::from_str(&format!(
+                "pull would overwrite local changes, commit or discard them first ({})", err.message()
+            )),
+            _ => err,
+        })?;
+
+    match repo.find_reference(&name) {
+        Ok(mut reference) => { reference.set_target(theirs.id(), &message)?; },
+        Err(_) => { repo.reference(&name, theirs.id(), true, &message)?; },
+    }
+    repo.set_head(&name)?;
+    Ok(theirs.id())
+}
+
+fn merge (repo: &Repository, theirs: &AnnotatedCommit, message: &str, signature: &Signature) -> Result<Pull, git2::Error> {
+    repo.merge(&[theirs], None, None)?;
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        // Left in progress like git does, see `abort_merge`
+        return Ok(Pull::Conflict(conflicts(repo)?));
+    }
+
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let ours = repo.head()?.peel_to_commit()?;
+    let theirs = repo.find_commit(theirs.id())?;
+    let id = repo.commit(Some("HEAD"), signature, signature, message, &tree, &[&ours, &theirs])?;
+    repo.cleanup_state()?;
+    Ok(Pull::Merged(id))
+}
+
+fn rebase (repo: &Repository, theirs: &AnnotatedCommit, signature: &Signature) -> Result<Pull, git2::Error> {
+    let ours = repo.reference_to_annotated_commit(&repo.head()?)?;
+    let mut rebase = repo.rebase(Some(&ours), Some(theirs), None, None)?;
+    while let Some(operation) = rebase.next() {
+        let commit = repo.find_commit(operation?.id())?;
+        if repo.index()?.has_conflicts() {
+            let conflicts = conflicts(repo)?;
+            rebase.abort()?;
+            return Ok(Pull::Conflict(conflicts));
+        }
+        match rebase.commit(&commit.author(), signature, commit.message().unwrap_or("")) {
+            Ok(_) => (),
+            // The remote has the same change already
+            Err(ref err) if err.code() == git2::ErrorCode::Applied => (),
+            Err(err) => return Err(err),
+        }
+    }
+    rebase.finish(signature)?;
+    Ok(Pull::Rebased(repo.head()?.peel_to_commit()?.id()))
+}
+
+/// Fetches `branch` of `remote` and brings the current branch up to date with it,
+/// fast-forwarding when possible and merging or rebasing otherwise
+pub fn pull (repo: &Repository, remote: &str, branch: Option<&str>, rebase_onto: bool, credentials: &Credentials, signature: Option<Signature>) -> Result<Pull, git2::Error> {
+    let current = current_branch(repo)?;
+    let branch = branch.unwrap_or(current.as_str());
+    fetch(repo, remote, &[], credentials)?;
+
+    let tracking = repo.find_reference(&format!("refs/remotes/{}/{}", remote, branch))?;
+    let theirs = repo.reference_to_annotated_commit(&tracking)?;
+    let (analysis, _) = repo.merge_analysis(&[&theirs])?;
+
+    if analysis.is_up_to_date() {
+        return Ok(Pull::UpToDate);
+    }
+    if analysis.is_fast_forward() || analysis.is_unborn() {
+        return fast_forward(repo, &current, &theirs).map(Pull::FastForward);
+    }
+
+    let signature = match signature {
+        Some(signature) => signature,
+        None => repo.signature()?,
+    };
+    if rebase_onto {
+        rebase(repo, &theirs, &signature)
+    } else {
+        let url = repo.find_remote(remote)?.url().unwrap_or(remote).to_owned();
+        merge(repo, &theirs, &format!("Merge branch '{}' of {}", branch, url), &signature)
+    }
+}
+
+/// Pushes `refspecs`, failing when the remote rejects any of them
+pub fn push (repo: &Repository, remote: &str, refspecs: &[&str], credentials: &Credentials) -> Result<(), git2::Error> {
+    let rejected = RefCell::new(vec![]);
+    {
+        let mut callbacks = credentials.callbacks();
+        callbacks.push_update_reference(|name, status| {
+            if let Some(status) = status {
+                rejected.borrow_mut().push(format!("{} ({})", name, status));
+            }
+            Ok(())
+        });
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        repo.find_remote(remote)?.push(refspecs, Some(&mut options))?;
+    }
+
+    let rejected = rejected.into_inner();
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(git2::Error::from_str(&format!("push rejected for {}", rejected.join(", "))))
+    }
+}
+
+pub fn clone (url: &str, into: &Path, branch: Option<&str>, bare: bool, credentials: &Credentials) -> Result<Repository, git2::Error> {
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.bare(bare).fetch_options(credentials.fetch_options());
+    if let Some(branch) = branch {
+        builder.branch(branch);
+    }
+    builder.clone(url, into)
+}
+
+fn pull_to_lua<'lua> (lua: LuaContext<'lua>, pull: Pull) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    let (status, id) = match pull {
+        Pull::UpToDate => ("up_to_date", None),
+        Pull::FastForward(id) => ("fast_forward", Some(id)),
+        Pull::Merged(id) => ("merged", Some(id)),
+        Pull::Rebased(id) => ("rebased", Some(id)),
+        Pull::Conflict(paths) => {
+            table.set("conflicts", paths)?;
+            ("conflict", None)
+        },
+    };
+    table.set("status", status)?;
+    table.set("id", id.map(|id| id.to_string()))?;
+    Ok(table)
+}
+
+fn git_err (err: git2::Error) -> LuaError {
+    LuaError::external(err)
+}
+
+pub fn add_methods<'lua, M: LuaUserDataMethods<'lua, LuaRepository>> (methods: &mut M) {
+    methods.add_method("remotes", |lua, this, ()| {
+        let result = lua.create_table()?;
+        for (i, name) in this.0.remotes().map_err(git_err)?.iter().filter_map(|name| name).enumerate() {
+            let remote = this.0.find_remote(name).map_err(git_err)?;
+            let table = lua.create_table()?;
+            table.set("name", name)?;
+            table.set("url", remote.url())?;
+            result.set(i + 1, table)?;
+        }
+        Ok(result)
+    });
+
+    methods.add_method("add_remote", |_, this, (name, url): (String, String)| {
+        this.0.remote(&name, &url).map(|_| ()).map_err(git_err)
+    });
+
+    // repo:fetch("origin", { refspecs = { "main" }, credentials = { token = "..." } })
+    methods.add_method("fetch", |_, this, (remote, options): (Option<String>, Option<LuaTable>)| {
+        let credentials = Credentials::from_options(options.as_ref())?;
+        let refspecs: Vec<String> = match options {
+            Some(ref options) => options.get::<_, Option<Vec<String>>>("refspecs")?.unwrap_or_default(),
+            None => vec![],
+        };
+        let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+        fetch(&this.0, remote.as_ref().map_or("origin", String::as_str), &refspecs, &credentials).map_err(git_err)
+    });
+
+    // repo:pull({ remote = "origin", branch = "main", rebase = false, author = { name, email } })
+    // returns { status = "up_to_date" | "fast_forward" | "merged" | "rebased" | "conflict", id, conflicts }
+    methods.add_method("pull", |lua, this, options: Option<LuaTable>| {
+        let credentials = Credentials::from_options(options.as_ref())?;
+        let (remote, branch, rebase, author) = match options {
+            Some(ref options) => (
+                options.get::<_, Option<String>>("remote")?,
+                options.get::<_, Option<String>>("branch")?,
+                options.get::<_, Option<bool>>("rebase")?.unwrap_or(false),
+                options.get::<_, Option<LuaTable>>("author")?,
+            ),
+            None => (None, None, false, None),
+        };
+        let signature = match author {
+            Some(author) => Some(signature_from_lua(&this.0, Some(author))?),
+            None => None,
+        };
+        let pull = pull(
+            &this.0,
+            remote.as_ref().map_or("origin", String::as_str),
+            branch.as_ref().map(String::as_str),
+            rebase,
+            &credentials,
+            signature,
+        ).map_err(git_err)?;
+        pull_to_lua(lua, pull)
+    });
+
+    // Gives up a merge left with conflicts, back to the last commit
+    methods.add_method("abort_merge", |_, this, ()| {
+        let head = find_commit(&this.0, "HEAD").map_err(git_err)?;
+        this.0.reset(head.as_object(), ResetType::Hard, None).map_err(git_err)?;
+        this.0.cleanup_state().map_err(git_err)
+    });
+
+    // repo:push({ remote = "origin", refspecs = { "refs/heads/main" } }), the current branch by default
+    methods.add_method("push", |_, this, options: Option<LuaTable>| {
+        let credentials = Credentials::from_options(options.as_ref())?;
+        let (remote, refspecs) = match options {
+            Some(ref options) => (
+                options.get::<_, Option<String>>("remote")?,
+                options.get::<_, Option<Vec<String>>>("refspecs")?,
+            ),
+            None => (None, None),
+        };
+        let refspecs = match refspecs {
+            Some(refspecs) => refspecs,
+            None => {
+                let branch = current_branch(&this.0).map_err(git_err)?;
+                vec![format!("refs/heads/{}:refs/heads/{}", branch, branch)]
+            },
+        };
+        let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+        push(&this.0, remote.as_ref().map_or("origin", String::as_str), &refspecs, &credentials).map_err(git_err)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use rlua::Lua;
+
+    #[test]
+    fn lua_pull_and_push () {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        super::super::init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local me = { name = "user", email = "user@example.com" }
+                local function write (repo, name, content)
+                    local file = io.open(repo:workdir() .. name, "w")
+                    file:write(content)
+                    file:close()
+                    repo:add({ name })
+                end
+
+                git.create(dir .. "/origin.git", { bare = true })
+                local a = git.create(dir .. "/a")
+                a:add_remote("origin", dir .. "/origin.git")
+                write(a, "notes.md", "one\n")
+                a:commit("first", { author = me })
+                a:push()
+
+                assert(git.clone(dir .. "/origin.git", dir .. "/plain") == nil)
+                local b = git.clone_repo(dir .. "/origin.git", dir .. "/b")
+                assert(b:pull().status == "up_to_date")
+
+                write(a, "notes.md", "two\n")
+                local second = a:commit("second", { author = me })
+                a:push()
+                local pulled = b:pull()
+                assert(pulled.status == "fast_forward" and pulled.id == second)
+
+                write(a, "a.md", "a\n")
+                a:commit("from a", { author = me })
+                a:push()
+                write(b, "b.md", "b\n")
+                b:commit("from b", { author = me })
+                assert(not pcall(b.push, b))
+                assert(b:pull({ author = me }).status == "merged")
+                b:push()
+                assert(a:pull().status == "fast_forward")
+
+                write(a, "notes.md", "three\n")
+                a:commit("three", { author = me })
+                a:push()
+                write(b, "notes.md", "four\n")
+                b:commit("four", { author = me })
+                local conflict = b:pull({ author = me })
+                assert(conflict.status == "conflict" and conflict.conflicts[1] == "notes.md")
+                b:abort_merge()
+                assert(b:status()[1] == nil)
+
+                git.reset(dir .. "/b", "origin/master", "hard")
+                write(b, "c.md", "c\n")
+                b:commit("from b again", { author = me })
+                write(a, "d.md", "d\n")
+                local last = a:commit("from a again", { author = me })
+                a:push()
+                assert(b:pull({ author = me, rebase = true }).status == "rebased")
+                local log = b:log({ limit = 2 })
+                assert(log[1].summary == "from b again" and log[2].id == last)
+                b:push()
+
+                -- A fast-forward keeps local edits and refuses to overwrite them
+                local function edit (repo, name, content)
+                    local file = io.open(repo:workdir() .. name, "w")
+                    file:write(content)
+                    file:close()
+                end
+                local function read (repo, name)
+                    local file = io.open(repo:workdir() .. name)
+                    local content = file:read("a")
+                    file:close()
+                    return content
+                end
+                assert(a:pull().status == "fast_forward")
+                write(a, "notes.md", "five\n")
+                a:commit("five", { author = me })
+                a:push()
+
+                edit(b, "notes.md", "local edit\n")
+                local ok, err = pcall(b.pull, b, { author = me })
+                assert(not ok and tostring(err):find("local changes"))
+                assert(read(b, "notes.md") == "local edit\n")
+                assert(b:log({ limit = 1 })[1].summary == "from b again")
+
+                git.reset(dir .. "/b", "HEAD", "hard")
+                edit(b, "c.md", "c, edited\n")
+                assert(b:pull().status == "fast_forward")
+                assert(read(b, "notes.md") == "five\n" and read(b, "c.md") == "c, edited\n")
+            "#).exec().unwrap();
+        });
+    }
+}
@@ -0,0 +1,550 @@
+use std::path::Path;
+use git2::{
+    self, BlameOptions, BranchType, Commit, Delta, Diff, DiffFormat, DiffOptions, Oid, Patch,
+    Repository, Signature, Sort, Status, StatusOptions, Tree,
+};
+use rlua::prelude::*;
+use sodiumoxide::crypto::sign::SecretKey;
+
+use super::signing;
+
+/// A repository opened from Lua with `git.open` or `git.create`
+pub struct LuaRepository(pub Repository);
+
+fn git_err (err: git2::Error) -> LuaError {
+    LuaError::external(err)
+}
+
+pub fn signature_to_lua<'lua> (lua: LuaContext<'lua>, signature: &Signature) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    table.set("name", lua.create_string(signature.name_bytes())?)?;
+    table.set("email", lua.create_string(signature.email_bytes())?)?;
+    table.set("time", signature.when().seconds())?;
+    table.set("offset", signature.when().offset_minutes())?;
+    Ok(table)
+}
+
+/// Reads `{ name = ..., email = ... }`, falling back on the user of the repository config
+pub fn signature_from_lua (repo: &Repository, table: Option<LuaTable>) -> LuaResult<Signature<'static>> {
+    let signature = match table {
+        Some(table) => Signature::now(&table.get::<_, String>("name")?, &table.get::<_, String>("email")?),
+        None => repo.signature(),
+    };
+    signature.map_err(git_err)
+}
+
+pub fn commit_to_lua<'lua> (lua: LuaContext<'lua>, commit: &Commit) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    table.set("id", commit.id().to_string())?;
+    table.set("message", lua.create_string(commit.message_bytes())?)?;
+    table.set("summary", commit.summary().unwrap_or(""))?;
+    table.set("time", commit.time().seconds())?;
+    table.set("author", signature_to_lua(lua, &commit.author())?)?;
+    table.set("committer", signature_to_lua(lua, &commit.committer())?)?;
+    table.set("parents", commit.parent_ids().map(|id| id.to_string()).collect::<Vec<_>>())?;
+    Ok(table)
+}
+
+/// The commit a revision such as `HEAD~2`, `v1.0` or an id points at
+pub fn find_commit<'r> (repo: &'r Repository, revision: &str) -> Result<Commit<'r>, git2::Error> {
+    repo.revparse_single(revision)?.peel_to_commit()
+}
+
+fn find_tree<'r> (repo: &'r Repository, revision: &str) -> Result<Tree<'r>, git2::Error> {
+    repo.revparse_single(revision)?.peel_to_tree()
+}
+
+/// Whether `commit` changed `path` compared to all of its parents
+fn touches (commit: &Commit, path: &Path) -> Result<bool, git2::Error> {
+    let id = commit.tree()?.get_path(path).ok().map(|entry| entry.id());
+    if commit.parent_count() == 0 {
+        return Ok(id.is_some());
+    }
+    for parent in commit.parents() {
+        if parent.tree()?.get_path(path).ok().map(|entry| entry.id()) == id {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Commits the index of `repo` on top of HEAD, if there is one, signed with `key` when given
+pub fn commit_index (repo: &Repository, message: &str, author: &Signature, committer: &Signature, key: Option<&SecretKey>) -> Result<Oid, git2::Error> {
+    let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+    match repo.head().ok().and_then(|head| head.target()) {
+        Some(head) => {
+            let parent = repo.find_commit(head)?;
+            signing::commit(repo, "HEAD", author, committer, message, &tree, &[&parent], key)
+        },
+        None => signing::commit(repo, "HEAD", author, committer, message, &tree, &[], key),
+    }
+}
+
+fn index_state (status: Status) -> Option<&'static str> {
+    if status.contains(Status::INDEX_NEW) { Some("new") }
+    else if status.contains(Status::INDEX_MODIFIED) { Some("modified") }
+    else if status.contains(Status::INDEX_DELETED) { Some("deleted") }
+    else if status.contains(Status::INDEX_RENAMED) { Some("renamed") }
+    else if status.contains(Status::INDEX_TYPECHANGE) { Some("typechange") }
+    else { None }
+}
+
+fn worktree_state (status: Status) -> Option<&'static str> {
+    if status.contains(Status::WT_NEW) { Some("new") }
+    else if status.contains(Status::WT_MODIFIED) { Some("modified") }
+    else if status.contains(Status::WT_DELETED) { Some("deleted") }
+    else if status.contains(Status::WT_RENAMED) { Some("renamed") }
+    else if status.contains(Status::WT_TYPECHANGE) { Some("typechange") }
+    else { None }
+}
+
+pub fn delta_name (delta: Delta) -> &'static str {
+    match delta {
+        Delta::Unmodified => "unmodified",
+        Delta::Added => "added",
+        Delta::Deleted => "deleted",
+        Delta::Modified => "modified",
+        Delta::Renamed => "renamed",
+        Delta::Copied => "copied",
+        Delta::Ignored => "ignored",
+        Delta::Untracked => "untracked",
+        Delta::Typechange => "typechange",
+        Delta::Unreadable => "unreadable",
+        Delta::Conflicted => "conflicted",
+    }
+}
+
+/// Diff between two revisions, `to` being the working tree and index when missing
+/// and `from` being HEAD when missing
+fn diff<'r> (repo: &'r Repository, from: Option<&str>, to: Option<&str>, options: &mut DiffOptions) -> Result<Diff<'r>, git2::Error> {
+    let old = match from {
+        Some(from) => Some(find_tree(repo, from)?),
+        None => repo.head().ok().and_then(|head| head.peel_to_tree().ok()),
+    };
+    match to {
+        Some(to) => repo.diff_tree_to_tree(old.as_ref(), Some(&find_tree(repo, to)?), Some(options)),
+        None => repo.diff_tree_to_workdir_with_index(old.as_ref(), Some(options)),
+    }
+}
+
+/// `{ text = patch, files = { { old_path, new_path, status, binary, hunks = { ... } } } }`
+fn diff_to_lua<'lua> (lua: LuaContext<'lua>, diff: &Diff) -> LuaResult<LuaTable<'lua>> {
+    let mut text = vec![];
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        if let '+' | '-' | ' ' = line.origin() {
+            text.push(line.origin() as u8);
+        }
+        text.extend_from_slice(line.content());
+        true
+    }).map_err(git_err)?;
+
+    let files = lua.create_table()?;
+    for (index, delta) in diff.deltas().enumerate() {
+        let file = lua.create_table()?;
+        file.set("old_path", delta.old_file().path().and_then(Path::to_str))?;
+        file.set("new_path", delta.new_file().path().and_then(Path::to_str))?;
+        file.set("status", delta_name(delta.status()))?;
+
+        let hunks = lua.create_table()?;
+        match Patch::from_diff(diff, index).map_err(git_err)? {
+            Some(patch) => {
+                for h in 0..patch.num_hunks() {
+                    let (hunk, count) = patch.hunk(h).map_err(git_err)?;
+                    let table = lua.create_table()?;
+                    table.set("header", String::from_utf8_lossy(hunk.header()).trim_end().to_owned())?;
+                    table.set("old_start", hunk.old_start())?;
+                    table.set("old_lines", hunk.old_lines())?;
+                    table.set("new_start", hunk.new_start())?;
+                    table.set("new_lines", hunk.new_lines())?;
+                    let lines = lua.create_table()?;
+                    for l in 0..count {
+                        let line = patch.line_in_hunk(h, l).map_err(git_err)?;
+                        let entry = lua.create_table()?;
+                        entry.set("origin", line.origin().to_string())?;
+                        entry.set("content", lua.create_string(line.content())?)?;
+                        entry.set("old_lineno", line.old_lineno())?;
+                        entry.set("new_lineno", line.new_lineno())?;
+                        lines.set(l + 1, entry)?;
+                    }
+                    table.set("lines", lines)?;
+                    hunks.set(h + 1, table)?;
+                }
+            },
+            None => file.set("binary", true)?,
+        }
+        file.set("hunks", hunks)?;
+        files.set(index + 1, file)?;
+    }
+
+    let table = lua.create_table()?;
+    table.set("text", lua.create_string(&text)?)?;
+    table.set("files", files)?;
+    Ok(table)
+}
+
+fn checkout (repo: &Repository, target: &str, force: bool) -> Result<(), git2::Error> {
+    let mut builder = git2::build::CheckoutBuilder::new();
+    if force {
+        builder.force();
+    } else {
+        builder.safe();
+    }
+
+    match repo.find_branch(target, BranchType::Local) {
+        Ok(branch) => {
+            let reference = branch.into_reference();
+            let commit = reference.peel_to_commit()?;
+            repo.checkout_tree(commit.as_object(), Some(&mut builder))?;
+            repo.set_head(reference.name().unwrap_or_default())
+        },
+        Err(_) => {
+            let commit = find_commit(repo, target)?;
+            repo.checkout_tree(commit.as_object(), Some(&mut builder))?;
+            repo.set_head_detached(commit.id())
+        },
+    }
+}
+
+impl LuaUserData for LuaRepository {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("path", |_, this, ()| {
+            Ok(this.0.path().to_str().map(String::from))
+        });
+
+        methods.add_method("workdir", |_, this, ()| {
+            Ok(this.0.workdir().and_then(Path::to_str).map(String::from))
+        });
+
+        // The current branch and commit, nil before the first commit
+        methods.add_method("head", |lua, this, ()| {
+            let head = match this.0.head() {
+                Ok(head) => head,
+                Err(_) => return Ok(None),
+            };
+            let table = lua.create_table()?;
+            table.set("name", head.shorthand())?;
+            table.set("branch", head.is_branch())?;
+            table.set("id", head.target().map(|id| id.to_string()))?;
+            Ok(Some(table))
+        });
+
+        methods.add_method("add", |_, this, paths: Vec<String>| {
+            let mut index = this.0.index().map_err(git_err)?;
+            index.add_all(paths.iter(), git2::IndexAddOption::DEFAULT, None).map_err(git_err)?;
+            index.write().map_err(git_err)
+        });
+
+        // repo:commit("message", { author = { name, email }, committer = { name, email }, sign = secret_key })
+        // returns the new id, `sign` being a key from `crypto.sign`
+        methods.add_method("commit", |_, this, (message, options): (String, Option<LuaTable>)| {
+            let (author, committer, key) = match options {
+                Some(options) => (options.get("author")?, options.get("committer")?, signing::secret_key(&options)?),
+                None => (None, None, None),
+            };
+            let author = signature_from_lua(&this.0, author)?;
+            let committer = match committer {
+                Some(committer) => signature_from_lua(&this.0, Some(committer))?,
+                None => author.clone(),
+            };
+            let id = commit_index(&this.0, &message, &author, &committer, key.as_ref()).map_err(git_err)?;
+            Ok(id.to_string())
+        });
+
+        // Files that differ from HEAD, with how they differ in the index and in the working tree
+        methods.add_method("status", |lua, this, options: Option<LuaTable>| {
+            let mut opts = StatusOptions::new();
+            opts.include_untracked(true).recurse_untracked_dirs(true).renames_head_to_index(true);
+            if let Some(options) = options {
+                opts.include_untracked(options.get::<_, Option<bool>>("untracked")?.unwrap_or(true));
+                opts.include_ignored(options.get::<_, Option<bool>>("ignored")?.unwrap_or(false));
+            }
+
+            let statuses = this.0.statuses(Some(&mut opts)).map_err(git_err)?;
+            let result = lua.create_table()?;
+            for (i, entry) in statuses.iter().enumerate() {
+                let status = entry.status();
+                let table = lua.create_table()?;
+                table.set("path", lua.create_string(entry.path_bytes())?)?;
+                table.set("index", index_state(status))?;
+                table.set("worktree", worktree_state(status))?;
+                table.set("conflicted", status.contains(Status::CONFLICTED))?;
+                table.set("ignored", status.contains(Status::IGNORED))?;
+                result.set(i + 1, table)?;
+            }
+            Ok(result)
+        });
+
+        // repo:diff("HEAD~1", "HEAD", { paths = { "src" }, context = 3 }), the working tree when `to` is nil
+        methods.add_method("diff", |lua, this, (from, to, options): (Option<String>, Option<String>, Option<LuaTable>)| {
+            let mut opts = DiffOptions::new();
+            if let Some(options) = options {
+                if let Some(paths) = options.get::<_, Option<Vec<String>>>("paths")? {
+                    for path in paths {
+                        opts.pathspec(path);
+                    }
+                }
+                if let Some(context) = options.get::<_, Option<u32>>("context")? {
+                    opts.context_lines(context);
+                }
+                opts.include_untracked(options.get::<_, Option<bool>>("untracked")?.unwrap_or(false));
+                opts.show_untracked_content(true);
+            }
+            let diff = diff(&this.0, from.as_ref().map(String::as_str), to.as_ref().map(String::as_str), &mut opts)
+                .map_err(git_err)?;
+            diff_to_lua(lua, &diff)
+        });
+
+        methods.add_method("branches", |lua, this, options: Option<LuaTable>| {
+            let remote = match options {
+                Some(options) => options.get::<_, Option<bool>>("remote")?.unwrap_or(false),
+                None => false,
+            };
+            let kind = if remote { BranchType::Remote } else { BranchType::Local };
+
+            let result = lua.create_table()?;
+            for (i, branch) in this.0.branches(Some(kind)).map_err(git_err)?.enumerate() {
+                let (branch, _) = branch.map_err(git_err)?;
+                let table = lua.create_table()?;
+                table.set("name", branch.name().map_err(git_err)?)?;
+                table.set("head", branch.is_head())?;
+                table.set("id", branch.get().target().map(|id| id.to_string()))?;
+                let upstream = branch.upstream().ok();
+                table.set("upstream", upstream.as_ref().and_then(|upstream| upstream.name().ok()).and_then(|name| name))?;
+                result.set(i + 1, table)?;
+            }
+            Ok(result)
+        });
+
+        methods.add_method("create_branch", |_, this, (name, revision, force): (String, Option<String>, Option<bool>)| {
+            let commit = find_commit(&this.0, revision.as_ref().map_or("HEAD", String::as_str)).map_err(git_err)?;
+            this.0.branch(&name, &commit, force.unwrap_or(false)).map_err(git_err)?;
+            Ok(commit.id().to_string())
+        });
+
+        methods.add_method("delete_branch", |_, this, name: String| {
+            this.0.find_branch(&name, BranchType::Local)
+                .and_then(|mut branch| branch.delete())
+                .map_err(git_err)
+        });
+
+        // repo:checkout("feature", { create = true, force = false }), a revision detaches HEAD
+        methods.add_method("checkout", |_, this, (target, options): (String, Option<LuaTable>)| {
+            let (create, force) = match options {
+                Some(options) => (
+                    options.get::<_, Option<bool>>("create")?.unwrap_or(false),
+                    options.get::<_, Option<bool>>("force")?.unwrap_or(false),
+                ),
+                None => (false, false),
+            };
+            if create {
+                let head = find_commit(&this.0, "HEAD").map_err(git_err)?;
+                this.0.branch(&target, &head, false).map_err(git_err)?;
+            }
+            checkout(&this.0, &target, force).map_err(git_err)
+        });
+
+        methods.add_method("tags", |_, this, pattern: Option<String>| {
+            let names = this.0.tag_names(pattern.as_ref().map(String::as_str)).map_err(git_err)?;
+            Ok(names.iter().filter_map(|name| name.map(String::from)).collect::<Vec<_>>())
+        });
+
+        // repo:tag("v1.0", { revision = "HEAD", message = "...", tagger = { name, email }, sign = secret_key }),
+        // lightweight without a message
+        methods.add_method("tag", |_, this, (name, options): (String, Option<LuaTable>)| {
+            let (revision, message, tagger, force, key) = match options {
+                Some(options) => (
+                    options.get::<_, Option<String>>("revision")?,
+                    options.get::<_, Option<String>>("message")?,
+                    options.get::<_, Option<LuaTable>>("tagger")?,
+                    options.get::<_, Option<bool>>("force")?.unwrap_or(false),
+                    signing::secret_key(&options)?,
+                ),
+                None => (None, None, None, false, None),
+            };
+            let target = this.0.revparse_single(revision.as_ref().map_or("HEAD", String::as_str)).map_err(git_err)?;
+            let id = match (message, key) {
+                (Some(message), Some(key)) => {
+                    let tagger = signature_from_lua(&this.0, tagger)?;
+                    signing::tag(&this.0, &name, &target, &tagger, &message, force, &key)
+                },
+                (Some(message), None) => {
+                    let tagger = signature_from_lua(&this.0, tagger)?;
+                    this.0.tag(&name, &target, &tagger, &message, force)
+                },
+                (None, Some(_)) => return Err(LuaError::external(format_err!("signed tags need a message"))),
+                (None, None) => this.0.tag_lightweight(&name, &target, force),
+            }.map_err(git_err)?;
+            Ok(id.to_string())
+        });
+
+        methods.add_method("delete_tag", |_, this, name: String| {
+            this.0.tag_delete(&name).map_err(git_err)
+        });
+
+        // repo:log({ revision = "main", path = "docs/index.md", limit = 10, skip = 0, trusted = { public_key } }),
+        // newest first, signed commits having a `signature` trusted when made with one of the `trusted` keys
+        methods.add_method("log", |lua, this, options: Option<LuaTable>| {
+            let (revision, path, limit, skip, trusted) = match options {
+                Some(options) => (
+                    options.get::<_, Option<String>>("revision")?,
+                    options.get::<_, Option<String>>("path")?,
+                    options.get::<_, Option<usize>>("limit")?,
+                    options.get::<_, Option<usize>>("skip")?.unwrap_or(0),
+                    signing::public_keys(options.get("trusted")?)?,
+                ),
+                None => (None, None, None, 0, vec![]),
+            };
+
+            let result = lua.create_table()?;
+            let start = match revision {
+                Some(revision) => find_commit(&this.0, &revision).map_err(git_err)?.id(),
+                None => match this.0.head().ok().and_then(|head| head.target()) {
+                    Some(id) => id,
+                    // No commits yet
+                    None => return Ok(result),
+                },
+            };
+
+            let mut walk = this.0.revwalk().map_err(git_err)?;
+            walk.set_sorting(Sort::TIME);
+            walk.push(start).map_err(git_err)?;
+
+            let mut matched = 0;
+            let mut count = 0;
+            for id in walk {
+                if limit.map_or(false, |limit| count >= limit) {
+                    break;
+                }
+                let commit = this.0.find_commit(id.map_err(git_err)?).map_err(git_err)?;
+                if let Some(ref path) = path {
+                    if !touches(&commit, Path::new(path)).map_err(git_err)? {
+                        continue;
+                    }
+                }
+                matched += 1;
+                if matched <= skip {
+                    continue;
+                }
+                count += 1;
+                let table = commit_to_lua(lua, &commit)?;
+                if let Some(verification) = signing::verify_commit(&this.0, commit.id()) {
+                    table.set("signature", signing::verification_to_lua(lua, &verification, &trusted)?)?;
+                }
+                result.set(count, table)?;
+            }
+            Ok(result)
+        });
+
+        // The content of `path` at `revision`
+        methods.add_method("show", |lua, this, (revision, path): (String, String)| {
+            let tree = find_tree(&this.0, &revision).map_err(git_err)?;
+            let entry = tree.get_path(Path::new(&path)).map_err(git_err)?;
+            let blob = entry.to_object(&this.0).and_then(|object| object.peel_to_blob()).map_err(git_err)?;
+            lua.create_string(blob.content())
+        });
+
+        // Which commit last changed each range of lines of `path`
+        methods.add_method("blame", |lua, this, (path, revision): (String, Option<String>)| {
+            let mut opts = BlameOptions::new();
+            if let Some(revision) = revision {
+                opts.newest_commit(find_commit(&this.0, &revision).map_err(git_err)?.id());
+            }
+            let blame = this.0.blame_file(Path::new(&path), Some(&mut opts)).map_err(git_err)?;
+
+            let result = lua.create_table()?;
+            for (i, hunk) in blame.iter().enumerate() {
+                let table = lua.create_table()?;
+                table.set("start_line", hunk.final_start_line())?;
+                table.set("lines", hunk.lines_in_hunk())?;
+                table.set("id", hunk.final_commit_id().to_string())?;
+                table.set("author", signature_to_lua(lua, &hunk.final_signature())?)?;
+                result.set(i + 1, table)?;
+            }
+            Ok(result)
+        });
+
+        super::remote::add_methods(methods);
+    }
+}
+
+/// Adds the functions opening repositories to the `git` module
+pub fn register<'lua> (lua: LuaContext<'lua>, git: &LuaTable<'lua>) -> LuaResult<()> {
+    git.set("open", lua.create_function(|_, path: String| {
+        Repository::open(&path).map(LuaRepository).map_err(git_err)
+    })?)?;
+
+    // git.create(path, { bare = true })
+    git.set("create", lua.create_function(|_, (path, options): (String, Option<LuaTable>)| {
+        let bare = match options {
+            Some(options) => options.get::<_, Option<bool>>("bare")?.unwrap_or(false),
+            None => false,
+        };
+        let repo = if bare { Repository::init_bare(&path) } else { Repository::init(&path) };
+        repo.map(LuaRepository).map_err(git_err)
+    })?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lua_repository () {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        crate::bindings::app::git::init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().join("repo").to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local repo = git.create(dir)
+                local me = { name = "user", email = "user@example.com" }
+                assert(repo:head() == nil)
+                assert(#repo:log() == 0)
+
+                local file = io.open(dir .. "/notes.md", "w")
+                file:write("one\ntwo\n")
+                file:close()
+                assert(repo:status()[1].worktree == "new")
+                repo:add({ "notes.md" })
+                assert(repo:status()[1].index == "new")
+                local first = repo:commit("first", { author = me })
+
+                file = io.open(dir .. "/notes.md", "w")
+                file:write("one\nthree\n")
+                file:close()
+                local diff = repo:diff()
+                assert(diff.text:find("+three", 1, true))
+                assert(diff.files[1].status == "modified")
+                assert(diff.files[1].hunks[1].old_start == 1)
+
+                repo:add({ "notes.md" })
+                local second = repo:commit("second", { author = me })
+                assert(repo:show(first, "notes.md") == "one\ntwo\n")
+                assert(repo:show("HEAD", "notes.md") == "one\nthree\n")
+                assert(#repo:diff(first, second).files == 1)
+
+                local log = repo:log({ path = "notes.md" })
+                assert(#log == 2 and log[1].id == second and log[1].parents[1] == first)
+                assert(log[1].author.email == "user@example.com")
+                assert(#repo:log({ limit = 1 }) == 1)
+
+                repo:tag("v1", { revision = first })
+                repo:tag("v2", { message = "second release", tagger = me })
+                local tags = repo:tags()
+                assert(#tags == 2)
+
+                repo:checkout("feature", { create = true })
+                assert(repo:head().name == "feature")
+                assert(#repo:branches() == 2)
+                repo:checkout("v1")
+                assert(repo:head().branch == false)
+
+                assert(#repo:blame("notes.md", second) == 2)
+                assert(not pcall(repo.show, repo, "HEAD", "missing.md"))
+                assert(not pcall(git.open, dir .. "/missing"))
+            "#).exec().unwrap();
+        });
+    }
+}
@@ -0,0 +1,346 @@
+use git2::{self, Commit, ObjectType, Oid, Repository, Signature, Tree};
+use rlua::prelude::*;
+use sodiumoxide::crypto::{
+    hash::{sha256, sha512},
+    sign::{self, PublicKey, SecretKey},
+};
+
+use crate::bindings::crypto::sign::{LuaPublicKey, LuaSecretKey};
+
+const MAGIC: &[u8] = b"SSHSIG";
+const VERSION: u32 = 1;
+const NAMESPACE: &[u8] = b"git";
+const KEY_TYPE: &[u8] = b"ssh-ed25519";
+const HASH: &[u8] = b"sha512";
+const BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const END: &str = "-----END SSH SIGNATURE-----";
+
+fn put_string (buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take (&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u32 (&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from(bytes[0]) << 24 | u32::from(bytes[1]) << 16 | u32::from(bytes[2]) << 8 | u32::from(bytes[3]))
+    }
+
+    fn string (&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// The public key in the SSH wire format
+fn public_key_blob (key: &PublicKey) -> Vec<u8> {
+    let mut blob = vec![];
+    put_string(&mut blob, KEY_TYPE);
+    put_string(&mut blob, key.as_ref());
+    blob
+}
+
+/// `key` as a line of an `authorized_keys` or git `allowed_signers` file
+pub fn ssh_public_key (key: &PublicKey) -> String {
+    format!("ssh-ed25519 {}", base64::encode(&public_key_blob(key)))
+}
+
+/// What the signature actually covers, per the SSHSIG format
+fn signed_data (namespace: &[u8], hash: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+    let digest = match hash {
+        b"sha512" => sha512::hash(message).as_ref().to_vec(),
+        b"sha256" => sha256::hash(message).as_ref().to_vec(),
+        _ => return None,
+    };
+    let mut data = MAGIC.to_vec();
+    put_string(&mut data, namespace);
+    put_string(&mut data, b"");
+    put_string(&mut data, hash);
+    put_string(&mut data, &digest);
+    Some(data)
+}
+
+/// Signs `message` in the armored SSH signature format git uses for `gpg.format = ssh`
+pub fn sign_message (message: &[u8], key: &SecretKey) -> String {
+    let data = signed_data(NAMESPACE, HASH, message).expect("supported hash");
+    let signature = sign::sign_detached(&data, key);
+
+    let mut signature_blob = vec![];
+    put_string(&mut signature_blob, KEY_TYPE);
+    put_string(&mut signature_blob, signature.as_ref());
+
+    let mut blob = MAGIC.to_vec();
+    blob.extend_from_slice(&VERSION.to_be_bytes());
+    put_string(&mut blob, &public_key_blob(&key.public_key()));
+    put_string(&mut blob, NAMESPACE);
+    put_string(&mut blob, b"");
+    put_string(&mut blob, HASH);
+    put_string(&mut blob, &signature_blob);
+
+    let encoded = base64::encode(&blob);
+    let mut armored = String::from(BEGIN);
+    armored.push('\n');
+    for line in encoded.as_bytes().chunks(70) {
+        armored.push_str(std::str::from_utf8(line).expect("base64 is ascii"));
+        armored.push('\n');
+    }
+    armored.push_str(END);
+    armored.push('\n');
+    armored
+}
+
+pub struct Verification {
+    pub key: PublicKey,
+    pub valid: bool,
+}
+
+/// Checks an armored SSH signature of `message`, `None` when it isn't
+/// an ed25519 SSH signature for git at all
+pub fn verify_message (message: &[u8], armored: &str) -> Option<Verification> {
+    let encoded: String = armored.lines()
+        .map(str::trim)
+        .skip_while(|line| *line != BEGIN)
+        .skip(1)
+        .take_while(|line| *line != END)
+        .collect();
+    let blob = base64::decode(&encoded).ok()?;
+
+    let mut reader = Reader(&blob);
+    if reader.take(MAGIC.len())? != MAGIC || reader.u32()? != VERSION {
+        return None;
+    }
+    let mut key_blob = Reader(reader.string()?);
+    let namespace = reader.string()?;
+    let _reserved = reader.string()?;
+    let hash = reader.string()?;
+    let mut signature_blob = Reader(reader.string()?);
+
+    if key_blob.string()? != KEY_TYPE || signature_blob.string()? != KEY_TYPE || namespace != NAMESPACE {
+        return None;
+    }
+    let key = PublicKey::from_slice(key_blob.string()?)?;
+    let signature = sign::Signature::from_slice(signature_blob.string()?)?;
+    let data = signed_data(namespace, hash, message)?;
+
+    Some(Verification { valid: sign::verify_detached(&signature, &data, &key), key })
+}
+
+/// A signature as it appears in commit and tag headers
+fn signature_line (signature: &Signature) -> String {
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    format!(
+        "{} <{}> {} {}{:02}{:02}",
+        String::from_utf8_lossy(signature.name_bytes()),
+        String::from_utf8_lossy(signature.email_bytes()),
+        when.seconds(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60,
+    )
+}
+
+/// Points `name` at `id`, `HEAD` meaning the branch it is on. Like `Repository::commit`,
+/// fails unless `name` still points at `parent`, or doesn't exist yet for a root commit.
+fn update_reference (repo: &Repository, name: &str, id: Oid, parent: Option<Oid>, message: &str) -> Result<(), git2::Error> {
+    let moved = || git2::Error::from_str("failed to create commit: current tip is not the first parent");
+    let name = if name == "HEAD" {
+        let head = repo.find_reference("HEAD")?;
+        match head.symbolic_target() {
+            Some(target) => target.to_owned(),
+            None => {
+                if head.target() != parent {
+                    return Err(moved());
+                }
+                return repo.set_head_detached(id);
+            },
+        }
+    } else {
+        name.to_owned()
+    };
+    let updated = match parent {
+        Some(parent) => repo.reference_matching(&name, id, true, parent, message),
+        None => repo.reference(&name, id, false, message),
+    };
+    updated.map(|_| ()).map_err(|err| match err.code() {
+        git2::ErrorCode::Modified | git2::ErrorCode::Exists => moved(),
+        _ => err,
+    })
+}
+
+/// Creates a commit like `Repository::commit`, signed with `key` when there is one
+#[allow(clippy::too_many_arguments)]
+pub fn commit (repo: &Repository, update_ref: &str, author: &Signature, committer: &Signature, message: &str, tree: &Tree, parents: &[&Commit], key: Option<&SecretKey>) -> Result<Oid, git2::Error> {
+    let key = match key {
+        Some(key) => key,
+        None => return repo.commit(Some(update_ref), author, committer, message, tree, parents),
+    };
+
+    let mut content = format!("tree {}\n", tree.id());
+    for parent in parents {
+        content.push_str(&format!("parent {}\n", parent.id()));
+    }
+    content.push_str(&format!("author {}\n", signature_line(author)));
+    content.push_str(&format!("committer {}\n\n", signature_line(committer)));
+    content.push_str(message);
+
+    let signature = sign_message(content.as_bytes(), key);
+    let id = repo.commit_signed(&content, &signature, None)?;
+    let summary = message.lines().next().unwrap_or("");
+    update_reference(repo, update_ref, id, parents.first().map(|parent| parent.id()), &format!("commit: {}", summary))?;
+    Ok(id)
+}
+
+/// Creates an annotated tag with the signature appended to its message, like `git tag -s`
+pub fn tag (repo: &Repository, name: &str, target: &git2::Object, tagger: &Signature, message: &str, force: bool, key: &SecretKey) -> Result<Oid, git2::Error> {
+    let kind = target.kind().map_or("commit", |kind| kind.str());
+    let mut content = format!("object {}\ntype {}\ntag {}\ntagger {}\n\n{}", target.id(), kind, name, signature_line(tagger), message);
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&sign_message(content.as_bytes(), key));
+
+    let id = repo.odb()?.write(ObjectType::Tag, content.as_bytes())?;
+    repo.reference(&format!("refs/tags/{}", name), id, force, "tag: signed")?;
+    Ok(id)
+}
+
+/// How `id` is signed, `None` when it has no SSH signature
+pub fn verify_commit (repo: &Repository, id: Oid) -> Option<Verification> {
+    let (signature, content) = repo.extract_signature(&id, None).ok()?;
+    verify_message(&content, signature.as_str()?)
+}
+
+/// The secret key in the `sign` field of `options`, a key from `crypto.sign`
+pub fn secret_key (options: &LuaTable) -> LuaResult<Option<SecretKey>> {
+    match options.get::<_, Option<LuaAnyUserData>>("sign")? {
+        Some(key) => Ok(Some(key.borrow::<LuaSecretKey>()?.0.clone())),
+        None => Ok(None),
+    }
+}
+
+/// Public keys from `crypto.sign` or their base64 encoding
+pub fn public_keys (keys: Option<Vec<LuaValue>>) -> LuaResult<Vec<PublicKey>> {
+    keys.unwrap_or_default().into_iter()
+        .map(|key| {
+            let key = match key {
+                LuaValue::UserData(key) => Some(key.borrow::<LuaPublicKey>()?.0),
+                LuaValue::String(key) => base64::decode(key.as_bytes()).ok()
+                    .and_then(|bytes| PublicKey::from_slice(&bytes)),
+                _ => None,
+            };
+            key.ok_or_else(|| LuaError::external(format_err!("expected a public key from crypto.sign")))
+        })
+        .collect()
+}
+
+/// `{ key = base64 public key, valid = bool, trusted = bool }` for a signed commit
+pub fn verification_to_lua<'lua> (lua: LuaContext<'lua>, verification: &Verification, trusted: &[PublicKey]) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    table.set("key", base64::encode(verification.key.as_ref()))?;
+    table.set("valid", verification.valid)?;
+    table.set("trusted", verification.valid && trusted.contains(&verification.key))?;
+    Ok(table)
+}
+
+/// Adds `git.ssh_public_key` to the `git` module
+pub fn register<'lua> (lua: LuaContext<'lua>, git: &LuaTable<'lua>) -> LuaResult<()> {
+    // The "ssh-ed25519 AAAA..." line to give git in its allowed signers file
+    git.set("ssh_public_key", lua.create_function(|_, key: LuaAnyUserData| {
+        Ok(ssh_public_key(&key.borrow::<LuaPublicKey>()?.0))
+    })?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sshsig_round_trip () {
+        sodiumoxide::init().unwrap();
+        let (public, secret) = sign::gen_keypair();
+        let signature = sign_message(b"tree 1234\n", &secret);
+        assert!(signature.starts_with(BEGIN));
+
+        let verification = verify_message(b"tree 1234\n", &signature).unwrap();
+        assert!(verification.valid);
+        assert_eq!(verification.key, public);
+        assert!(!verify_message(b"tree 5678\n", &signature).unwrap().valid);
+        assert!(verify_message(b"tree 1234\n", "-----BEGIN PGP SIGNATURE-----").is_none());
+    }
+
+    #[test]
+    fn signed_commits_need_the_current_tip () {
+        sodiumoxide::init().unwrap();
+        let (_, secret) = sign::gen_keypair();
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let me = Signature::now("user", "user@example.com").unwrap();
+        let tree = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+        let branch = "refs/heads/master";
+
+        let root = commit(&repo, branch, &me, &me, "root", &tree, &[], Some(&secret)).unwrap();
+        let root = repo.find_commit(root).unwrap();
+        // A second writer that started from nothing can't replace the history
+        assert!(commit(&repo, branch, &me, &me, "other root", &tree, &[], Some(&secret)).is_err());
+
+        // Two writers both building on the root: the second one has to start over
+        let first = commit(&repo, branch, &me, &me, "first", &tree, &[&root], Some(&secret)).unwrap();
+        assert!(commit(&repo, branch, &me, &me, "second", &tree, &[&root], Some(&secret)).is_err());
+        assert_eq!(repo.refname_to_id(branch).unwrap(), first);
+
+        let first = repo.find_commit(first).unwrap();
+        let second = commit(&repo, branch, &me, &me, "second", &tree, &[&first], Some(&secret)).unwrap();
+        assert_eq!(repo.refname_to_id(branch).unwrap(), second);
+    }
+
+    #[test]
+    fn lua_signed_commits () {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        crate::bindings::crypto::init(&lua).unwrap();
+        crate::bindings::app::git::init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().join("repo").to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local me = { name = "user", email = "user@example.com" }
+                local secret, public = crypto.sign.new_keypair()
+                local other_secret, other = crypto.sign.new_keypair()
+                assert(git.ssh_public_key(public):find("ssh-ed25519 ", 1, true) == 1)
+
+                local repo = git.create(dir)
+                local file = io.open(dir .. "/a.md", "w")
+                file:write("a")
+                file:close()
+                repo:add({ "a.md" })
+                local signed = repo:commit("signed", { author = me, sign = secret })
+                assert(repo:head().id == signed)
+                repo:add({ "a.md" })
+                repo:commit("plain", { author = me })
+
+                local log = repo:log({ trusted = { public } })
+                assert(log[1].signature == nil)
+                assert(log[2].signature.valid and log[2].signature.trusted)
+                assert(log[2].signature.key == tostring(public))
+                assert(not repo:log({ trusted = { other } })[2].signature.trusted)
+
+                repo:tag("v1", { message = "release", tagger = me, sign = secret })
+                assert(repo:tags()[1] == "v1")
+                assert(not pcall(repo.tag, repo, "v2", { sign = secret }))
+            "#).exec().unwrap();
+        });
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+};
+use git2::{self, ObjectType, Oid, Repository, Tree};
+use rlua::prelude::*;
+use sodiumoxide::crypto::sign::SecretKey;
+
+use super::{repo::{delta_name, find_commit, signature_from_lua}, signing};
+
+const FILE_MODE: i32 = 0o100_644;
+const TREE_MODE: i32 = 0o040_000;
+
+/// Files of a branch read and written through the object database, so the
+/// repository needs no working tree. Changes are kept until `commit`.
+pub struct Store {
+    repo: Repository,
+    branch: String,
+    /// Pending changes by path, `None` deleting the file
+    changes: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+/// Splits `path` into its components, refusing the ones that would escape the tree
+fn components (path: &str) -> Result<Vec<&str>, git2::Error> {
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    if parts.is_empty() || parts.iter().any(|part| *part == "." || *part == ".." || *part == ".git") {
+        return Err(git2::Error::from_str(&format!("invalid path {:?}", path)));
+    }
+    Ok(parts)
+}
+
+type Change<'a> = (Vec<&'a str>, Option<&'a [u8]>);
+
+/// Writes `base` with `changes` applied, `None` when the tree ends up empty
+fn build_tree (repo: &Repository, base: Option<&Tree>, changes: &[Change]) -> Result<Option<Oid>, git2::Error> {
+    let mut builder = repo.treebuilder(base)?;
+    let mut subtrees: BTreeMap<&str, Vec<Change>> = BTreeMap::new();
+
+    for (parts, content) in changes {
+        if parts.len() > 1 {
+            subtrees.entry(parts[0]).or_insert_with(Vec::new).push((parts[1..].to_vec(), *content));
+            continue;
+        }
+        match content {
+            Some(content) => { builder.insert(parts[0], repo.blob(content)?, FILE_MODE)?; },
+            None => if builder.get(parts[0])?.is_some() {
+                builder.remove(parts[0])?;
+            },
+        }
+    }
+
+    for (name, changes) in subtrees {
+        let subtree = match builder.get(name)?.map(|entry| (entry.id(), entry.kind())) {
+            Some((id, Some(ObjectType::Tree))) => Some(repo.find_tree(id)?),
+            _ => None,
+        };
+        match build_tree(repo, subtree.as_ref(), &changes)? {
+            Some(id) => { builder.insert(name, id, TREE_MODE)?; },
+            None => if builder.get(name)?.is_some() {
+                builder.remove(name)?;
+            },
+        }
+    }
+
+    if builder.is_empty() {
+        return Ok(None);
+    }
+    builder.write().map(Some)
+}
+
+impl Store {
+    pub fn open (path: &str, branch: &str) -> Result<Store, git2::Error> {
+        let repo = if Path::new(path).exists() {
+            Repository::open(path)?
+        } else {
+            Repository::init_bare(path)?
+        };
+        Ok(Store { repo, branch: branch.to_owned(), changes: BTreeMap::new() })
+    }
+
+    fn reference (&self) -> String {
+        format!("refs/heads/{}", self.branch)
+    }
+
+    /// The last commit of the branch, `None` before the first one
+    fn tip (&self) -> Option<Oid> {
+        self.repo.find_reference(&self.reference()).ok().and_then(|reference| reference.target())
+    }
+
+    /// The tree of `revision`, the tip of the branch by default
+    fn tree (&self, revision: Option<&str>) -> Result<Option<Tree>, git2::Error> {
+        match revision {
+            Some(revision) => find_commit(&self.repo, revision)?.tree().map(Some),
+            None => match self.tip() {
+                Some(tip) => self.repo.find_commit(tip)?.tree().map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+
+    pub fn write (&mut self, path: &str, content: Vec<u8>) -> Result<(), git2::Error> {
+        let path = components(path)?.join("/");
+        self.changes.insert(path, Some(content));
+        Ok(())
+    }
+
+    pub fn delete (&mut self, path: &str) -> Result<(), git2::Error> {
+        let path = components(path)?.join("/");
+        self.changes.insert(path, None);
+        Ok(())
+    }
+
+    /// The content of `path` at `revision`, or with the pending changes when there is no revision
+    pub fn read (&self, path: &str, revision: Option<&str>) -> Result<Option<Vec<u8>>, git2::Error> {
+        let path = components(path)?.join("/");
+        if revision.is_none() {
+            if let Some(change) = self.changes.get(&path) {
+                return Ok(change.clone());
+            }
+        }
+        let tree = match self.tree(revision)? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        let entry = match tree.get_path(Path::new(&path)) {
+            Ok(entry) => entry,
+            Err(_) => return Ok(None),
+        };
+        match entry.to_object(&self.repo)?.into_blob() {
+            Ok(blob) => Ok(Some(blob.content().to_vec())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Names of the entries of the directory `dir` at `revision`, with whether they are directories
+    pub fn list (&self, dir: Option<&str>, revision: Option<&str>) -> Result<Vec<(String, bool)>, git2::Error> {
+        let root = match self.tree(revision)? {
+            Some(tree) => tree,
+            None => return Ok(vec![]),
+        };
+        let tree = match dir {
+            Some(dir) => {
+                let entry = match root.get_path(Path::new(&components(dir)?.join("/"))) {
+                    Ok(entry) => entry,
+                    Err(_) => return Ok(vec![]),
+                };
+                match entry.to_object(&self.repo)?.into_tree() {
+                    Ok(tree) => tree,
+                    Err(_) => return Ok(vec![]),
+                }
+            },
+            None => root,
+        };
+        Ok(tree.iter()
+            .filter_map(|entry| entry.name().map(|name| (name.to_owned(), entry.kind() == Some(ObjectType::Tree))))
+            .collect())
+    }
+
+    /// Commits the pending changes on top of the branch, returning `None` when there are none
+    ///
+    /// The branch only moves if it still points at the commit the changes were
+    /// applied to, so concurrent writers can't lose each other's commits.
+    pub fn commit (&mut self, message: &str, author: &git2::Signature, committer: &git2::Signature, key: Option<&SecretKey>) -> Result<Option<Oid>, git2::Error> {
+        if self.changes.is_empty() {
+            return Ok(None);
+        }
+
+        let parent = match self.tip() {
+            Some(tip) => Some(self.repo.find_commit(tip)?),
+            None => None,
+        };
+        let base = match parent {
+            Some(ref parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let changes: Vec<Change> = self.changes.iter()
+            .map(|(path, content)| (path.split('/').collect(), content.as_ref().map(Vec::as_slice)))
+            .collect();
+        let tree = match build_tree(&self.repo, base.as_ref(), &changes)? {
+            Some(id) => id,
+            None => self.repo.treebuilder(None)?.write()?,
+        };
+        if base.as_ref().map(Tree::id) == Some(tree) {
+            self.changes.clear();
+            return Ok(None);
+        }
+
+        let tree = self.repo.find_tree(tree)?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let id = signing::commit(&self.repo, &self.reference(), author, committer, message, &tree, &parents, key)?;
+        self.changes.clear();
+        Ok(Some(id))
+    }
+
+    /// Files changed between two revisions, the tip of the branch being the default for `to`
+    pub fn changed_files (&self, from: &str, to: Option<&str>) -> Result<Vec<(String, &'static str)>, git2::Error> {
+        let old = self.tree(Some(from))?;
+        let new = self.tree(to)?;
+        let diff = self.repo.diff_tree_to_tree(old.as_ref(), new.as_ref(), None)?;
+        Ok(diff.deltas()
+            .filter_map(|delta| {
+                let file = if delta.new_file().path().is_some() { delta.new_file() } else { delta.old_file() };
+                file.path().and_then(Path::to_str).map(|path| (path.to_owned(), delta_name(delta.status())))
+            })
+            .collect())
+    }
+}
+
+fn git_err (err: git2::Error) -> LuaError {
+    LuaError::external(err)
+}
+
+impl LuaUserData for Store {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("write", |_, this, (path, content): (String, LuaString)| {
+            this.write(&path, content.as_bytes().to_vec()).map_err(git_err)
+        });
+
+        methods.add_method_mut("delete", |_, this, path: String| {
+            this.delete(&path).map_err(git_err)
+        });
+
+        // store:read("docs/a.md", "HEAD~3"), nil when the file doesn't exist
+        methods.add_method("read", |lua, this, (path, revision): (String, Option<String>)| {
+            match this.read(&path, revision.as_ref().map(String::as_str)).map_err(git_err)? {
+                Some(content) => Ok(Some(lua.create_string(&content)?)),
+                None => Ok(None),
+            }
+        });
+
+        methods.add_method("exists", |_, this, (path, revision): (String, Option<String>)| {
+            Ok(this.read(&path, revision.as_ref().map(String::as_str)).map_err(git_err)?.is_some())
+        });
+
+        // store:list("docs") returns { { name = "a.md", dir = false }, ... }
+        methods.add_method("list", |lua, this, (dir, revision): (Option<String>, Option<String>)| {
+            let entries = this.list(dir.as_ref().map(String::as_str), revision.as_ref().map(String::as_str)).map_err(git_err)?;
+            let result = lua.create_table()?;
+            for (i, (name, dir)) in entries.into_iter().enumerate() {
+                let table = lua.create_table()?;
+                table.set("name", name)?;
+                table.set("dir", dir)?;
+                result.set(i + 1, table)?;
+            }
+            Ok(result)
+        });
+
+        // store:commit("message", { author = { name, email }, sign = secret_key }) returns the new id, nil without changes
+        methods.add_method_mut("commit", |_, this, (message, options): (String, Option<LuaTable>)| {
+            let (author, committer, key) = match options {
+                Some(options) => (options.get("author")?, options.get("committer")?, signing::secret_key(&options)?),
+                None => (None, None, None),
+            };
+            let author = signature_from_lua(&this.repo, author)?;
+            let committer = match committer {
+                Some(committer) => signature_from_lua(&this.repo, Some(committer))?,
+                None => author.clone(),
+            };
+            let id = this.commit(&message, &author, &committer, key.as_ref()).map_err(git_err)?;
+            Ok(id.map(|id| id.to_string()))
+        });
+
+        // Drops the pending changes
+        methods.add_method_mut("discard", |_, this, ()| {
+            this.changes.clear();
+            Ok(())
+        });
+
+        methods.add_method("head", |_, this, ()| {
+            Ok(this.tip().map(|id| id.to_string()))
+        });
+
+        // store:changes(from, to) returns { { path = "a.md", status = "modified" }, ... }
+        methods.add_method("changes", |lua, this, (from, to): (String, Option<String>)| {
+            let changes = this.changed_files(&from, to.as_ref().map(String::as_str)).map_err(git_err)?;
+            let result = lua.create_table()?;
+            for (i, (path, status)) in changes.into_iter().enumerate() {
+                let table = lua.create_table()?;
+                table.set("path", path)?;
+                table.set("status", status)?;
+                result.set(i + 1, table)?;
+            }
+            Ok(result)
+        });
+    }
+}
+
+/// Adds `git.store` to the `git` module
+pub fn register<'lua> (lua: LuaContext<'lua>, git: &LuaTable<'lua>) -> LuaResult<()> {
+    // git.store("data/content.git", { branch = "master" }), creating a bare repository if needed
+    git.set("store", lua.create_function(|_, (path, options): (String, Option<LuaTable>)| {
+        let branch = match options {
+            Some(options) => options.get::<_, Option<String>>("branch")?,
+            None => None,
+        };
+        Store::open(&path, branch.as_ref().map_or("master", String::as_str)).map_err(git_err)
+    })?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_changes () {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path().join("store.git").to_str().unwrap(), "master").unwrap();
+        let me = git2::Signature::now("user", "user@example.com").unwrap();
+
+        assert!(store.write("../outside", vec![]).is_err());
+        store.write("docs/guide/intro.md", b"intro".to_vec()).unwrap();
+        store.write("/docs/index.md", b"index".to_vec()).unwrap();
+        let first = store.commit("first", &me, &me, None).unwrap().unwrap();
+        assert_eq!(store.commit("nothing", &me, &me, None).unwrap(), None);
+
+        store.delete("docs/guide/intro.md").unwrap();
+        store.commit("second", &me, &me, None).unwrap().unwrap();
+
+        assert_eq!(store.list(Some("docs"), None).unwrap(), vec![("index.md".to_owned(), false)]);
+        assert_eq!(store.read("docs/guide/intro.md", None).unwrap(), None);
+        assert_eq!(store.read("docs/guide/intro.md", Some(&first.to_string())).unwrap(), Some(b"intro".to_vec()));
+        assert_eq!(store.changed_files(&first.to_string(), None).unwrap(), vec![("docs/guide/intro.md".to_owned(), "deleted")]);
+    }
+
+    #[test]
+    fn lua_store () {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        crate::bindings::app::git::init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("path", dir.path().join("content.git").to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local me = { name = "user", email = "user@example.com" }
+                local store = git.store(path)
+                assert(store:head() == nil and store:read("a.md") == nil)
+
+                store:write("a.md", "one")
+                assert(store:read("a.md") == "one")
+                local first = store:commit("add a", { author = me })
+                assert(store:head() == first)
+
+                store:write("a.md", "two")
+                store:write("b/c.md", "three")
+                store:commit("change a, add c", { author = me })
+
+                assert(store:read("a.md") == "two")
+                assert(store:read("a.md", first) == "one")
+                assert(store:exists("b/c.md") and not store:exists("b/c.md", first))
+                local changes = store:changes(first)
+                assert(#changes == 2 and changes[1].path == "a.md" and changes[1].status == "modified")
+                assert(#store:list() == 2)
+
+                local repo = git.open(path)
+                assert(repo:log()[1].summary == "change a, add c")
+            "#).exec().unwrap();
+        });
+    }
+}
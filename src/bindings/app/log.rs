@@ -1,46 +1,150 @@
 use rlua::prelude::*;
 use rlua::{Variadic, Value};
+use serde_json::{Map, Value as JsonValue};
+use crate::logger::{self, Level, LevelFilter};
 
-pub fn init(lua: &Lua) -> crate::Result<()> {
-    lua.context(|lua| {
-        fn tostr<'a>(lua: LuaContext<'a>, args: Variadic<Value<'a>>) -> LuaResult<String> {
-            let f: LuaFunction = lua.globals().get("tostring")?;
-            let mut s = String::new();
-            for value in args.into_iter() {
-                let vs: String = f.call(value)?;
-                s.push('\t');
-                s.push_str(&vs);
-            }
-            Ok(s)
+/// Turns a table of fields given to a log function into a JSON map
+fn to_fields (value: Value) -> LuaResult<Map<String, JsonValue>> {
+    match rlua_serde::from_value(value)? {
+        JsonValue::Object(map) => Ok(map),
+        // An empty table can't be told apart from an empty array
+        JsonValue::Array(ref array) if array.is_empty() => Ok(Map::new()),
+        _ => Err(LuaError::external(format_err!("log fields must be a table with string keys"))),
+    }
+}
+
+fn tostr<'a>(lua: LuaContext<'a>, args: Variadic<Value<'a>>) -> LuaResult<String> {
+    let f: LuaFunction = lua.globals().get("tostring")?;
+    let mut s = String::new();
+    for value in args.into_iter() {
+        let vs: String = f.call(value)?;
+        s.push('\t');
+        s.push_str(&vs);
+    }
+    Ok(s)
+}
+
+/// Whether `table` is a non-empty table whose keys are all strings
+fn is_record(table: &LuaTable) -> bool {
+    let mut any = false;
+    for pair in table.clone().pairs::<Value, Value>() {
+        match pair {
+            Ok((Value::String(_), _)) => any = true,
+            _ => return false,
         }
+    }
+    any
+}
+
+// A table after the message holds fields for that line, as in `_log.info("msg", {user=id})`.
+// Any other trailing value, such as a list, is printed as part of the message.
+fn take_fields<'a>(args: &mut Variadic<Value<'a>>) -> Map<String, JsonValue> {
+    let record = match args.last() {
+        Some(Value::Table(table)) if args.len() > 1 => is_record(table),
+        _ => false,
+    };
+    if !record {
+        return Map::new();
+    }
+    match args.last().cloned().map(to_fields) {
+        Some(Ok(fields)) => {
+            args.pop();
+            fields
+        },
+        _ => Map::new(),
+    }
+}
 
+fn log_args<'a>(lua: LuaContext<'a>, target: &str, level: Level, mut args: Variadic<Value<'a>>) -> LuaResult<()> {
+    let fields = take_fields(&mut args);
+    let message = tostr(lua, args)?;
+    logger::with_fields(fields, || log!(target: target, level, "{}", message));
+    Ok(())
+}
+
+fn parse_level (level: &str) -> LuaResult<LevelFilter> {
+    logger::parse_level(level).map_err(LuaError::external)
+}
+
+/// The target of the default Lua logger, named loggers log under `lua::<name>`
+const TARGET: &str = "lua";
+
+/// A named logger, whose level can be set apart from the others
+struct Logger {
+    target: String,
+}
+
+impl LuaUserData for Logger {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("error", |lua, this, args: _| log_args(lua, &this.target, Level::Error, args));
+        methods.add_method("warn", |lua, this, args: _| log_args(lua, &this.target, Level::Warn, args));
+        methods.add_method("info", |lua, this, args: _| log_args(lua, &this.target, Level::Info, args));
+        methods.add_method("debug", |lua, this, args: _| log_args(lua, &this.target, Level::Debug, args));
+        methods.add_method("trace", |lua, this, args: _| log_args(lua, &this.target, Level::Trace, args));
+
+        methods.add_method("set_level", |_, this, level: String| {
+            logger::set_level(Some(&this.target), parse_level(&level)?);
+            Ok(())
+        });
+
+        methods.add_method("get_level", |_, this, ()| {
+            Ok(logger::level(Some(&this.target)).to_string().to_lowercase())
+        });
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
         let module = lua.create_table()?;
 
         module.set("error", lua.create_function(|lua, args: _| {
-            error!("{}", tostr(lua, args)?);
-            Ok(())
+            log_args(lua, TARGET, Level::Error, args)
         })?)?;
 
         module.set("warn", lua.create_function(|lua, args: _| {
-            warn!("{}", tostr(lua, args)?);
-            Ok(())
+            log_args(lua, TARGET, Level::Warn, args)
         })?)?;
 
         module.set("info", lua.create_function(|lua, args: _| {
-            info!("{}", tostr(lua, args)?);
-            Ok(())
+            log_args(lua, TARGET, Level::Info, args)
         })?)?;
 
         module.set("debug", lua.create_function(|lua, args: _| {
-            debug!("{}", tostr(lua, args)?);
-            Ok(())
+            log_args(lua, TARGET, Level::Debug, args)
         })?)?;
 
         module.set("trace", lua.create_function(|lua, args: _| {
-            trace!("{}", tostr(lua, args)?);
+            log_args(lua, TARGET, Level::Trace, args)
+        })?)?;
+
+        // Fields added to every line logged until the context is cleared, such as the request id
+        module.set("set_context", lua.create_function(|_, fields: Value| {
+            logger::set_context(to_fields(fields)?);
+            Ok(())
+        })?)?;
+
+        module.set("clear_context", lua.create_function(|_, ()| {
+            logger::clear_context();
+            Ok(())
+        })?)?;
+
+        module.set("get", lua.create_function(|_, name: String| {
+            Ok(Logger { target: format!("{}::{}", TARGET, name) })
+        })?)?;
+
+        // `_log.set_level("debug")` sets the default level, `_log.set_level("actix_web", "debug")` the level of a target
+        module.set("set_level", lua.create_function(|_, (first, second): (String, Option<String>)| {
+            match second {
+                Some(level) => logger::set_level(Some(&first), parse_level(&level)?),
+                None => logger::set_level(None, parse_level(&first)?),
+            }
             Ok(())
         })?)?;
 
+        module.set("get_level", lua.create_function(|_, target: Option<String>| {
+            Ok(logger::level(target.as_ref().map(String::as_str)).to_string().to_lowercase())
+        })?)?;
+
         lua.globals().set("_log", module)?;
 
         Ok(())
@@ -75,7 +179,51 @@ mod tests {
             _log.warn("Warning")
             _log.debug("Debug")
             _log.trace("Trace", "with", {}, "data")
+            _log.set_context({ request_id = "01D8" })
+            _log.info("Fields", { user = 5, tags = { "a", "b" } })
+            _log.clear_context()
+
+            local billing = _log.get("billing")
+            billing:set_level("debug")
+            billing:debug("Charged", { amount = 10 })
+            assert(billing:get_level() == "debug")
+
+            _log.info("items", {1, 2, 3})
+            _log.info("mixed", { 1, user = 5 })
+            _log.info("handler", { fn = print })
         "#).exec().unwrap();
         });
     }
+
+    #[test]
+    fn trailing_fields () {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let split = |code: &str| {
+                let mut args: Variadic<Value> = lua.load(code).eval().unwrap();
+                let fields = take_fields(&mut args);
+                (args.len(), fields)
+            };
+
+            let (len, fields) = split(r#"return "msg", { user = 5, name = "x" }"#);
+            assert_eq!(len, 1);
+            assert_eq!(fields.get("user"), Some(&JsonValue::from(5)));
+            assert_eq!(fields.get("name"), Some(&JsonValue::from("x")));
+
+            // Lists, mixed and empty tables stay part of the message
+            for code in &[r#"return "items", {1, 2, 3}"#, r#"return "mixed", { 1, a = 2 }"#, r#"return "empty", {}"#] {
+                let (len, fields) = split(code);
+                assert_eq!(len, 2);
+                assert!(fields.is_empty());
+            }
+
+            // A lone table is the message itself
+            let (len, fields) = split(r#"return { user = 5 }"#);
+            assert_eq!(len, 1);
+            assert!(fields.is_empty());
+
+            let message = tostr(lua, lua.load(r#"return "items", {1, 2, 3}"#).eval().unwrap()).unwrap();
+            assert!(message.starts_with("\titems\ttable: "));
+        });
+    }
 }
\ No newline at end of file
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rlua::prelude::*;
+use rlua::{Variadic, Value};
+use crate::metrics::{self, Kind, REGISTRY};
+
+static NEXT_VM: AtomicUsize = AtomicUsize::new(1);
+
+/// Kept in the registry of every VM, counting it as alive until the VM is closed
+struct VmGuard {
+    id: String,
+}
+
+impl LuaUserData for VmGuard {}
+
+impl Drop for VmGuard {
+    fn drop (&mut self) {
+        let _ = REGISTRY.add(metrics::LUA_VMS, &[], -1.0);
+        REGISTRY.remove(metrics::LUA_MEMORY, &[self.id.clone()]);
+    }
+}
+
+/// A metric declared from Lua
+struct Metric {
+    name: String,
+    kind: Kind,
+    labels: Vec<String>,
+}
+
+impl Metric {
+    /// Reads the optional number and table of labels a method was called with, in any order
+    fn args (&self, args: Variadic<Value>, default: f64) -> LuaResult<(f64, Vec<String>)> {
+        let mut value = default;
+        let mut labels = HashMap::new();
+        for arg in args.into_iter() {
+            match arg {
+                Value::Integer(n) => value = n as f64,
+                Value::Number(n) => value = n,
+                Value::Table(table) => for pair in table.pairs::<String, String>() {
+                    let (name, label) = pair?;
+                    labels.insert(name, label);
+                },
+                Value::Nil => (),
+                _ => return Err(LuaError::external(format_err!("metric {} takes a number and a table of labels", self.name))),
+            }
+        }
+
+        let values = self.labels.iter()
+            .map(|name| labels.remove(name).ok_or_else(|| LuaError::external(format_err!("metric {} needs the label {}", self.name, name))))
+            .collect::<LuaResult<Vec<_>>>()?;
+        if let Some(name) = labels.keys().next() {
+            return Err(LuaError::external(format_err!("metric {} has no label {}", self.name, name)));
+        }
+        Ok((value, values))
+    }
+}
+
+impl LuaUserData for Metric {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("inc", |_, this, args: Variadic<Value>| {
+            let (value, labels) = this.args(args, 1.0)?;
+            let result = match this.kind {
+                Kind::Counter => REGISTRY.inc(&this.name, &labels, value),
+                _ => REGISTRY.add(&this.name, &labels, value),
+            };
+            result.map_err(LuaError::external)
+        });
+
+        methods.add_method("dec", |_, this, args: Variadic<Value>| {
+            let (value, labels) = this.args(args, 1.0)?;
+            REGISTRY.add(&this.name, &labels, -value).map_err(LuaError::external)
+        });
+
+        methods.add_method("set", |_, this, args: Variadic<Value>| {
+            let (value, labels) = this.args(args, 0.0)?;
+            REGISTRY.set(&this.name, &labels, value).map_err(LuaError::external)
+        });
+
+        methods.add_method("observe", |_, this, args: Variadic<Value>| {
+            let (value, labels) = this.args(args, 0.0)?;
+            REGISTRY.observe(&this.name, &labels, value).map_err(LuaError::external)
+        });
+
+        methods.add_method("get", |_, this, args: Variadic<Value>| {
+            let (_, labels) = this.args(args, 0.0)?;
+            Ok(REGISTRY.get(&this.name, &labels).unwrap_or(0.0))
+        });
+    }
+}
+
+fn declare (kind: Kind, name: String, help: Option<String>, labels: Option<Vec<String>>, buckets: Option<Vec<f64>>) -> LuaResult<Metric> {
+    let labels = labels.unwrap_or_default();
+    REGISTRY.register(&name, &help.unwrap_or_default(), kind, &labels, buckets.as_ref().map(Vec::as_slice))
+        .map_err(LuaError::external)?;
+    Ok(Metric { name, kind, labels })
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    let id = NEXT_VM.fetch_add(1, Ordering::SeqCst).to_string();
+    REGISTRY.add(metrics::LUA_VMS, &[], 1.0)?;
+    REGISTRY.inc(metrics::LUA_VMS_CREATED, &[], 1.0)?;
+
+    lua.context(|lua| {
+        lua.set_named_registry_value("metrics_vm", VmGuard { id: id.clone() })?;
+
+        let module = lua.create_table()?;
+
+        module.set("counter", lua.create_function(|_, (name, help, labels): (String, Option<String>, Option<Vec<String>>)| {
+            declare(Kind::Counter, name, help, labels, None)
+        })?)?;
+
+        module.set("gauge", lua.create_function(|_, (name, help, labels): (String, Option<String>, Option<Vec<String>>)| {
+            declare(Kind::Gauge, name, help, labels, None)
+        })?)?;
+
+        module.set("histogram", lua.create_function(|_, (name, help, labels, buckets): (String, Option<String>, Option<Vec<String>>, Option<Vec<f64>>)| {
+            declare(Kind::Histogram, name, help, labels, buckets)
+        })?)?;
+
+        module.set("render", lua.create_function(|_, ()| {
+            Ok(REGISTRY.render())
+        })?)?;
+
+        // Called after each request, as the memory of a VM can only be read from its own thread
+        module.set("record_vm_memory", lua.create_function(move |lua, ()| {
+            let collectgarbage: LuaFunction = lua.globals().get("collectgarbage")?;
+            let kilobytes: f64 = collectgarbage.call("count")?;
+            REGISTRY.set(metrics::LUA_MEMORY, &[id.clone()], kilobytes * 1024.0).map_err(LuaError::external)
+        })?)?;
+
+        lua.globals().set("metrics", module)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lua_metrics () {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                local orders = metrics.counter("test_orders_total", "Orders placed", { "kind" })
+                orders:inc({ kind = "book" })
+                orders:inc(2, { kind = "book" })
+                assert(orders:get({ kind = "book" }) == 3)
+                assert(not pcall(orders.inc, orders, { color = "red" }))
+
+                local queue = metrics.gauge("test_queue_size")
+                queue:set(10)
+                queue:dec(4)
+                assert(queue:get() == 6)
+
+                local latency = metrics.histogram("test_latency_seconds", "Latency", nil, { 0.1, 1 })
+                latency:observe(0.5)
+                assert(metrics.render():find('test_latency_seconds_bucket{le="1"} 1', 1, true))
+
+                metrics.record_vm_memory()
+            "#).exec().unwrap();
+        });
+    }
+}
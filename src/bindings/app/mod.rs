@@ -1,6 +1,12 @@
+pub mod actor;
+pub mod cache;
 pub mod git;
 pub mod log;
 pub mod markdown;
+pub mod metrics;
+pub mod queue;
+pub mod scheduler;
+pub mod sqlite;
 pub mod tera;
 pub mod handlebars;
 
@@ -16,9 +22,15 @@ pub mod tantivy {
 }
 
 pub fn init(lua: &Lua) -> crate::Result<()> {
+    actor::init(&lua)?;
+    cache::init(&lua)?;
     git::init(&lua)?;
     log::init(&lua)?;
     markdown::init(&lua)?;
+    metrics::init(&lua)?;
+    queue::init(&lua)?;
+    scheduler::init(&lua)?;
+    sqlite::init(&lua)?;
     tantivy::init(&lua)?;
     tera::init(&lua)?;
     handlebars::init(&lua)?;
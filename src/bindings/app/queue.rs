@@ -0,0 +1,607 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use actix::prelude::*;
+use actix_lua::LuaMessage;
+use rlua::prelude::*;
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::{hook_message, AppState, LuaAddr};
+
+/// Name of the registry table holding the job functions of a VM, by job name
+const HANDLERS: &str = "queue_handlers";
+
+/// How often idle workers look for due jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_BACKOFF: f64 = 2.0;
+
+lazy_static! {
+    /// The queue directories that have workers, each may only be served by one app
+    static ref WORKER_DIRS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+fn now_millis () -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+/// A job as stored on disk, one JSON file per job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub name: String,
+    pub payload: Value,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// Seconds to wait before the first retry, doubled on every further retry
+    pub backoff: f64,
+    /// Milliseconds since the epoch
+    pub run_at: u64,
+    pub created_at: u64,
+    pub last_error: Option<String>,
+}
+
+/// The states of a job, each kept in a directory of its own
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    Pending,
+    Running,
+    Failed,
+}
+
+impl State {
+    fn dir_name (self) -> &'static str {
+        match self {
+            State::Pending => "pending",
+            State::Running => "running",
+            State::Failed => "failed",
+        }
+    }
+}
+
+/// A queue stored in a directory, safe to share between workers as jobs
+/// are claimed by renaming their file
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn open<P: AsRef<Path>> (dir: P) -> crate::Result<Self> {
+        let store = Store { dir: dir.as_ref().to_path_buf() };
+        for state in &[State::Pending, State::Running, State::Failed] {
+            fs::create_dir_all(store.dir.join(state.dir_name()))?;
+        }
+        Ok(store)
+    }
+
+    fn path (&self, state: State, id: &str) -> PathBuf {
+        self.dir.join(state.dir_name()).join(format!("{}.json", id))
+    }
+
+    /// Writes to a temporary file first, so readers never see half a job,
+    /// and syncs it before and after the rename so it survives a crash
+    fn write (&self, state: State, job: &Job) -> crate::Result<()> {
+        let tmp = self.dir.join(format!(".{}.tmp", job.id));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(job)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.path(state, &job.id))?;
+        sync_dir(&self.dir.join(state.dir_name()))?;
+        Ok(())
+    }
+
+    /// Moves a job with a single rename, so it is never in two states at once
+    fn move_job (&self, id: &str, from: State, to: State) -> crate::Result<()> {
+        fs::rename(self.path(from, id), self.path(to, id))?;
+        sync_dir(&self.dir.join(to.dir_name()))?;
+        sync_dir(&self.dir.join(from.dir_name()))?;
+        Ok(())
+    }
+
+    fn read (&self, state: State, id: &str) -> crate::Result<Job> {
+        Ok(serde_json::from_slice(&fs::read(self.path(state, id))?)?)
+    }
+
+    pub fn push (&self, name: &str, payload: Value, delay: Duration, max_attempts: u32, backoff: f64) -> crate::Result<String> {
+        let now = now_millis();
+        let job = Job {
+            id: Ulid::new().to_string(),
+            name: name.to_owned(),
+            payload,
+            attempts: 0,
+            max_attempts,
+            backoff,
+            run_at: now + delay.as_secs() * 1000 + u64::from(delay.subsec_millis()),
+            created_at: now,
+            last_error: None,
+        };
+        self.write(State::Pending, &job)?;
+        Ok(job.id)
+    }
+
+    /// Every job in `state`, oldest first. Files that are not valid jobs are
+    /// moved to `failed` rather than holding up the whole queue.
+    pub fn list (&self, state: State) -> crate::Result<Vec<Job>> {
+        let mut jobs = vec![];
+        for entry in fs::read_dir(self.dir.join(state.dir_name()))? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                // The job may have been claimed in the meantime
+                let data = match fs::read(&path) {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                };
+                match serde_json::from_slice::<Job>(&data) {
+                    Ok(job) => jobs.push(job),
+                    Err(err) => self.set_aside(state, &path, &err),
+                }
+            }
+        }
+        jobs.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(jobs)
+    }
+
+    fn set_aside (&self, state: State, path: &Path, err: &serde_json::Error) {
+        if state == State::Failed {
+            warn!("skipping unreadable job file {:?}: {}", path, err);
+            return;
+        }
+        error!("moving unreadable job file {:?} to failed: {}", path, err);
+        if let Some(name) = path.file_name() {
+            if let Err(err) = fs::rename(path, self.dir.join(State::Failed.dir_name()).join(name)) {
+                error!("could not move job file {:?}: {}", path, err);
+            }
+        }
+    }
+
+    /// Moves the next due job to `running`, unless another worker got it first
+    pub fn claim_next (&self) -> crate::Result<Option<Job>> {
+        let now = now_millis();
+        for job in self.list(State::Pending)? {
+            if job.run_at > now {
+                continue;
+            }
+            if fs::rename(self.path(State::Pending, &job.id), self.path(State::Running, &job.id)).is_ok() {
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get_running (&self, id: &str) -> crate::Result<Job> {
+        self.read(State::Running, id)
+    }
+
+    pub fn complete (&self, id: &str) -> crate::Result<()> {
+        Ok(fs::remove_file(self.path(State::Running, id))?)
+    }
+
+    /// Schedules a retry with exponential backoff, or moves the job to `failed`
+    /// once it ran out of attempts. Returns whether it will be retried.
+    pub fn fail (&self, id: &str, error: &str) -> crate::Result<bool> {
+        let mut job = self.read(State::Running, id)?;
+        job.attempts += 1;
+        job.last_error = Some(error.to_owned());
+
+        let retry = job.attempts < job.max_attempts;
+        if retry {
+            let delay = job.backoff * 2f64.powi(job.attempts as i32 - 1);
+            job.run_at = now_millis() + (delay * 1000.0) as u64;
+        }
+
+        // Updated where it is, then moved, so a crash in between leaves it running for `recover`
+        self.write(State::Running, &job)?;
+        self.move_job(id, State::Running, if retry { State::Pending } else { State::Failed })?;
+        Ok(retry)
+    }
+
+    /// Moves a failed job back to `pending`, with its attempts reset
+    pub fn retry (&self, id: &str) -> crate::Result<bool> {
+        let mut job = match self.read(State::Failed, id) {
+            Ok(job) => job,
+            Err(_) => return Ok(false),
+        };
+        job.attempts = 0;
+        job.run_at = now_millis();
+        self.write(State::Failed, &job)?;
+        self.move_job(id, State::Failed, State::Pending)?;
+        Ok(true)
+    }
+
+    pub fn remove (&self, id: &str) -> bool {
+        [State::Pending, State::Failed].iter().any(|state| fs::remove_file(self.path(*state, id)).is_ok())
+    }
+
+    /// Puts back jobs left running by a previous process that stopped midway
+    pub fn recover (&self) -> crate::Result<usize> {
+        let jobs = self.list(State::Running)?;
+        for job in jobs.iter() {
+            self.move_job(&job.id, State::Running, State::Pending)?;
+        }
+        Ok(jobs.len())
+    }
+}
+
+/// Makes the renames in `dir` durable; directories can't be synced on Windows
+fn sync_dir (dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// The queue directory of an app, from the `queue_path` setting
+pub fn dir_from_settings (settings: &Value) -> PathBuf {
+    PathBuf::from(settings.get("queue_path").and_then(Value::as_str).unwrap_or("queue"))
+}
+
+/// The queue directory of a site without a `queue_path` of its own, below the general one,
+/// so the workers of a site never claim the jobs of another
+pub fn site_dir (general: &Value, host: &str) -> PathBuf {
+    let name: String = host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    dir_from_settings(general).join("sites").join(name)
+}
+
+fn lua_store (lua: LuaContext) -> LuaResult<Store> {
+    let settings: Option<LuaValue> = lua.globals().get::<_, Option<LuaTable>>("torchbear")?
+        .map(|torchbear| torchbear.get("settings"))
+        .transpose()?;
+    let settings: Value = match settings {
+        Some(settings) => rlua_serde::from_value(settings)?,
+        None => Value::Null,
+    };
+    Store::open(dir_from_settings(&settings)).map_err(LuaError::external)
+}
+
+fn job_to_lua<'lua> (lua: LuaContext<'lua>, job: &Job) -> LuaResult<LuaValue<'lua>> {
+    rlua_serde::to_value(lua, job).map_err(LuaError::external)
+}
+
+fn jobs_to_lua<'lua> (lua: LuaContext<'lua>, jobs: Vec<Job>) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    for (index, job) in jobs.iter().enumerate() {
+        table.set(index + 1, job_to_lua(lua, job)?)?;
+    }
+    Ok(table)
+}
+
+fn number_option (options: &Option<LuaTable>, key: &str) -> LuaResult<Option<f64>> {
+    match options {
+        Some(options) => options.get(key),
+        None => Ok(None),
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        // queue.register("email", function (payload, job) ... end, { max_attempts = 5, backoff = 2 })
+        module.set("register", lua.create_function(|lua, (name, function, options): (String, LuaFunction, Option<LuaTable>)| {
+            let handlers = match lua.named_registry_value::<_, Option<LuaTable>>(HANDLERS)? {
+                Some(handlers) => handlers,
+                None => {
+                    let handlers = lua.create_table()?;
+                    lua.set_named_registry_value(HANDLERS, handlers.clone())?;
+                    handlers
+                }
+            };
+            let handler = lua.create_table()?;
+            handler.set("fn", function)?;
+            handler.set("max_attempts", number_option(&options, "max_attempts")?)?;
+            handler.set("backoff", number_option(&options, "backoff")?)?;
+            handlers.set(name, handler)
+        })?)?;
+
+        // queue.enqueue("email", { to = "..." }, { delay = 60 }) returns the job id
+        module.set("enqueue", lua.create_function(|lua, (name, payload, options): (String, LuaValue, Option<LuaTable>)| {
+            let payload: Value = rlua_serde::from_value(payload)?;
+
+            // Options given here take precedence over the ones given to `register`
+            let handler: Option<LuaTable> = match lua.named_registry_value::<_, Option<LuaTable>>(HANDLERS)? {
+                Some(handlers) => handlers.get(name.as_str())?,
+                None => None,
+            };
+            let registered = |key: &str| -> LuaResult<Option<f64>> {
+                match handler {
+                    Some(ref handler) => handler.get(key),
+                    None => Ok(None),
+                }
+            };
+            let max_attempts = match number_option(&options, "max_attempts")? {
+                Some(max_attempts) => Some(max_attempts),
+                None => registered("max_attempts")?,
+            };
+            let backoff = match number_option(&options, "backoff")? {
+                Some(backoff) => Some(backoff),
+                None => registered("backoff")?,
+            };
+            let delay = number_option(&options, "delay")?.unwrap_or(0.0).max(0.0);
+
+            lua_store(lua)?
+                .push(
+                    &name,
+                    payload,
+                    Duration::from_millis((delay * 1000.0) as u64),
+                    max_attempts.map(|n| n.max(1.0) as u32).unwrap_or(DEFAULT_MAX_ATTEMPTS),
+                    backoff.unwrap_or(DEFAULT_BACKOFF),
+                )
+                .map_err(LuaError::external)
+        })?)?;
+
+        module.set("pending", lua.create_function(|lua, ()| {
+            jobs_to_lua(lua, lua_store(lua)?.list(State::Pending).map_err(LuaError::external)?)
+        })?)?;
+
+        module.set("running", lua.create_function(|lua, ()| {
+            jobs_to_lua(lua, lua_store(lua)?.list(State::Running).map_err(LuaError::external)?)
+        })?)?;
+
+        module.set("failed", lua.create_function(|lua, ()| {
+            jobs_to_lua(lua, lua_store(lua)?.list(State::Failed).map_err(LuaError::external)?)
+        })?)?;
+
+        module.set("retry", lua.create_function(|lua, id: String| {
+            lua_store(lua)?.retry(&id).map_err(LuaError::external)
+        })?)?;
+
+        module.set("remove", lua.create_function(|lua, id: String| {
+            Ok(lua_store(lua)?.remove(&id))
+        })?)?;
+
+        // Called by the workers through the `queue.run` hook, returning { ok = true }
+        // or { ok = false, error = "..." } so the worker can record the error
+        module.set("run", lua.create_function(|lua, id: String| {
+            let job = lua_store(lua)?.get_running(&id).map_err(LuaError::external)?;
+            let result = lua.create_table()?;
+
+            let handler: Option<LuaTable> = match lua.named_registry_value::<_, Option<LuaTable>>(HANDLERS)? {
+                Some(handlers) => handlers.get(job.name.as_str())?,
+                None => None,
+            };
+            let outcome = match handler {
+                Some(handler) => {
+                    let function: LuaFunction = handler.get("fn")?;
+                    function.call::<_, ()>((rlua_serde::to_value(lua, &job.payload)?, job_to_lua(lua, &job)?))
+                        .map_err(|err| err.to_string())
+                },
+                None => Err(format!("no function registered for jobs named {}", job.name)),
+            };
+
+            match outcome {
+                Ok(()) => result.set("ok", true)?,
+                Err(err) => {
+                    result.set("ok", false)?;
+                    result.set("error", err)?;
+                },
+            }
+            Ok(result)
+        })?)?;
+
+        lua.globals().set("queue", module)?;
+
+        Ok(())
+    })
+}
+
+/// Whether the app running in `lua` registered any job function
+fn has_handlers (lua: &Lua) -> crate::Result<bool> {
+    lua.context(|lua| {
+        let handlers: Option<LuaTable> = lua.named_registry_value(HANDLERS)?;
+        Ok(match handlers {
+            Some(handlers) => handlers.pairs::<LuaValue, LuaValue>().next().is_some(),
+            None => false,
+        })
+    })
+}
+
+/// Takes due jobs from the queue one at a time and runs them on its own Lua actor
+pub struct Worker {
+    store: Store,
+    addr: LuaAddr,
+    busy: bool,
+}
+
+impl Worker {
+    fn poll (&mut self, ctx: &mut Context<Self>) {
+        if self.busy {
+            return;
+        }
+
+        let job = match self.store.claim_next() {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(err) => {
+                error!("could not read the job queue: {}", err);
+                return;
+            }
+        };
+
+        self.busy = true;
+        debug!("running job {} ({})", job.id, job.name);
+
+        let run = self.addr.send(hook_message("queue.run", LuaMessage::String(job.id.clone())))
+            .into_actor(self)
+            .then(move |res, worker, ctx| {
+                worker.busy = false;
+
+                let error = match res {
+                    Ok(LuaMessage::Table(mut result)) => match (result.remove("ok"), result.remove("error")) {
+                        (Some(LuaMessage::Boolean(true)), _) => None,
+                        (_, Some(LuaMessage::String(error))) => Some(error),
+                        _ => Some(String::from("the job failed")),
+                    },
+                    Ok(_) => Some(String::from("the job could not be run")),
+                    Err(err) => Some(err.to_string()),
+                };
+
+                let outcome = match error {
+                    None => worker.store.complete(&job.id).map(|_| debug!("job {} ({}) done", job.id, job.name)),
+                    Some(error) => worker.store.fail(&job.id, &error).map(|retry| if retry {
+                        warn!("job {} ({}) failed and will be retried: {}", job.id, job.name, error);
+                    } else {
+                        error!("job {} ({}) failed for good: {}", job.id, job.name, error);
+                    }),
+                };
+                if let Err(err) = outcome {
+                    error!("could not update job {}: {}", job.id, err);
+                }
+
+                // Look for the next job right away, there may be a backlog
+                worker.poll(ctx);
+                actix::fut::ok(())
+            });
+        ctx.spawn(run);
+    }
+}
+
+impl Actor for Worker {
+    type Context = Context<Self>;
+
+    fn started (&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(POLL_INTERVAL, |worker, ctx| worker.poll(ctx));
+    }
+}
+
+/// Starts the workers of the app of `state`, `queue_workers` of them (one by default),
+/// if it registered any job function
+pub fn start (state: &AppState) -> crate::Result<()> {
+    let vm = state.create_vm()?;
+    if !has_handlers(&vm)? {
+        return Ok(());
+    }
+
+    let dir = dir_from_settings(&state.settings);
+    let store = Store::open(&dir)?;
+    if !WORKER_DIRS.lock().unwrap().insert(fs::canonicalize(&dir)?) {
+        return Err(format_err!("queue_path {:?} is used by more than one app, each needs its own", dir));
+    }
+
+    let recovered = store.recover()?;
+    if recovered > 0 {
+        warn!("{} jobs were left running by the previous process, queued them again", recovered);
+    }
+
+    let workers = state.settings.get("queue_workers").and_then(Value::as_u64).unwrap_or(1).max(1);
+    let mut vm = Some(vm);
+    for _ in 0..workers {
+        let addr = match vm.take() {
+            Some(vm) => AppState::start_actor(vm),
+            None => state.create_addr(),
+        };
+        Worker { store: store.clone(), addr, busy: false }.start();
+    }
+    info!("started {} queue workers for {:?}", workers, state.init_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn retries_then_dead_letter () {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+
+        let id = store.push("email", json!({"to": "a@b.c"}), Duration::from_secs(0), 2, 0.0).unwrap();
+        assert_eq!(store.list(State::Pending).unwrap().len(), 1);
+
+        let job = store.claim_next().unwrap().unwrap();
+        assert_eq!(job.id, id);
+        assert_eq!(job.payload, json!({"to": "a@b.c"}));
+        assert!(store.claim_next().unwrap().is_none());
+
+        assert!(store.fail(&id, "timeout").unwrap());
+        assert!(store.list(State::Running).unwrap().is_empty());
+        assert_eq!(store.list(State::Pending).unwrap()[0].attempts, 1);
+        store.claim_next().unwrap().unwrap();
+        assert!(!store.fail(&id, "timeout again").unwrap());
+
+        let failed = store.list(State::Failed).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, 2);
+        assert_eq!(failed[0].last_error.as_ref().map(String::as_str), Some("timeout again"));
+
+        assert!(store.retry(&id).unwrap());
+        store.claim_next().unwrap().unwrap();
+        store.complete(&id).unwrap();
+        assert!(store.list(State::Pending).unwrap().is_empty());
+        assert!(store.list(State::Failed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreadable_jobs_are_set_aside () {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        fs::write(dir.path().join("pending").join("broken.json"), "{ not a job").unwrap();
+        let id = store.push("email", Value::Null, Duration::from_secs(0), 1, 1.0).unwrap();
+
+        assert_eq!(store.claim_next().unwrap().unwrap().id, id);
+        assert!(dir.path().join("failed").join("broken.json").exists());
+        assert!(store.list(State::Failed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn site_dirs () {
+        let general = serde_json::json!({ "queue_path": "/var/queue" });
+        assert_eq!(site_dir(&general, "*.example.com"), PathBuf::from("/var/queue/sites/_.example.com"));
+        assert_eq!(site_dir(&Value::Null, "[::1]"), PathBuf::from("queue/sites/___1_"));
+    }
+
+    #[test]
+    fn delayed_jobs_wait () {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        store.push("later", Value::Null, Duration::from_secs(60), 1, 1.0).unwrap();
+        assert!(store.claim_next().unwrap().is_none());
+    }
+
+    #[test]
+    fn lua_queue () {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            let settings = lua.create_table().unwrap();
+            settings.set("queue_path", dir.path().to_str().unwrap()).unwrap();
+            let torchbear = lua.create_table().unwrap();
+            torchbear.set("settings", settings).unwrap();
+            lua.globals().set("torchbear", torchbear).unwrap();
+
+            lua.load(r#"
+                sent = nil
+                queue.register("email", function (payload) sent = payload.to end, { max_attempts = 3 })
+                local id = queue.enqueue("email", { to = "someone" })
+                local pending = queue.pending()
+                assert(#pending == 1 and pending[1].max_attempts == 3)
+                job_id = id
+            "#).exec().unwrap();
+        });
+        assert!(has_handlers(&lua).unwrap());
+
+        let store = Store::open(dir.path()).unwrap();
+        store.claim_next().unwrap().unwrap();
+
+        lua.context(|lua| {
+            lua.load(r#"
+                local result = queue.run(job_id)
+                assert(result.ok and sent == "someone")
+            "#).exec().unwrap();
+        });
+    }
+}
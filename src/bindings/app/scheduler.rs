@@ -0,0 +1,297 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use actix::prelude::*;
+use actix_lua::LuaMessage;
+use chrono::Local;
+use rlua::prelude::*;
+use sodiumoxide::randombytes;
+
+use crate::{hook_message, AppState, LuaAddr};
+
+/// Name of the registry table holding the jobs of a VM, by name
+const JOBS: &str = "scheduler_jobs";
+
+#[derive(Clone)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+/// A job registered from Lua, read back from the VM that will run it
+#[derive(Clone)]
+pub struct Job {
+    pub name: String,
+    pub schedule: Schedule,
+    pub jitter: Duration,
+}
+
+impl Job {
+    /// Time until the next run, or `None` when a cron expression has no more dates
+    fn next_delay (&self) -> Option<Duration> {
+        let delay = match self.schedule {
+            Schedule::Every(interval) => interval,
+            Schedule::Cron(ref schedule) => {
+                let next = schedule.upcoming(Local).next()?;
+                (next - Local::now()).to_std().unwrap_or_else(|_| Duration::from_secs(0))
+            },
+        };
+
+        let jitter = self.jitter.as_secs() * 1000 + u64::from(self.jitter.subsec_millis());
+        if jitter == 0 {
+            return Some(delay);
+        }
+        let jitter = randombytes::randombytes_uniform(jitter.min(u64::from(u32::max_value())) as u32);
+        Some(delay + Duration::from_millis(u64::from(jitter)))
+    }
+}
+
+/// Parses intervals such as `30`, `"90s"`, `"5m"`, `"2h"` or `"1d"`, seconds by default
+pub fn parse_interval (interval: &str) -> Option<Duration> {
+    let interval = interval.trim();
+    let (number, unit) = match interval.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(index) => interval.split_at(index),
+        None => (interval, "s"),
+    };
+    let multiplier = match unit.trim() {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => return None,
+    };
+    let seconds = number.parse::<f64>().ok()? * multiplier;
+    if seconds > 0.0 {
+        Some(Duration::from_millis((seconds * 1000.0) as u64))
+    } else {
+        None
+    }
+}
+
+fn interval_from_lua (value: LuaValue) -> LuaResult<Duration> {
+    let interval = match value {
+        LuaValue::Integer(seconds) if seconds > 0 => Some(Duration::from_secs(seconds as u64)),
+        LuaValue::Number(seconds) if seconds > 0.0 => Some(Duration::from_millis((seconds * 1000.0) as u64)),
+        LuaValue::String(ref interval) => interval.to_str().ok().and_then(parse_interval),
+        _ => None,
+    };
+    interval.ok_or_else(|| LuaError::external(format_err!("invalid interval, expected seconds or a string such as \"5m\"")))
+}
+
+/// Reads the jobs registered in `lua`, usually by its `init.lua`
+pub fn jobs (lua: &Lua) -> crate::Result<Vec<Job>> {
+    lua.context(|lua| {
+        let table: Option<LuaTable> = lua.named_registry_value(JOBS)?;
+        let table = match table {
+            Some(table) => table,
+            None => return Ok(vec![]),
+        };
+
+        let mut jobs = vec![];
+        for pair in table.pairs::<String, LuaTable>() {
+            let (name, job) = pair?;
+            let schedule = match job.get::<_, Option<String>>("cron")? {
+                Some(expression) => Schedule::Cron(Box::new(cron::Schedule::from_str(&expression)
+                    .map_err(|err| format_err!("invalid cron expression for job {}: {}", name, err))?)),
+                None => Schedule::Every(Duration::from_millis(job.get::<_, u64>("every")?)),
+            };
+            jobs.push(Job {
+                name,
+                schedule,
+                jitter: Duration::from_millis(job.get::<_, Option<u64>>("jitter")?.unwrap_or(0)),
+            });
+        }
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(jobs)
+    })
+}
+
+fn register<'lua> (lua: LuaContext<'lua>, name: String, schedule: LuaTable<'lua>, function: LuaFunction<'lua>, options: Option<LuaTable<'lua>>) -> LuaResult<()> {
+    let jobs = match lua.named_registry_value::<_, Option<LuaTable>>(JOBS)? {
+        Some(jobs) => jobs,
+        None => {
+            let jobs = lua.create_table()?;
+            lua.set_named_registry_value(JOBS, jobs.clone())?;
+            jobs
+        }
+    };
+
+    if let Some(options) = options {
+        if let Some(jitter) = options.get::<_, Option<LuaValue>>("jitter")? {
+            let jitter = interval_from_lua(jitter)?;
+            schedule.set("jitter", jitter.as_secs() * 1000 + u64::from(jitter.subsec_millis()))?;
+        }
+        // Every run of a job goes to the same VM, so runs could only ever queue up
+        if options.get::<_, Option<LuaValue>>("overlap")?.is_some() {
+            return Err(LuaError::external(format_err!("jobs don't support overlap, a run starting while the previous one is still going is skipped")));
+        }
+    }
+
+    schedule.set("fn", function)?;
+    jobs.set(name, schedule)
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        // scheduler.every("cleanup", "10m", function () ... end, { jitter = "30s" })
+        module.set("every", lua.create_function(|lua, (name, interval, function, options): (String, LuaValue, LuaFunction, Option<LuaTable>)| {
+            let interval = interval_from_lua(interval)?;
+            let schedule = lua.create_table()?;
+            schedule.set("every", interval.as_secs() * 1000 + u64::from(interval.subsec_millis()))?;
+            register(lua, name, schedule, function, options)
+        })?)?;
+
+        // scheduler.cron("report", "0 0 6 * * Mon-Fri", function () ... end), with seconds first
+        module.set("cron", lua.create_function(|lua, (name, expression, function, options): (String, String, LuaFunction, Option<LuaTable>)| {
+            cron::Schedule::from_str(&expression)
+                .map_err(|err| LuaError::external(format_err!("invalid cron expression {:?}: {}", expression, err)))?;
+            let schedule = lua.create_table()?;
+            schedule.set("cron", expression)?;
+            register(lua, name, schedule, function, options)
+        })?)?;
+
+        // Called by the job runners through the `scheduler.run` hook
+        module.set("run", lua.create_function(|lua, name: String| {
+            let jobs: Option<LuaTable> = lua.named_registry_value(JOBS)?;
+            let job: Option<LuaTable> = match jobs {
+                Some(jobs) => jobs.get(name.as_str())?,
+                None => None,
+            };
+            let job = job.ok_or_else(|| LuaError::external(format_err!("no job named {}", name)))?;
+            job.get::<_, LuaFunction>("fn")?.call::<_, ()>(())?;
+            Ok(true)
+        })?)?;
+
+        lua.globals().set("scheduler", module)?;
+
+        Ok(())
+    })
+}
+
+/// Runs one job on its own Lua actor, so a slow job doesn't hold up the others
+///
+/// Runs of the same job never overlap: one that comes due while the
+/// previous run is still going is skipped.
+pub struct JobRunner {
+    job: Job,
+    addr: LuaAddr,
+    running: bool,
+}
+
+impl JobRunner {
+    fn schedule_next (&self, ctx: &mut Context<Self>) {
+        match self.job.next_delay() {
+            Some(delay) => {
+                ctx.run_later(delay, |runner, ctx| {
+                    runner.run(ctx);
+                    runner.schedule_next(ctx);
+                });
+            },
+            None => warn!("scheduled job {} has no more runs", self.job.name),
+        }
+    }
+
+    fn run (&mut self, ctx: &mut Context<Self>) {
+        if self.running {
+            warn!("skipping scheduled job {}, its previous run has not finished", self.job.name);
+            return;
+        }
+        self.running = true;
+
+        let name = self.job.name.clone();
+        let start = Instant::now();
+        debug!("running scheduled job {}", name);
+
+        let run = self.addr.send(hook_message("scheduler.run", LuaMessage::String(name.clone())))
+            .into_actor(self)
+            .then(move |res, runner, _| {
+                runner.running = false;
+                match res {
+                    Ok(LuaMessage::Boolean(true)) => debug!("scheduled job {} finished in {:?}", name, start.elapsed()),
+                    Ok(_) => error!("scheduled job {} failed", name),
+                    Err(err) => error!("could not run scheduled job {}: {}", name, err),
+                }
+                actix::fut::ok(())
+            });
+        ctx.spawn(run);
+    }
+}
+
+impl Actor for JobRunner {
+    type Context = Context<Self>;
+
+    fn started (&mut self, ctx: &mut Self::Context) {
+        self.schedule_next(ctx);
+    }
+}
+
+/// Starts a runner for every job registered by the app of `state`
+///
+/// The first VM finds out which jobs there are and runs the first of them,
+/// the other jobs get VMs of their own.
+pub fn start (state: &AppState) -> crate::Result<()> {
+    let vm = state.create_vm()?;
+    let jobs = jobs(&vm)?;
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let mut vm = Some(vm);
+    for job in jobs {
+        let addr = match vm.take() {
+            Some(vm) => AppState::start_actor(vm),
+            None => state.create_addr(),
+        };
+        info!("scheduled job {} for {:?}", job.name, state.init_path);
+        JobRunner { job, addr, running: false }.start();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals () {
+        assert_eq!(parse_interval("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_interval("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_interval("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_interval("1.5h"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_interval("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_interval("0"), None);
+        assert_eq!(parse_interval("soon"), None);
+    }
+
+    #[test]
+    fn lua_scheduler () {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                ran = 0
+                scheduler.every("count", "5m", function () ran = ran + 1 end, { jitter = 10 })
+                scheduler.cron("report", "0 0 6 * * Mon-Fri", function () end)
+                assert(not pcall(scheduler.cron, "bad", "every day", function () end))
+                assert(not pcall(scheduler.every, "overlapping", "1m", function () end, { overlap = true }))
+                scheduler.run("count")
+                assert(ran == 1)
+            "#).exec().unwrap();
+        });
+
+        let jobs = jobs(&lua).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].name, "count");
+        assert_eq!(jobs[0].jitter, Duration::from_secs(10));
+        match jobs[0].schedule {
+            Schedule::Every(interval) => assert_eq!(interval, Duration::from_secs(300)),
+            _ => panic!("expected an interval"),
+        }
+        assert_eq!(jobs[1].name, "report");
+    }
+}
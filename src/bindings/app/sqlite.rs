@@ -0,0 +1,499 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use rlua::prelude::*;
+use rusqlite::{
+    types::{Value, ValueRef},
+    Connection, OpenFlags, ToSql,
+};
+
+const DEFAULT_POOL_SIZE: usize = 4;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// At most `size` connections to one database file, shared by every VM
+struct Pool {
+    path: String,
+    connections: Mutex<Connections>,
+    /// Signalled whenever a connection is given back or closed
+    released: Condvar,
+    size: usize,
+}
+
+struct Connections {
+    idle: Vec<Connection>,
+    /// Idle and taken connections
+    open: usize,
+}
+
+lazy_static! {
+    static ref POOLS: Mutex<HashMap<String, Arc<Pool>>> = Mutex::new(HashMap::new());
+}
+
+impl Pool {
+    /// The pool of `path`, created with `size` connections (or the default) by the first caller
+    fn get (path: &str, size: Option<usize>) -> Arc<Pool> {
+        let pool = POOLS.lock().unwrap()
+            .entry(path.to_owned())
+            .or_insert_with(|| Arc::new(Pool {
+                path: path.to_owned(),
+                connections: Mutex::new(Connections { idle: vec![], open: 0 }),
+                released: Condvar::new(),
+                size: size.unwrap_or(DEFAULT_POOL_SIZE).max(1),
+            }))
+            .clone();
+        if let Some(size) = size {
+            if size.max(1) != pool.size {
+                warn!("sqlite database {} is already open with a pool_size of {}, ignoring pool_size {}", path, pool.size, size);
+            }
+        }
+        pool
+    }
+
+    fn connect (&self) -> rusqlite::Result<Connection> {
+        let conn = if self.path == ":memory:" {
+            // A shared cache, so that every connection of the pool sees the same database
+            let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI;
+            Connection::open_with_flags("file::memory:?cache=shared", flags)?
+        } else {
+            Connection::open(&self.path)?
+        };
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(conn)
+    }
+
+    /// Takes an idle connection or opens a new one, waiting up to the busy timeout
+    /// for one to be given back once `size` connections are open
+    fn take (&self) -> crate::Result<Connection> {
+        let deadline = Instant::now() + BUSY_TIMEOUT;
+        let mut connections = self.connections.lock().unwrap();
+        loop {
+            if let Some(conn) = connections.idle.pop() {
+                return Ok(conn);
+            }
+            if connections.open < self.size {
+                connections.open += 1;
+                drop(connections);
+                return self.connect().map_err(|err| {
+                    self.close();
+                    format_err!("{}", err)
+                });
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format_err!("all {} connections to sqlite database {} are in use", self.size, self.path));
+            }
+            connections = self.released.wait_timeout(connections, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Keeps `conn` for later, unless it was left in the middle of a transaction
+    fn give_back (&self, conn: Connection) {
+        if !conn.is_autocommit() {
+            drop(conn);
+            return self.close();
+        }
+        self.connections.lock().unwrap().idle.push(conn);
+        self.released.notify_one();
+    }
+
+    /// Makes room for a new connection once one is dropped
+    fn close (&self) {
+        self.connections.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// Where a database handle gets its connection from
+#[derive(Clone)]
+enum Handle {
+    /// A connection from the pool for every call
+    Pool(Arc<Pool>),
+    /// The connection of a transaction, gone once the transaction ends
+    Transaction(Arc<Mutex<Option<Connection>>>),
+}
+
+impl Handle {
+    fn with_conn<F, R> (&self, f: F) -> LuaResult<R>
+        where F: FnOnce(&Connection) -> rusqlite::Result<R>
+    {
+        match self {
+            Handle::Pool(pool) => {
+                let conn = pool.take().map_err(LuaError::external)?;
+                let result = f(&conn);
+                pool.give_back(conn);
+                result.map_err(LuaError::external)
+            },
+            Handle::Transaction(conn) => {
+                let conn = conn.lock().unwrap();
+                let conn = conn.as_ref()
+                    .ok_or_else(|| LuaError::external(format_err!("the transaction has already ended")))?;
+                f(conn).map_err(LuaError::external)
+            },
+        }
+    }
+}
+
+enum Params {
+    Positional(Vec<Value>),
+    Named(Vec<(String, Value)>),
+}
+
+fn value_from_lua (value: LuaValue) -> LuaResult<Value> {
+    Ok(match value {
+        LuaValue::Nil => Value::Null,
+        LuaValue::Boolean(b) => Value::Integer(b as i64),
+        LuaValue::Integer(n) => Value::Integer(n),
+        LuaValue::Number(n) => Value::Real(n),
+        LuaValue::String(s) => match s.to_str() {
+            Ok(text) => Value::Text(text.to_owned()),
+            Err(_) => Value::Blob(s.as_bytes().to_vec()),
+        },
+        value => return Err(LuaError::external(format_err!("can't bind a {} as an SQL parameter", value.type_name()))),
+    })
+}
+
+/// Reads parameters given as a sequence (`?`), a table of names (`:name`) or a single value
+fn params_from_lua (params: Option<LuaValue>) -> LuaResult<Params> {
+    match params {
+        None | Some(LuaValue::Nil) => Ok(Params::Positional(vec![])),
+        Some(LuaValue::Table(table)) => {
+            if table.raw_len() > 0 {
+                let values = table.sequence_values::<LuaValue>()
+                    .map(|value| value.and_then(value_from_lua))
+                    .collect::<LuaResult<Vec<_>>>()?;
+                return Ok(Params::Positional(values));
+            }
+            let mut named = vec![];
+            for pair in table.pairs::<String, LuaValue>() {
+                let (name, value) = pair?;
+                let name = if name.starts_with(|c| c == ':' || c == '@' || c == '$') { name } else { format!(":{}", name) };
+                named.push((name, value_from_lua(value)?));
+            }
+            Ok(Params::Named(named))
+        },
+        Some(value) => Ok(Params::Positional(vec![value_from_lua(value)?])),
+    }
+}
+
+type Row = Vec<(String, Value)>;
+
+fn query (conn: &Connection, sql: &str, params: &Params, limit: Option<usize>) -> rusqlite::Result<Vec<Row>> {
+    let mut statement = conn.prepare_cached(sql)?;
+    let columns: Vec<String> = statement.column_names().iter().map(|name| name.to_string()).collect();
+
+    let mut rows = match params {
+        Params::Positional(values) => statement.query(values)?,
+        Params::Named(values) => {
+            let values: Vec<(&str, &dyn ToSql)> = values.iter().map(|(name, value)| (name.as_str(), value as &dyn ToSql)).collect();
+            statement.query_named(&values)?
+        },
+    };
+
+    let mut result = vec![];
+    while let Some(row) = rows.next()? {
+        let mut values = Vec::with_capacity(columns.len());
+        for (index, name) in columns.iter().enumerate() {
+            let value = match row.get_raw(index) {
+                ValueRef::Null => Value::Null,
+                ValueRef::Integer(n) => Value::Integer(n),
+                ValueRef::Real(n) => Value::Real(n),
+                ValueRef::Text(text) => Value::Blob(text.to_vec()),
+                ValueRef::Blob(blob) => Value::Blob(blob.to_vec()),
+            };
+            values.push((name.clone(), value));
+        }
+        result.push(values);
+        if limit.map_or(false, |limit| result.len() >= limit) {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+/// Runs a statement, returning the number of changed rows and the last inserted row id
+fn execute (conn: &Connection, sql: &str, params: &Params) -> rusqlite::Result<(usize, i64)> {
+    let mut statement = conn.prepare_cached(sql)?;
+    let changes = match params {
+        Params::Positional(values) => statement.execute(values)?,
+        Params::Named(values) => {
+            let values: Vec<(&str, &dyn ToSql)> = values.iter().map(|(name, value)| (name.as_str(), value as &dyn ToSql)).collect();
+            statement.execute_named(&values)?
+        },
+    };
+    Ok((changes, conn.last_insert_rowid()))
+}
+
+fn row_to_lua<'lua> (lua: LuaContext<'lua>, row: Row) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    for (name, value) in row {
+        match value {
+            Value::Null => (),
+            Value::Integer(n) => table.set(name, n)?,
+            Value::Real(n) => table.set(name, n)?,
+            Value::Text(text) => table.set(name, text)?,
+            Value::Blob(blob) => table.set(name, lua.create_string(&blob)?)?,
+        }
+    }
+    Ok(table)
+}
+
+fn rows_to_lua<'lua> (lua: LuaContext<'lua>, rows: Vec<Row>) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+    for (index, row) in rows.into_iter().enumerate() {
+        table.set(index + 1, row_to_lua(lua, row)?)?;
+    }
+    Ok(table)
+}
+
+/// Applies the `.sql` files of `dir` not applied yet, in the order of their names,
+/// each in a transaction of its own. Returns the names of the applied files.
+fn migrate (conn: &Connection, dir: &Path) -> crate::Result<Vec<String>> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS _migrations (name TEXT PRIMARY KEY, applied_at INTEGER NOT NULL)")
+        .map_err(|err| format_err!("{}", err))?;
+
+    let mut files: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == "sql"))
+        .collect();
+    files.sort();
+
+    let mut applied = vec![];
+    for path in files {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_owned();
+        let done: i64 = conn.query_row("SELECT COUNT(*) FROM _migrations WHERE name = ?", &[&name], |row| row.get(0))
+            .map_err(|err| format_err!("{}", err))?;
+        if done > 0 {
+            continue;
+        }
+
+        let sql = fs::read_to_string(&path)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let result = conn.execute_batch("BEGIN")
+            .and_then(|_| conn.execute_batch(&sql))
+            .and_then(|_| conn.execute("INSERT INTO _migrations (name, applied_at) VALUES (?, ?)", &[&name as &dyn ToSql, &now]))
+            .and_then(|_| conn.execute_batch("COMMIT"));
+        if let Err(err) = result {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(format_err!("migration {} failed: {}", name, err));
+        }
+        info!("applied migration {}", name);
+        applied.push(name);
+    }
+    Ok(applied)
+}
+
+struct Database {
+    handle: Handle,
+}
+
+/// A statement prepared once per pooled connection, through the statement cache
+struct Statement {
+    handle: Handle,
+    sql: String,
+}
+
+impl LuaUserData for Statement {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("query", |lua, this, params: Option<LuaValue>| {
+            let params = params_from_lua(params)?;
+            let rows = this.handle.with_conn(|conn| query(conn, &this.sql, &params, None))?;
+            rows_to_lua(lua, rows)
+        });
+
+        methods.add_method("query_row", |lua, this, params: Option<LuaValue>| {
+            let params = params_from_lua(params)?;
+            let rows = this.handle.with_conn(|conn| query(conn, &this.sql, &params, Some(1)))?;
+            rows.into_iter().next().map(|row| row_to_lua(lua, row)).transpose()
+        });
+
+        methods.add_method("execute", |_, this, params: Option<LuaValue>| {
+            let params = params_from_lua(params)?;
+            let (changes, _) = this.handle.with_conn(|conn| execute(conn, &this.sql, &params))?;
+            Ok(changes)
+        });
+
+        methods.add_method("insert", |_, this, params: Option<LuaValue>| {
+            let params = params_from_lua(params)?;
+            let (_, id) = this.handle.with_conn(|conn| execute(conn, &this.sql, &params))?;
+            Ok(id)
+        });
+    }
+}
+
+impl LuaUserData for Database {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("prepare", |_, this, sql: String| {
+            // Fails early on syntax errors
+            this.handle.with_conn(|conn| conn.prepare_cached(&sql).map(|_| ()))?;
+            Ok(Statement { handle: this.handle.clone(), sql })
+        });
+
+        methods.add_method("query", |lua, this, (sql, params): (String, Option<LuaValue>)| {
+            let params = params_from_lua(params)?;
+            let rows = this.handle.with_conn(|conn| query(conn, &sql, &params, None))?;
+            rows_to_lua(lua, rows)
+        });
+
+        methods.add_method("query_row", |lua, this, (sql, params): (String, Option<LuaValue>)| {
+            let params = params_from_lua(params)?;
+            let rows = this.handle.with_conn(|conn| query(conn, &sql, &params, Some(1)))?;
+            rows.into_iter().next().map(|row| row_to_lua(lua, row)).transpose()
+        });
+
+        methods.add_method("execute", |_, this, (sql, params): (String, Option<LuaValue>)| {
+            let params = params_from_lua(params)?;
+            let (changes, _) = this.handle.with_conn(|conn| execute(conn, &sql, &params))?;
+            Ok(changes)
+        });
+
+        // Like `execute`, returning the id of the inserted row
+        methods.add_method("insert", |_, this, (sql, params): (String, Option<LuaValue>)| {
+            let params = params_from_lua(params)?;
+            let (_, id) = this.handle.with_conn(|conn| execute(conn, &sql, &params))?;
+            Ok(id)
+        });
+
+        // Runs several statements without parameters, such as a schema
+        methods.add_method("exec", |_, this, sql: String| {
+            this.handle.with_conn(|conn| conn.execute_batch(&sql))
+        });
+
+        // db:transaction(function (tx) ... end) commits when the function returns
+        // and rolls back when it raises an error, which is raised again
+        methods.add_method("transaction", |_, this, function: LuaFunction| {
+            let pool = match this.handle {
+                Handle::Pool(ref pool) => pool.clone(),
+                Handle::Transaction(_) => return Err(LuaError::external(format_err!("transactions can't be nested"))),
+            };
+
+            let conn = pool.take().map_err(LuaError::external)?;
+            if let Err(err) = conn.execute_batch("BEGIN") {
+                pool.give_back(conn);
+                return Err(LuaError::external(err));
+            }
+            let shared = Arc::new(Mutex::new(Some(conn)));
+
+            let result = function.call::<_, LuaValue>(Database { handle: Handle::Transaction(shared.clone()) });
+
+            let conn = shared.lock().unwrap().take().expect("transaction connection");
+            let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+            let ended = conn.execute_batch(end);
+            if ended.is_err() && result.is_ok() {
+                let _ = conn.execute_batch("ROLLBACK");
+            }
+            pool.give_back(conn);
+
+            let value = result?;
+            ended.map_err(LuaError::external)?;
+            Ok(value)
+        });
+
+        methods.add_method("migrate", |_, this, dir: String| {
+            let mut applied = None;
+            this.handle.with_conn(|conn| {
+                applied = Some(migrate(conn, Path::new(&dir)));
+                Ok(())
+            })?;
+            applied.expect("migrations ran").map_err(LuaError::external)
+        });
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        // sqlite.open("data/app.db", { pool_size = 4 }), or ":memory:" for a database in memory.
+        // The pool is shared by every VM, so only the first `pool_size` of a path counts.
+        module.set("open", lua.create_function(|_, (path, options): (String, Option<LuaTable>)| {
+            let size = match options {
+                Some(options) => options.get::<_, Option<usize>>("pool_size")?,
+                None => None,
+            };
+            let pool = Pool::get(&path, size);
+            // Fails early when the file can't be opened
+            let conn = pool.take().map_err(LuaError::external)?;
+            pool.give_back(conn);
+            Ok(Database { handle: Handle::Pool(pool) })
+        })?)?;
+
+        lua.globals().set("sqlite", module)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_limits_connections () {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pool.db");
+        let pool = Pool::get(path.to_str().unwrap(), Some(1));
+        assert!(Arc::ptr_eq(&pool, &Pool::get(path.to_str().unwrap(), Some(2))));
+
+        let conn = pool.take().unwrap();
+        let started = Instant::now();
+        assert!(pool.take().is_err());
+        assert!(started.elapsed() >= BUSY_TIMEOUT);
+
+        pool.give_back(conn);
+        let conn = pool.take().unwrap();
+        conn.execute_batch("BEGIN").unwrap();
+        // Dropped as it is still in a transaction, which makes room for a new one
+        pool.give_back(conn);
+        pool.take().unwrap();
+    }
+
+    #[test]
+    fn lua_sqlite () {
+        let dir = tempfile::tempdir().unwrap();
+        let migrations = dir.path().join("migrations");
+        fs::create_dir(&migrations).unwrap();
+        fs::write(migrations.join("001_users.sql"), "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL, score REAL);").unwrap();
+        fs::write(migrations.join("002_index.sql"), "CREATE INDEX users_name ON users (name);").unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("db_path", dir.path().join("test.db").to_str().unwrap()).unwrap();
+            lua.globals().set("migrations", migrations.to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local db = sqlite.open(db_path)
+                local applied = db:migrate(migrations)
+                assert(#applied == 2 and applied[1] == "001_users.sql")
+                assert(#db:migrate(migrations) == 0)
+
+                local id = db:insert("INSERT INTO users (name, score) VALUES (?, ?)", { "ana", 1.5 })
+                assert(id == 1)
+                db:execute("INSERT INTO users (name) VALUES (:name)", { name = "bo" })
+
+                local insert = db:prepare("INSERT INTO users (name) VALUES (?)")
+                insert:execute("cy")
+
+                local rows = db:query("SELECT * FROM users ORDER BY id")
+                assert(#rows == 3)
+                assert(rows[1].name == "ana" and rows[1].score == 1.5)
+                assert(rows[2].score == nil)
+
+                assert(not pcall(db.transaction, db, function (tx)
+                    tx:execute("DELETE FROM users")
+                    error("changed my mind")
+                end))
+                assert(db:query_row("SELECT COUNT(*) AS n FROM users").n == 3)
+
+                db:transaction(function (tx)
+                    tx:execute("DELETE FROM users WHERE name = ?", "cy")
+                end)
+                assert(db:query_row("SELECT COUNT(*) AS n FROM users").n == 2)
+                assert(db:query_row("SELECT * FROM users WHERE name = ?", "nobody") == nil)
+            "#).exec().unwrap();
+        });
+    }
+}
@@ -0,0 +1,376 @@
+use rlua::prelude::*;
+use rlua::{UserData, UserDataMethods};
+use flate2::{Compression, write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder}};
+use std::{
+    fs,
+    io::{self, Write},
+    mem,
+    sync::{Arc, Mutex},
+};
+use xz2::write::{XzDecoder, XzEncoder};
+
+/// Supported formats; `deflate` is the zlib-wrapped stream used by `Content-Encoding: deflate`
+#[derive(Clone, Copy)]
+pub(super) enum Algorithm {
+    Gzip,
+    Deflate,
+    Zstd,
+    Brotli,
+    Xz,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> LuaResult<Self> {
+        match name {
+            "gzip" | "gz" => Ok(Algorithm::Gzip),
+            "deflate" | "zlib" => Ok(Algorithm::Deflate),
+            "zstd" => Ok(Algorithm::Zstd),
+            "brotli" | "br" => Ok(Algorithm::Brotli),
+            "xz" => Ok(Algorithm::Xz),
+            _ => Err(LuaError::external(format_err!("unsupported compression: {}", name))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Deflate => "deflate",
+            Algorithm::Zstd => "zstd",
+            Algorithm::Brotli => "brotli",
+            Algorithm::Xz => "xz",
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Algorithm::Zstd => 3,
+            _ => 6,
+        }
+    }
+
+    /// Checks `level` against the range of the format, as some encoders panic on others
+    pub(super) fn level(self, level: Option<u32>) -> io::Result<u32> {
+        let (min, max) = match self {
+            Algorithm::Gzip | Algorithm::Deflate | Algorithm::Xz => (0, 9),
+            Algorithm::Zstd => (1, 22),
+            Algorithm::Brotli => (0, 11),
+        };
+        match level.unwrap_or_else(|| self.default_level()) {
+            level if level >= min && level <= max => Ok(level),
+            level => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} level must be between {} and {}, got {}", self.name(), min, max, level),
+            )),
+        }
+    }
+
+    fn encoder<W: Write + Send + 'static>(self, out: W, level: Option<u32>) -> io::Result<Box<dyn Stream<W> + Send>> {
+        let level = self.level(level)?;
+        Ok(match self {
+            Algorithm::Gzip => Box::new(GzEncoder::new(out, Compression::new(level))),
+            Algorithm::Deflate => Box::new(ZlibEncoder::new(out, Compression::new(level))),
+            Algorithm::Zstd => Box::new(zstd::stream::write::Encoder::new(out, level as i32)?),
+            Algorithm::Brotli => Box::new(brotli::CompressorWriter::new(out, 4096, level, 22)),
+            Algorithm::Xz => Box::new(XzEncoder::new(out, level)),
+        })
+    }
+
+    fn decoder<W: Write + Send + 'static>(self, out: W) -> io::Result<Box<dyn Stream<W> + Send>> {
+        Ok(match self {
+            Algorithm::Gzip => Box::new(GzDecoder::new(out)),
+            Algorithm::Deflate => Box::new(ZlibDecoder::new(out)),
+            Algorithm::Zstd => Box::new(ZstdDecoder::new(out, zstd::stream::raw::Decoder::new()?)),
+            Algorithm::Brotli => Box::new(brotli::DecompressorWriter::new(out, 4096)),
+            Algorithm::Xz => Box::new(XzDecoder::new(out)),
+        })
+    }
+}
+
+/// An encoder or decoder that writes its output into `W`
+trait Stream<W>: Write {
+    /// Flushes the end of the stream and returns the inner writer
+    fn finish(self: Box<Self>) -> io::Result<W>;
+}
+
+impl<W: Write> Stream<W> for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        GzEncoder::finish(*self)
+    }
+}
+
+impl<W: Write> Stream<W> for GzDecoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        GzDecoder::finish(*self)
+    }
+}
+
+impl<W: Write> Stream<W> for ZlibEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        ZlibEncoder::finish(*self)
+    }
+}
+
+impl<W: Write> Stream<W> for ZlibDecoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        ZlibDecoder::finish(*self)
+    }
+}
+
+impl<W: Write> Stream<W> for zstd::stream::write::Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        zstd::stream::write::Encoder::finish(*self)
+    }
+}
+
+/// `zstd::stream::write::Decoder` can't report a truncated stream, the writer under it can
+type ZstdDecoder<W> = zstd::stream::zio::Writer<W, zstd::stream::raw::Decoder>;
+
+impl<W: Write> Stream<W> for ZstdDecoder<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<W> {
+        ZstdDecoder::finish(&mut *self)?;
+        Ok(self.into_inner().0)
+    }
+}
+
+impl<W: Write> Stream<W> for brotli::CompressorWriter<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        Ok(brotli::CompressorWriter::into_inner(*self))
+    }
+}
+
+impl<W: Write> Stream<W> for brotli::DecompressorWriter<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        brotli::DecompressorWriter::into_inner(*self).map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete brotli stream"))
+    }
+}
+
+impl<W: Write> Stream<W> for XzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        XzEncoder::finish(*self)
+    }
+}
+
+impl<W: Write> Stream<W> for XzDecoder<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<W> {
+        XzDecoder::finish(&mut *self)
+    }
+}
+
+/// Fails once more than `max_size` bytes are written through it, so a small
+/// compressed input can't expand into unbounded output
+struct Capped<W> {
+    inner: W,
+    left: Option<u64>,
+}
+
+impl<W> Capped<W> {
+    fn new(inner: W, max_size: Option<u64>) -> Self {
+        Capped { inner, left: max_size }
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Capped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(left) = self.left {
+            if buf.len() as u64 > left {
+                return Err(io::Error::new(io::ErrorKind::Other, "decompressed data exceeds max_size"));
+            }
+        }
+        let written = self.inner.write(buf)?;
+        self.left = self.left.map(|left| left - written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the `max_size` option of the decompressing functions
+fn max_size(options: Option<LuaTable>) -> LuaResult<Option<u64>> {
+    match options {
+        Some(options) => options.get("max_size"),
+        None => Ok(None),
+    }
+}
+
+/// Runs `data` through a stream writing into memory
+fn run(mut stream: Box<dyn Stream<Capped<Vec<u8>>> + Send>, data: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(data)?;
+    stream.finish().map(Capped::into_inner)
+}
+
+/// Runs the file at `input` through a stream writing into `output`
+fn run_file(mut stream: Box<dyn Stream<Capped<fs::File>> + Send>, input: &str) -> io::Result<()> {
+    io::copy(&mut fs::File::open(input)?, &mut stream)?;
+    stream.finish()?.into_inner().sync_all()
+}
+
+/// Buffer shared between a stream and the Lua object draining it
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Sink {
+    fn take(&self) -> Vec<u8> {
+        mem::replace(&mut *self.0.lock().unwrap(), Vec::new())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Incremental encoder or decoder returning output as it becomes available
+pub struct LuaStream {
+    stream: Option<Box<dyn Stream<Capped<Sink>> + Send>>,
+    output: Sink,
+}
+
+impl UserData for LuaStream {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("write", |lua, this: &mut LuaStream, data: LuaValue| {
+            let stream = this.stream.as_mut()
+                .ok_or_else(|| LuaError::external(format_err!("stream is already finished")))?;
+            stream.write_all(&super::bytes(data)?).map_err(LuaError::external)?;
+            lua.create_string(&this.output.take())
+        });
+        methods.add_method_mut("finish", |lua, this: &mut LuaStream, _: ()| {
+            let stream = this.stream.take()
+                .ok_or_else(|| LuaError::external(format_err!("stream is already finished")))?;
+            stream.finish().map_err(LuaError::external)?;
+            lua.create_string(&this.output.take())
+        });
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        module.set("compress", lua.create_function(|lua, (algorithm, data, level): (String, LuaValue, Option<u32>)| {
+            let stream = Algorithm::parse(&algorithm)?.encoder(Capped::new(Vec::new(), None), level).map_err(LuaError::external)?;
+            let data = run(stream, &super::bytes(data)?).map_err(LuaError::external)?;
+            lua.create_string(&data)
+        })?)?;
+
+        // compress.decompress("gzip", data, { max_size = 1048576 }) fails rather than return more than max_size bytes
+        module.set("decompress", lua.create_function(|lua, (algorithm, data, options): (String, LuaValue, Option<LuaTable>)| {
+            let output = Capped::new(Vec::new(), max_size(options)?);
+            let stream = Algorithm::parse(&algorithm)?.decoder(output).map_err(LuaError::external)?;
+            let data = run(stream, &super::bytes(data)?).map_err(LuaError::external)?;
+            lua.create_string(&data)
+        })?)?;
+
+        module.set("compress_file", lua.create_function(|_, (algorithm, input, output, level): (String, String, String, Option<u32>)| {
+            let algorithm = Algorithm::parse(&algorithm)?;
+            let level = algorithm.level(level).map_err(LuaError::external)?;
+            let output = fs::File::create(output).map_err(LuaError::external)?;
+            algorithm.encoder(Capped::new(output, None), Some(level))
+                .and_then(|stream| run_file(stream, &input))
+                .map_err(LuaError::external)
+        })?)?;
+
+        module.set("decompress_file", lua.create_function(|_, (algorithm, input, output, options): (String, String, String, Option<LuaTable>)| {
+            let algorithm = Algorithm::parse(&algorithm)?;
+            let max_size = max_size(options)?;
+            let output = fs::File::create(output).map_err(LuaError::external)?;
+            algorithm.decoder(Capped::new(output, max_size))
+                .and_then(|stream| run_file(stream, &input))
+                .map_err(LuaError::external)
+        })?)?;
+
+        module.set("encoder", lua.create_function(|_, (algorithm, level): (String, Option<u32>)| {
+            let output = Sink::default();
+            let stream = Algorithm::parse(&algorithm)?.encoder(Capped::new(output.clone(), None), level).map_err(LuaError::external)?;
+            Ok(LuaStream { stream: Some(stream), output })
+        })?)?;
+
+        // The limit given by `max_size` covers everything the decoder returns, over all its writes
+        module.set("decoder", lua.create_function(|_, (algorithm, options): (String, Option<LuaTable>)| {
+            let output = Sink::default();
+            let stream = Algorithm::parse(&algorithm)?.decoder(Capped::new(output.clone(), max_size(options)?)).map_err(LuaError::external)?;
+            Ok(LuaStream { stream: Some(stream), output })
+        })?)?;
+
+        lua.globals().set("compress", module)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lua_compress() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("input.txt"), "hello ".repeat(1000)).unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local text = string.rep("hello world ", 500)
+                for _, algorithm in ipairs({ "gzip", "deflate", "zstd", "br", "xz" }) do
+                    local packed = compress.compress(algorithm, text, 9)
+                    assert(#packed < #text)
+                    assert(compress.decompress(algorithm, packed) == text)
+
+                    local encoder = compress.encoder(algorithm)
+                    local chunks = {}
+                    for i = 1, 10 do
+                        table.insert(chunks, encoder:write(string.rep("chunk " .. i, 100)))
+                    end
+                    table.insert(chunks, encoder:finish())
+                    assert(not pcall(encoder.write, encoder, "late"))
+
+                    local decoder = compress.decoder(algorithm)
+                    local out = {}
+                    for _, chunk in ipairs(chunks) do
+                        table.insert(out, decoder:write(chunk))
+                    end
+                    table.insert(out, decoder:finish())
+                    assert(#table.concat(out) == 10 * 700 + 100)
+
+                    local packed_file = dir .. "/input." .. algorithm
+                    compress.compress_file(algorithm, dir .. "/input.txt", packed_file)
+                    compress.decompress_file(algorithm, packed_file, dir .. "/output.txt")
+                end
+
+                assert(not pcall(compress.compress, "lz4", text))
+                assert(not pcall(compress.decompress, "gzip", "not gzip"))
+
+                for _, bad in ipairs({ { "xz", 20 }, { "gzip", 10 }, { "br", 12 }, { "zstd", 0 }, { "zstd", 23 } }) do
+                    assert(not pcall(compress.compress, bad[1], text, bad[2]))
+                    assert(not pcall(compress.encoder, bad[1], bad[2]))
+                    assert(not pcall(compress.compress_file, bad[1], dir .. "/input.txt", dir .. "/bad", bad[2]))
+                end
+
+                local packed = compress.compress("zstd", text)
+                assert(not pcall(compress.decompress, "zstd", packed:sub(1, #packed - 4)))
+                assert(not pcall(compress.decompress, "zstd", ""))
+
+                local bomb = compress.compress("gzip", string.rep("0", 100000))
+                assert(#compress.decompress("gzip", bomb, { max_size = 100000 }) == 100000)
+                assert(not pcall(compress.decompress, "gzip", bomb, { max_size = 1000 }))
+                local decoder = compress.decoder("gzip", { max_size = 1000 })
+                assert(not pcall(decoder.write, decoder, bomb) or not pcall(decoder.finish, decoder))
+            "#).exec().unwrap();
+        });
+
+        assert_eq!(fs::read_to_string(dir.path().join("output.txt")).unwrap(), "hello ".repeat(1000));
+    }
+}
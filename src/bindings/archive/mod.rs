@@ -1,17 +1,370 @@
+pub mod compress;
 pub mod tar;
 pub mod xz;
 pub mod zip;
 
 use rlua::prelude::*;
 use rlua::UserData;
+use regex::Regex;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH}
+};
 
 #[derive(Clone)]
 struct ByteBuf(Vec<u8>);
 
 impl UserData for ByteBuf {}
 
+/// Where the contents of an archive entry come from
+enum Source {
+    Directory,
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// A file or directory to be written into an archive
+struct Entry {
+    name: String,
+    source: Source,
+    mode: u32,
+    mtime: u64,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        match self.source {
+            Source::Directory => true,
+            _ => false,
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        match &self.source {
+            Source::Directory => Ok(0),
+            Source::File(path) => fs::metadata(path).map(|meta| meta.len()),
+            Source::Bytes(bytes) => Ok(bytes.len() as u64),
+        }
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match &self.source {
+            Source::Directory => Ok(Box::new(io::empty())),
+            Source::File(path) => Ok(Box::new(fs::File::open(path)?)),
+            Source::Bytes(bytes) => Ok(Box::new(&bytes[..])),
+        }
+    }
+}
+
+/// Reads a Lua string or a `ByteBuf` as raw bytes
+fn bytes(value: LuaValue) -> LuaResult<Vec<u8>> {
+    match value {
+        LuaValue::String(s) => Ok(s.as_bytes().to_vec()),
+        LuaValue::UserData(ud) => Ok(ud.borrow::<ByteBuf>()?.0.clone()),
+        _ => Err(LuaError::external(format_err!("expected a string or byte buffer"))),
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    if meta.is_dir() { 0o755 } else { 0o644 }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "symbolic links are not supported on this platform"))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `path` stays below the directory it is joined to
+fn is_safe(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+/// Rejects in-archive names that are absolute or climb out of the archive root
+fn entry_name(name: &str) -> LuaResult<String> {
+    if !name.is_empty() && is_safe(Path::new(name)) {
+        Ok(name.replace('\\', "/").trim_end_matches('/').to_string())
+    } else {
+        Err(LuaError::external(format_err!("invalid archive entry name: {}", name)))
+    }
+}
+
+/// Adds `path` under `name`, descending into directories in a stable order.
+/// Symbolic links below `path` are left out, so a loop or a link pointing out
+/// of the tree can't pull in anything else.
+fn walk(path: &Path, name: &str, mode: Option<u32>, mtime: Option<u64>, entries: &mut Vec<Entry>) -> LuaResult<()> {
+    let meta = fs::metadata(path).map_err(LuaError::external)?;
+    let mtime = mtime.unwrap_or_else(|| meta.modified().map(unix_time).unwrap_or(0));
+    let mode = mode.unwrap_or_else(|| file_mode(&meta));
+
+    if !meta.is_dir() {
+        entries.push(Entry { name: name.to_string(), source: Source::File(path.to_path_buf()), mode, mtime });
+        return Ok(());
+    }
+
+    if !name.is_empty() {
+        entries.push(Entry { name: name.to_string(), source: Source::Directory, mode, mtime });
+    }
+
+    let mut children = fs::read_dir(path)
+        .and_then(|dir| dir.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<_>>>())
+        .map_err(LuaError::external)?;
+    children.sort();
+
+    for child in children {
+        if fs::symlink_metadata(&child).map(|meta| meta.file_type().is_symlink()).map_err(LuaError::external)? {
+            debug!("leaving symbolic link {:?} out of the archive", child);
+            continue;
+        }
+        let file_name = child.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let child_name = if name.is_empty() { file_name } else { format!("{}/{}", name, file_name) };
+        walk(&child, &child_name, None, None, entries)?;
+    }
+
+    Ok(())
+}
+
+/// Collects archive entries from a directory path or a list of
+/// `{ name, path | content, mode, mtime }` tables
+fn entries(sources: LuaValue) -> LuaResult<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    match sources {
+        LuaValue::String(path) => {
+            let path = PathBuf::from(path.to_str()?);
+            if path.is_dir() {
+                walk(&path, "", None, None, &mut entries)?;
+            } else {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                walk(&path, &entry_name(&name)?, None, None, &mut entries)?;
+            }
+        },
+        LuaValue::Table(list) => {
+            for item in list.sequence_values::<LuaTable>() {
+                let item = item?;
+                let name: Option<String> = item.get("name")?;
+                let mode: Option<u32> = item.get("mode")?;
+                let mtime: Option<u64> = item.get("mtime")?;
+
+                match item.get::<_, LuaValue>("content")? {
+                    LuaValue::Nil => {
+                        let path = PathBuf::from(item.get::<_, String>("path")?);
+                        let name = match name {
+                            Some(name) => name,
+                            None => path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+                        };
+                        walk(&path, &entry_name(&name)?, mode, mtime, &mut entries)?;
+                    },
+                    content => {
+                        let name = name.ok_or_else(|| LuaError::external(format_err!("entries with content need a name")))?;
+                        entries.push(Entry {
+                            name: entry_name(&name)?,
+                            source: Source::Bytes(bytes(content)?),
+                            mode: mode.unwrap_or(0o644),
+                            mtime: mtime.unwrap_or_else(|| unix_time(SystemTime::now())),
+                        });
+                    }
+                }
+            }
+        },
+        _ => return Err(LuaError::external(format_err!("archive sources must be a directory path or a list of entries"))),
+    }
+
+    Ok(entries)
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).map(|meta| meta.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// Checks that no directory between `dst` and the entry at `relative` is a symbolic
+/// link, which an earlier entry of the archive could have pointed anywhere
+fn check_parents(dst: &Path, relative: &Path) -> LuaResult<()> {
+    let mut path = dst.to_path_buf();
+    if let Some(parent) = relative.parent() {
+        for component in parent.components() {
+            path.push(component);
+            if is_symlink(&path) {
+                return Err(LuaError::external(format_err!("entry {} goes through a symbolic link", relative.display())));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a link at `path` pointing to `target` resolves inside the extraction root `dst`,
+/// without going through links extracted before it. Symbolic links resolve from the link's
+/// directory, hard links from the root.
+fn check_link(dst: &Path, path: &Path, target: &Path, symbolic: bool) -> LuaResult<()> {
+    let escapes = || LuaError::external(format_err!("link {} escapes the destination: {}", path.display(), target.display()));
+    let mut resolved: Vec<Component> = match path.parent() {
+        Some(parent) if symbolic => parent.components().filter(|c| match c { Component::Normal(_) => true, _ => false }).collect(),
+        _ => Vec::new(),
+    };
+
+    let count = target.components().count();
+    for (index, component) in target.components().enumerate() {
+        match component {
+            Component::Normal(_) => {
+                resolved.push(component);
+                if index + 1 < count && is_symlink(&dst.join(resolved.iter().collect::<PathBuf>())) {
+                    return Err(escapes());
+                }
+            },
+            Component::CurDir => {},
+            Component::ParentDir if !resolved.is_empty() => { resolved.pop(); },
+            _ => return Err(escapes()),
+        }
+    }
+    Ok(())
+}
+
+/// Converts a glob pattern into an anchored regex; `*` stays within a path segment, `**` crosses them
+fn glob(pattern: &str) -> LuaResult<Regex> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            },
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).map_err(LuaError::external)
+}
+
+/// Metadata of an entry in an existing archive
+struct Info {
+    name: String,
+    kind: &'static str,
+    size: u64,
+    compressed_size: Option<u64>,
+    mode: Option<u32>,
+    mtime: u64,
+}
+
+impl Info {
+    fn to_lua<'lua>(&self, lua: LuaContext<'lua>) -> LuaResult<LuaTable<'lua>> {
+        let table = lua.create_table()?;
+        table.set("name", self.name.as_str())?;
+        table.set("type", self.kind)?;
+        table.set("size", self.size)?;
+        table.set("compressed_size", self.compressed_size)?;
+        table.set("mode", self.mode)?;
+        table.set("mtime", self.mtime)?;
+        Ok(table)
+    }
+}
+
+/// Entry filters and size limits applied while reading untrusted archives
+struct Limits {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    max_size: Option<u64>,
+    max_entries: Option<usize>,
+    size: u64,
+    entries: usize,
+}
+
+impl Limits {
+    fn from_lua(options: Option<&LuaTable>) -> LuaResult<Self> {
+        let mut limits = Limits { include: vec![], exclude: vec![], max_size: None, max_entries: None, size: 0, entries: 0 };
+        if let Some(options) = options {
+            limits.include = Self::patterns(options.get("include")?)?;
+            limits.exclude = Self::patterns(options.get("exclude")?)?;
+            limits.max_size = options.get("max_size")?;
+            limits.max_entries = options.get("max_entries")?;
+        }
+        Ok(limits)
+    }
+
+    fn patterns(value: LuaValue) -> LuaResult<Vec<Regex>> {
+        match value {
+            LuaValue::Nil => Ok(vec![]),
+            LuaValue::String(pattern) => Ok(vec![glob(pattern.to_str()?)?]),
+            LuaValue::Table(list) => list.sequence_values::<String>().map(|p| glob(&p?)).collect(),
+            _ => Err(LuaError::external(format_err!("glob patterns must be a string or a list of strings"))),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('/');
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(name)))
+            && !self.exclude.iter().any(|re| re.is_match(name))
+    }
+
+    /// Counts one more archive entry, whether or not it gets extracted
+    fn entry(&mut self) -> LuaResult<()> {
+        self.entries += 1;
+        match self.max_entries {
+            Some(max) if self.entries > max => Err(LuaError::external(format_err!("archive has more than {} entries", max))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies an entry into `output`, failing once the total size would exceed `max_size`
+    fn copy<R: Read, W: Write>(&mut self, mut reader: R, output: &mut W) -> LuaResult<u64> {
+        let copied = match self.max_size {
+            Some(max) => io::copy(&mut reader.take(max.saturating_sub(self.size) + 1), output),
+            None => io::copy(&mut reader, output),
+        }.map_err(LuaError::external)?;
+        self.reserve(copied)?;
+        Ok(copied)
+    }
+
+    /// Accounts for `size` extracted bytes
+    fn reserve(&mut self, size: u64) -> LuaResult<()> {
+        self.size += size;
+        match self.max_size {
+            Some(max) if self.size > max => Err(LuaError::external(format_err!("archive contents exceed {} bytes", max))),
+            _ => Ok(()),
+        }
+    }
+}
 
 pub fn init(lua: &Lua) -> crate::Result<()> {
+    compress::init(lua)?;
     tar::init(lua)?;
     xz::init(lua)?;
     zip::init(lua)?;
@@ -1,14 +1,68 @@
 use rlua::prelude::*;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use std::{
     fs,
-    io::Read,
+    io::{self, Cursor, Read, Write},
     result,
     path::Path
 };
-use tar::Archive;
+use tar::{Archive, Builder, EntryType, Header};
+use xz2::{read::XzDecoder, write::XzEncoder};
+use crate::bindings::system::LuaCommonIO;
 use crate::error::Error;
 
-use super::ByteBuf;
+use super::{ByteBuf, Entry, Info, Limits};
+use super::compress::Algorithm;
+
+/// Writes `entries` as a tar stream into `out`
+fn write<W: Write>(out: W, entries: &[Entry]) -> io::Result<W> {
+    let mut tar = Builder::new(out);
+
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(if entry.is_dir() { EntryType::Directory } else { EntryType::Regular });
+        header.set_size(entry.size()?);
+        header.set_mode(entry.mode);
+        header.set_mtime(entry.mtime);
+        tar.append_data(&mut header, &entry.name, entry.reader()?)?;
+    }
+
+    tar.into_inner()
+}
+
+/// Checks `level` against `compression` before anything gets written
+fn level(compression: Option<&str>, level: Option<u32>) -> LuaResult<u32> {
+    let algorithm = match compression {
+        Some("gz") | Some("gzip") => Algorithm::Gzip,
+        Some("xz") => Algorithm::Xz,
+        _ => return Ok(level.unwrap_or(6)),
+    };
+    algorithm.level(level).map_err(LuaError::external)
+}
+
+/// Writes a tar archive compressed with `compression` ("gz", "xz" or none)
+fn compress<W: Write>(out: W, entries: &[Entry], compression: Option<&str>, level: u32) -> LuaResult<W> {
+    let out = match compression {
+        None | Some("none") => write(out, entries),
+        Some("gz") | Some("gzip") => write(GzEncoder::new(out, Compression::new(level)), entries)
+            .and_then(|encoder| encoder.finish()),
+        Some("xz") => write(XzEncoder::new(out, level), entries)
+            .and_then(|encoder| encoder.finish()),
+        Some(other) => return Err(LuaError::external(format_err!("unsupported tar compression: {}", other))),
+    };
+    out.map_err(LuaError::external)
+}
+
+/// Guesses the compression from the output file extension
+fn compression_for(output: &str) -> Option<&'static str> {
+    if output.ends_with(".gz") || output.ends_with(".tgz") {
+        Some("gz")
+    } else if output.ends_with(".xz") || output.ends_with(".txz") {
+        Some("xz")
+    } else {
+        None
+    }
+}
 
 fn extract<T: Read>(archive: &mut Archive<T>, dst: &Path) -> result::Result<(), LuaError> {
     for file in archive.entries().map_err(LuaError::external)? {
@@ -19,6 +73,97 @@ fn extract<T: Read>(archive: &mut Archive<T>, dst: &Path) -> result::Result<(),
     Ok(())
 }
 
+/// Opens an archive from a file path or an in-memory `ByteBuf`, decompressing
+/// it as `compression` or as guessed from the file extension
+fn open(src: LuaValue, compression: Option<String>) -> LuaResult<Archive<Box<dyn Read>>> {
+    let (reader, guess) = match src {
+        LuaValue::String(path) => {
+            let path = path.to_str()?;
+            let file = fs::File::open(path).map_err(LuaError::external)?;
+            (Box::new(file) as Box<dyn Read>, compression_for(path))
+        },
+        other => (Box::new(Cursor::new(super::bytes(other)?)) as Box<dyn Read>, None),
+    };
+
+    let reader: Box<dyn Read> = match compression.as_ref().map(String::as_str).or(guess) {
+        None | Some("none") => reader,
+        Some("gz") | Some("gzip") => Box::new(GzDecoder::new(reader)),
+        Some("xz") => Box::new(XzDecoder::new(reader)),
+        Some(other) => return Err(LuaError::external(format_err!("unsupported tar compression: {}", other))),
+    };
+    Ok(Archive::new(reader))
+}
+
+fn info<R: Read>(entry: &tar::Entry<R>) -> io::Result<Info> {
+    let header = entry.header();
+    let kind = match header.entry_type() {
+        t if t.is_dir() => "directory",
+        t if t.is_symlink() => "symlink",
+        t if t.is_hard_link() => "link",
+        t if t.is_file() || t.is_contiguous() => "file",
+        _ => "other",
+    };
+
+    Ok(Info {
+        name: entry.path()?.to_string_lossy().trim_start_matches("./").to_string(),
+        kind,
+        size: header.size()?,
+        compressed_size: None,
+        mode: Some(header.mode()?),
+        mtime: header.mtime()?,
+    })
+}
+
+/// Reads a single entry into memory, `None` if the archive has no such entry
+fn read<R: Read>(archive: &mut Archive<R>, name: &str, limits: &mut Limits) -> LuaResult<Option<Vec<u8>>> {
+    let name = name.trim_start_matches("./").trim_end_matches('/');
+    for entry in archive.entries().map_err(LuaError::external)? {
+        let mut entry = entry.map_err(LuaError::external)?;
+        if info(&entry).map_err(LuaError::external)?.name.trim_end_matches('/') == name {
+            let mut buf = Vec::new();
+            limits.copy(&mut entry, &mut buf)?;
+            return Ok(Some(buf));
+        }
+    }
+    Ok(None)
+}
+
+/// Extracts the entries allowed by `limits` into `dst`, returning their names
+fn extract_limited<R: Read>(archive: &mut Archive<R>, dst: &Path, limits: &mut Limits) -> LuaResult<Vec<String>> {
+    let mut extracted = Vec::new();
+
+    for entry in archive.entries().map_err(LuaError::external)? {
+        limits.entry()?;
+        let mut entry = entry.map_err(LuaError::external)?;
+        let info = info(&entry).map_err(LuaError::external)?;
+        if !limits.matches(&info.name) {
+            continue;
+        }
+
+        let relative = entry.path().map_err(LuaError::external)?.into_owned();
+        if !super::is_safe(&relative) {
+            return Err(LuaError::external(format_err!("entry escapes the destination: {}", info.name)));
+        }
+        super::check_parents(dst, &relative)?;
+
+        match info.kind {
+            "symlink" | "link" => {
+                let target = entry.link_name().map_err(LuaError::external)?
+                    .ok_or_else(|| LuaError::external(format_err!("link without a target: {}", info.name)))?;
+                super::check_link(dst, &relative, &target, info.kind == "symlink")?;
+            },
+            "file" | "directory" => {},
+            _ => continue,
+        }
+
+        limits.reserve(info.size)?;
+        entry.unpack_in(dst).map_err(LuaError::external)?;
+        extracted.push(info.name);
+    }
+
+    Ok(extracted)
+}
+
 pub fn init(lua: &Lua) -> crate::Result<()> {
     lua.context(|lua| {
         let module = lua.create_table()?;
@@ -35,8 +180,207 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
             extract(&mut archive, &dst)
         })?)?;
 
+        module.set("compress", lua.create_function(|lua, (sources, output, options): (LuaValue, Option<String>, Option<LuaTable>)| {
+            let (compression, level) = match options {
+                Some(options) => (options.get::<_, Option<String>>("compression")?, options.get::<_, Option<u32>>("level")?),
+                None => (None, None),
+            };
+            let compression = compression.or_else(|| output.as_ref().and_then(|o| compression_for(o)).map(String::from));
+            let level = self::level(compression.as_ref().map(String::as_str), level)?;
+            let entries = super::entries(sources)?;
+
+            match output {
+                Some(output) => {
+                    let file = fs::File::create(output).map_err(LuaError::external)?;
+                    compress(file, &entries, compression.as_ref().map(String::as_str), level)?;
+                    Ok(LuaValue::Boolean(true))
+                },
+                None => {
+                    let buf = compress(Vec::new(), &entries, compression.as_ref().map(String::as_str), level)?;
+                    lua.create_string(&buf).map(LuaValue::String)
+                }
+            }
+        })?)?;
+
+        module.set("list", lua.create_function(|lua, (src, options): (LuaValue, Option<LuaTable>)| {
+            let compression = match options {
+                Some(options) => options.get("compression")?,
+                None => None,
+            };
+            let mut archive = open(src, compression)?;
+            let entries = lua.create_table()?;
+            for (i, entry) in archive.entries().map_err(LuaError::external)?.enumerate() {
+                let entry = entry.map_err(LuaError::external)?;
+                entries.set(i + 1, info(&entry).map_err(LuaError::external)?.to_lua(lua)?)?;
+            }
+            Ok(entries)
+        })?)?;
+
+        module.set("read", lua.create_function(|lua, (src, name, options): (LuaValue, String, Option<LuaTable>)| {
+            let mut limits = Limits::from_lua(options.as_ref())?;
+            let compression = match options {
+                Some(options) => options.get("compression")?,
+                None => None,
+            };
+            match read(&mut open(src, compression)?, &name, &mut limits)? {
+                Some(data) => lua.create_string(&data).map(LuaValue::String),
+                None => Ok(LuaValue::Nil),
+            }
+        })?)?;
+
+        module.set("open", lua.create_function(|_, (src, name, options): (LuaValue, String, Option<LuaTable>)| {
+            let mut limits = Limits::from_lua(options.as_ref())?;
+            let compression = match options {
+                Some(options) => options.get("compression")?,
+                None => None,
+            };
+            Ok(read(&mut open(src, compression)?, &name, &mut limits)?.map(LuaCommonIO::from_bytes))
+        })?)?;
+
+        module.set("extract", lua.create_function(|_, (src, dst, options): (LuaValue, String, Option<LuaTable>)| {
+            let mut limits = Limits::from_lua(options.as_ref())?;
+            let compression = match options {
+                Some(options) => options.get("compression")?,
+                None => None,
+            };
+            extract_limited(&mut open(src, compression)?, Path::new(&dst), &mut limits)
+        })?)?;
+
         lua.globals().set("tar", module).map_err(Error::from)?;
 
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn lua_tar_compress() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("site/js")).unwrap();
+        fs::write(dir.path().join("site/js/app.js"), "run()").unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                assert(tar.compress(dir .. "/site", dir .. "/site.tar"))
+                tar.decompress(dir .. "/site.tar", dir .. "/out")
+
+                assert(tar.compress({
+                    { name = "bin/run.sh", content = "echo hi", mode = 493, mtime = 1546300800 },
+                }, dir .. "/bin.tar.gz", { level = 9 }))
+
+                local data = tar.compress(dir .. "/site", nil, { compression = "xz" })
+                assert(data:sub(2, 5) == "7zXZ")
+                assert(not pcall(tar.compress, dir .. "/site", nil, { compression = "xz", level = 20 }))
+                assert(not pcall(tar.compress, dir .. "/site", dir .. "/bad.tar.gz", { level = 10 }))
+            "#).exec().unwrap();
+        });
+
+        assert_eq!(fs::read_to_string(dir.path().join("out/js/app.js")).unwrap(), "run()");
+        assert!(!dir.path().join("bad.tar.gz").exists());
+
+        let gz = fs::File::open(dir.path().join("bin.tar.gz")).unwrap();
+        let mut archive = Archive::new(GzDecoder::new(gz));
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some("bin/run.sh"));
+        assert_eq!(entry.header().mode().unwrap(), 0o755);
+        assert_eq!(entry.header().mtime().unwrap(), 1546300800);
+    }
+
+    #[test]
+    fn lua_tar_inspect() {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local archive = dir .. "/upload.tar.gz"
+                tar.compress({
+                    { name = "src/main.rs", content = "fn main() {}", mode = 420, mtime = 1546300800 },
+                    { name = "src/lib.rs", content = "" },
+                    { name = "README.md", content = string.rep("r", 500) },
+                }, archive)
+
+                local entries = tar.list(archive)
+                assert(#entries == 3)
+                assert(entries[1].name == "src/main.rs" and entries[1].type == "file")
+                assert(entries[1].size == 12 and entries[1].mode == 420 and entries[1].mtime == 1546300800)
+                assert(entries[1].compressed_size == nil)
+
+                assert(tar.read(archive, "src/main.rs") == "fn main() {}")
+                assert(tar.read(archive, "missing") == nil)
+                assert(tar.open(archive, "README.md"):read("string") == string.rep("r", 500))
+
+                local extracted = tar.extract(archive, dir .. "/out", { include = { "src/*.rs" }, exclude = "**/lib.rs" })
+                assert(#extracted == 1 and extracted[1] == "src/main.rs")
+                assert(not pcall(tar.extract, archive, dir .. "/limited", { max_size = 100 }))
+            "#).exec().unwrap();
+        });
+
+        assert!(dir.path().join("out/src/main.rs").exists());
+        assert!(!dir.path().join("out/README.md").exists());
+    }
+
+    #[test]
+    fn rejects_escaping_links() {
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_link_name("../../etc").unwrap();
+        builder.append_data(&mut header, "data/etc", io::empty()).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert!(extract_limited(&mut Archive::new(&data[..]), dir.path(), &mut limits).is_err());
+        assert!(!dir.path().join("data/etc").exists());
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_link_name("../shared").unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.append_data(&mut header, "data/current", io::empty()).unwrap();
+        let data = builder.into_inner().unwrap();
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert_eq!(extract_limited(&mut Archive::new(&data[..]), dir.path(), &mut limits).unwrap(), vec!["data/current"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_chained_links() {
+        fn archive(entries: &[(&str, &str)]) -> Vec<u8> {
+            let mut builder = Builder::new(Vec::new());
+            for (name, target) in entries {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                header.set_link_name(target).unwrap();
+                builder.append_data(&mut header, name, io::empty()).unwrap();
+            }
+            builder.into_inner().unwrap()
+        }
+
+        // `x/y` is written through `x`, which would leave `y` pointing at dst/..
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out");
+        fs::create_dir(&dst).unwrap();
+        let data = archive(&[("x", "."), ("x/y", "..")]);
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert!(extract_limited(&mut Archive::new(&data[..]), &dst, &mut limits).is_err());
+        assert!(fs::symlink_metadata(dst.join("y")).is_err());
+
+        let data = archive(&[("a", "."), ("b", "a/..")]);
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert!(extract_limited(&mut Archive::new(&data[..]), &dst, &mut limits).is_err());
+        assert!(fs::symlink_metadata(dst.join("b")).is_err());
+    }
+}
@@ -3,13 +3,14 @@ use std::fs;
 use std::io::{self, Read};
 use xz2::read::*;
 use super::ByteBuf;
+use super::compress::Algorithm;
 
 pub fn init(lua: &Lua) -> crate::Result<()> {
     lua.context(|lua| {
         let module = lua.create_table()?;
 
         module.set("compress", lua.create_function(|_, (input, output, level): (String, String, Option<u32>)| {
-            let level = level.unwrap_or(6);
+            let level = Algorithm::Xz.level(level).map_err(LuaError::external)?;
             let file = fs::File::open(&input).map_err(LuaError::external)?;
             let mut output = fs::File::create(&output).map_err(LuaError::external)?;
             let mut data = XzEncoder::new(file, level);
@@ -1,8 +1,161 @@
 use rlua::prelude::*;
-use zip::ZipArchive;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+use zip::read::ZipFile;
+use zip::result::{ZipError, ZipResult};
+use zip::write::FileOptions;
 use std::fs;
-use std::io;
-use std::path::Path;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use crate::bindings::system::LuaCommonIO;
+
+use super::{Entry, Info, Limits};
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Opens an archive from a file path or an in-memory `ByteBuf`
+fn open(src: LuaValue) -> LuaResult<ZipArchive<Box<dyn ReadSeek>>> {
+    let reader: Box<dyn ReadSeek> = match src {
+        LuaValue::String(path) => Box::new(fs::File::open(path.to_str()?).map_err(LuaError::external)?),
+        other => Box::new(Cursor::new(super::bytes(other)?)),
+    };
+    ZipArchive::new(reader).map_err(LuaError::external)
+}
+
+fn unix_time(time: DateTime) -> u64 {
+    NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)
+        .and_then(|date| date.and_hms_opt(time.hour() as u32, time.minute() as u32, time.second() as u32))
+        .map_or(0, |time| time.timestamp() as u64)
+}
+
+fn info(file: &ZipFile) -> Info {
+    let mode = file.unix_mode();
+    let kind = if file.is_dir() {
+        "directory"
+    } else if mode.map_or(false, |mode| mode & 0o170000 == 0o120000) {
+        "symlink"
+    } else {
+        "file"
+    };
+
+    Info {
+        name: file.name().to_string(),
+        kind,
+        size: file.size(),
+        compressed_size: Some(file.compressed_size()),
+        mode: mode.map(|mode| mode & 0o7777),
+        mtime: unix_time(file.last_modified()),
+    }
+}
+
+/// Reads a single entry into memory, `None` if the archive has no such entry
+fn read<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str, limits: &mut Limits) -> LuaResult<Option<Vec<u8>>> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(LuaError::external(err)),
+    };
+    let mut buf = Vec::new();
+    limits.copy(&mut file, &mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Extracts the entries allowed by `limits` into `dst`, returning their names
+fn extract<R: Read + Seek>(archive: &mut ZipArchive<R>, dst: &Path, limits: &mut Limits) -> LuaResult<Vec<String>> {
+    let mut extracted = Vec::new();
+
+    for i in 0..archive.len() {
+        limits.entry()?;
+        let mut file = archive.by_index(i).map_err(LuaError::external)?;
+        let info = info(&file);
+        if !limits.matches(&info.name) {
+            continue;
+        }
+
+        let relative = PathBuf::from(info.name.trim_end_matches('/'));
+        if !super::is_safe(&relative) {
+            return Err(LuaError::external(format_err!("entry escapes the destination: {}", info.name)));
+        }
+        super::check_parents(dst, &relative)?;
+        let path = dst.join(&relative);
+        // Entries replace links rather than writing through them
+        if super::is_symlink(&path) {
+            fs::remove_file(&path).map_err(LuaError::external)?;
+        }
+
+        if info.kind == "directory" {
+            fs::create_dir_all(&path).map_err(LuaError::external)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(LuaError::external)?;
+            }
+            if info.kind == "symlink" {
+                let mut target = Vec::new();
+                limits.copy(&mut file, &mut target)?;
+                let target = PathBuf::from(String::from_utf8_lossy(&target).into_owned());
+                super::check_link(dst, &relative, &target, true)?;
+                super::symlink(&target, &path).map_err(LuaError::external)?;
+            } else {
+                let mut output = fs::File::create(&path).map_err(LuaError::external)?;
+                limits.copy(&mut file, &mut output)?;
+                if let Some(mode) = info.mode {
+                    super::set_mode(&path, mode).map_err(LuaError::external)?;
+                }
+            }
+        }
+
+        extracted.push(info.name);
+    }
+
+    Ok(extracted)
+}
+
+fn modified(mtime: u64) -> DateTime {
+    let time = NaiveDateTime::from_timestamp(mtime as i64, 0);
+    DateTime::from_date_and_time(
+        time.year() as u16, time.month() as u8, time.day() as u8,
+        time.hour() as u8, time.minute() as u8, time.second() as u8
+    ).unwrap_or_default()
+}
+
+/// The zip writer only deflates at its default level, so that is the only other level there is
+const DEFLATE_LEVEL: u32 = 6;
+
+/// Reads the `level` option: 0 stores files uncompressed, 6 (the default) deflates them
+fn level(options: Option<&LuaTable>) -> LuaResult<u32> {
+    let level = match options {
+        Some(options) => options.get::<_, Option<u32>>("level")?.unwrap_or(DEFLATE_LEVEL),
+        None => DEFLATE_LEVEL,
+    };
+    match level {
+        0 | DEFLATE_LEVEL => Ok(level),
+        _ => Err(LuaError::external(format_err!("zip level must be 0 (stored) or {} (deflated), got {}", DEFLATE_LEVEL, level))),
+    }
+}
+
+/// Writes `entries` into a new zip archive; level 0 stores files uncompressed
+fn write<W: Write + Seek>(out: W, entries: &[Entry], level: u32) -> ZipResult<W> {
+    let method = if level == 0 { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+    let mut zip = ZipWriter::new(out);
+
+    for entry in entries {
+        let options = FileOptions::default()
+            .compression_method(method)
+            .last_modified_time(modified(entry.mtime))
+            .unix_permissions(entry.mode);
+
+        if entry.is_dir() {
+            zip.add_directory(format!("{}/", entry.name), options)?;
+        } else {
+            zip.start_file(entry.name.as_str(), options)?;
+            io::copy(&mut entry.reader()?, &mut zip)?;
+        }
+    }
+
+    zip.finish()
+}
 
 pub fn init(lua: &Lua) -> crate::Result<()> {
     lua.context(|lua| {
@@ -33,8 +186,208 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
             })
         })?)?;
 
+        module.set("compress", lua.create_function(|lua, (sources, output, options): (LuaValue, Option<String>, Option<LuaTable>)| {
+            let level = level(options.as_ref())?;
+            let entries = super::entries(sources)?;
+
+            match output {
+                Some(output) => {
+                    let file = fs::File::create(output).map_err(LuaError::external)?;
+                    write(file, &entries, level).map_err(LuaError::external)?;
+                    Ok(LuaValue::Boolean(true))
+                },
+                None => {
+                    let buf = write(Cursor::new(Vec::new()), &entries, level).map_err(LuaError::external)?;
+                    lua.create_string(&buf.into_inner()).map(LuaValue::String)
+                }
+            }
+        })?)?;
+
+        module.set("list", lua.create_function(|lua, src: LuaValue| {
+            let mut archive = open(src)?;
+            let entries = lua.create_table()?;
+            for i in 0..archive.len() {
+                let file = archive.by_index(i).map_err(LuaError::external)?;
+                entries.set(i + 1, info(&file).to_lua(lua)?)?;
+            }
+            Ok(entries)
+        })?)?;
+
+        module.set("read", lua.create_function(|lua, (src, name, options): (LuaValue, String, Option<LuaTable>)| {
+            let mut limits = Limits::from_lua(options.as_ref())?;
+            match read(&mut open(src)?, &name, &mut limits)? {
+                Some(data) => lua.create_string(&data).map(LuaValue::String),
+                None => Ok(LuaValue::Nil),
+            }
+        })?)?;
+
+        module.set("open", lua.create_function(|_, (src, name, options): (LuaValue, String, Option<LuaTable>)| {
+            let mut limits = Limits::from_lua(options.as_ref())?;
+            Ok(read(&mut open(src)?, &name, &mut limits)?.map(LuaCommonIO::from_bytes))
+        })?)?;
+
+        module.set("extract", lua.create_function(|_, (src, dst, options): (LuaValue, String, Option<LuaTable>)| {
+            let mut limits = Limits::from_lua(options.as_ref())?;
+            extract(&mut open(src)?, Path::new(&dst), &mut limits)
+        })?)?;
+
         lua.globals().set("zip", module)?;
 
         Ok(())
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lua_zip_compress() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("site/css")).unwrap();
+        fs::write(dir.path().join("site/index.html"), "<h1>hi</h1>").unwrap();
+        fs::write(dir.path().join("site/css/main.css"), "body {}").unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                assert(zip.compress(dir .. "/site", dir .. "/site.zip", { level = 0 }))
+                assert(not pcall(zip.compress, dir .. "/site", dir .. "/other.zip", { level = 9 }))
+                zip.decompress(dir .. "/site.zip", dir .. "/out")
+
+                local data = zip.compress({
+                    { name = "docs/readme.txt", content = "hello", mode = 420, mtime = 1546300800 },
+                    { name = "index.html", path = dir .. "/site/index.html" },
+                })
+                assert(type(data) == "string" and data:sub(1, 2) == "PK")
+            "#).exec().unwrap();
+        });
+
+        assert_eq!(fs::read_to_string(dir.path().join("out/css/main.css")).unwrap(), "body {}");
+        assert_eq!(fs::read_to_string(dir.path().join("out/index.html")).unwrap(), "<h1>hi</h1>");
+    }
+
+    #[test]
+    fn lua_zip_inspect() {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local archive = dir .. "/upload.zip"
+                zip.compress({
+                    { name = "docs/a.md", content = "abc", mode = 384, mtime = 1546300800 },
+                    { name = "docs/b.txt", content = string.rep("b", 1000) },
+                    { name = "img/logo.png", content = "png" },
+                }, archive)
+
+                local entries = zip.list(archive)
+                assert(#entries == 3)
+                assert(entries[1].name == "docs/a.md" and entries[1].type == "file")
+                assert(entries[1].size == 3 and entries[1].mode == 384 and entries[1].mtime == 1546300800)
+                assert(entries[2].compressed_size < entries[2].size)
+
+                assert(zip.read(archive, "docs/a.md") == "abc")
+                assert(zip.read(archive, "missing") == nil)
+                assert(zip.open(archive, "img/logo.png"):read("string") == "png")
+                assert(not pcall(zip.read, archive, "docs/b.txt", { max_size = 10 }))
+
+                local extracted = zip.extract(archive, dir .. "/out", { include = "docs/**", exclude = "*/*.txt" })
+                assert(#extracted == 1 and extracted[1] == "docs/a.md")
+                assert(not pcall(zip.extract, archive, dir .. "/limited", { max_entries = 2 }))
+                assert(not pcall(zip.extract, archive, dir .. "/limited", { max_size = 100 }))
+            "#).exec().unwrap();
+        });
+
+        assert!(dir.path().join("out/docs/a.md").exists());
+        assert!(!dir.path().join("out/docs/b.txt").exists());
+        assert!(!dir.path().join("out/img").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks_when_walking() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("site/sub")).unwrap();
+        fs::write(dir.path().join("site/index.html"), "hi").unwrap();
+        fs::write(dir.path().join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink("..", dir.path().join("site/sub/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret"), dir.path().join("site/secret")).unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local names = {}
+                zip.compress(dir .. "/site", dir .. "/site.zip")
+                for _, entry in ipairs(zip.list(dir .. "/site.zip")) do
+                    names[#names + 1] = entry.name
+                end
+                assert(#names == 2 and names[1] == "index.html" and names[2] == "sub/", table.concat(names, ", "))
+            "#).exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn rejects_escaping_entries() {
+        let mut buf = ZipWriter::new(Cursor::new(Vec::new()));
+        buf.start_file("../evil.txt", FileOptions::default()).unwrap();
+        buf.write_all(b"evil").unwrap();
+        let data = buf.finish().unwrap().into_inner();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert!(extract(&mut archive, &dir.path().join("out"), &mut limits).is_err());
+        assert!(!dir.path().join("evil.txt").exists());
+    }
+
+    /// Builds an archive of `(name, content, is_link)` entries; the writer can't
+    /// make links, so their mode is patched into the central directory afterwards
+    fn archive_with_links(entries: &[(&str, &str, bool)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content, _) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let mut data = zip.finish().unwrap().into_inner();
+
+        let headers: Vec<usize> = (0..data.len() - 4).filter(|&i| data[i..i + 4] == [0x50, 0x4b, 0x01, 0x02]).collect();
+        for (start, (_, _, is_link)) in headers.into_iter().zip(entries) {
+            if *is_link {
+                data[start + 38..start + 42].copy_from_slice(&(0o120_777u32 << 16).to_le_bytes());
+            }
+        }
+        data
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_chained_links() {
+        // Each entry looks fine on its own, together they would write to dst/../evil
+        let data = archive_with_links(&[("x", ".", true), ("x/y", "..", true), ("y/evil", "evil", false)]);
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out");
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert!(extract(&mut ZipArchive::new(Cursor::new(data)).unwrap(), &dst, &mut limits).is_err());
+        assert!(!dir.path().join("evil").exists());
+        assert!(!dst.join("y").exists());
+
+        // A link resolving through an earlier one is refused as well
+        let data = archive_with_links(&[("x", ".", true), ("y", "x/..", true)]);
+        let dst = dir.path().join("other");
+        let mut limits = Limits::from_lua(None).unwrap();
+        assert!(extract(&mut ZipArchive::new(Cursor::new(data)).unwrap(), &dst, &mut limits).is_err());
+        assert!(fs::symlink_metadata(dst.join("y")).is_err());
+
+        let data = archive_with_links(&[("docs/current", "../shared", true), ("shared/a.md", "a", false)]);
+        let mut limits = Limits::from_lua(None).unwrap();
+        let extracted = extract(&mut ZipArchive::new(Cursor::new(data)).unwrap(), &dir.path().join("fine"), &mut limits).unwrap();
+        assert_eq!(extracted, vec!["docs/current", "shared/a.md"]);
+        assert_eq!(fs::read_to_string(dir.path().join("fine/docs/current/a.md")).unwrap(), "a");
+    }
+}
@@ -0,0 +1,145 @@
+use rlua::{prelude::*, UserDataMethods, UserData, MetaMethod};
+use sodiumoxide::crypto::aead::{aes256gcm, xchacha20poly1305_ietf as xchacha};
+use std::fs;
+use base64;
+use crate::error::Error;
+
+/// AEAD key for XChaCha20-Poly1305, or AES256-GCM where the CPU supports it
+pub enum LuaKey {
+    XChaCha(xchacha::Key),
+    Aes(aes256gcm::Aes256Gcm, aes256gcm::Key),
+}
+
+impl LuaKey {
+    fn nonce_bytes(&self) -> usize {
+        match self {
+            LuaKey::XChaCha(_) => xchacha::NONCEBYTES,
+            LuaKey::Aes(..) => aes256gcm::NONCEBYTES,
+        }
+    }
+
+    fn new_nonce(&self) -> Vec<u8> {
+        match self {
+            LuaKey::XChaCha(_) => xchacha::gen_nonce().0.to_vec(),
+            LuaKey::Aes(aes, _) => aes.gen_initial_nonce().0.to_vec(),
+        }
+    }
+
+    /// Encrypts `msg` under `nonce` and prepends the nonce to the ciphertext
+    fn seal(&self, msg: &[u8], ad: Option<&[u8]>, nonce: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut sealed = nonce.to_vec();
+        sealed.extend(match self {
+            LuaKey::XChaCha(key) => {
+                let nonce = xchacha::Nonce::from_slice(nonce).ok_or(Error::InvalidNonce)?;
+                xchacha::seal(msg, ad, &nonce, key)
+            },
+            LuaKey::Aes(aes, key) => {
+                let nonce = aes256gcm::Nonce::from_slice(nonce).ok_or(Error::InvalidNonce)?;
+                aes.seal(msg, ad, &nonce, key)
+            },
+        });
+        Ok(sealed)
+    }
+
+    /// Splits the nonce off `sealed` and decrypts the rest
+    fn open(&self, sealed: &[u8], ad: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        if sealed.len() < self.nonce_bytes() {
+            return Err(Error::FailedToDecrypt);
+        }
+        let (nonce, cipher) = sealed.split_at(self.nonce_bytes());
+        let msg = match self {
+            LuaKey::XChaCha(key) => {
+                let nonce = xchacha::Nonce::from_slice(nonce).ok_or(Error::InvalidNonce)?;
+                xchacha::open(cipher, ad, &nonce, key)
+            },
+            LuaKey::Aes(aes, key) => {
+                let nonce = aes256gcm::Nonce::from_slice(nonce).ok_or(Error::InvalidNonce)?;
+                aes.open(cipher, ad, &nonce, key)
+            },
+        };
+        msg.map_err(|_| Error::FailedToDecrypt)
+    }
+}
+
+fn aes() -> Result<aes256gcm::Aes256Gcm, LuaError> {
+    aes256gcm::Aes256Gcm::new()
+        .map_err(|_| LuaError::external(format_err!("AES256-GCM is not supported on this CPU")))
+}
+
+/// Returns `msg` encrypted and authenticated together with the optional
+/// additional data `ad`, base64 encoded with the nonce prepended
+pub fn seal(_: LuaContext, this: &LuaKey, (msg, ad, nonce): (LuaString, Option<LuaString>, Option<String>)) -> Result<String, LuaError> {
+    let nonce = match nonce {
+        Some(nonce) => base64::decode(&nonce).map_err(LuaError::external)?,
+        None => this.new_nonce(),
+    };
+    this.seal(msg.as_bytes(), ad.as_ref().map(LuaString::as_bytes), &nonce)
+        .map(|sealed| base64::encode(&sealed))
+        .map_err(LuaError::external)
+}
+
+/// Returns the plaintext of a message produced by `seal` with the same additional data
+pub fn open<'lua>(lua: LuaContext<'lua>, this: &LuaKey, (sealed, ad): (String, Option<LuaString>)) -> Result<LuaString<'lua>, LuaError> {
+    let sealed = base64::decode(&sealed).map_err(LuaError::external)?;
+    let msg = this.open(&sealed, ad.as_ref().map(LuaString::as_bytes)).map_err(LuaError::external)?;
+    lua.create_string(&msg)
+}
+
+/// Encrypts the file at `input` into `output` as the nonce followed by the ciphertext
+pub fn seal_file(_: LuaContext, this: &LuaKey, (input, output, ad): (String, String, Option<LuaString>)) -> Result<(), LuaError> {
+    let msg = fs::read(&input).map_err(LuaError::external)?;
+    let sealed = this.seal(&msg, ad.as_ref().map(LuaString::as_bytes), &this.new_nonce()).map_err(LuaError::external)?;
+    fs::write(&output, sealed).map_err(LuaError::external)
+}
+
+/// Decrypts a file written by `seal_file`
+pub fn open_file(_: LuaContext, this: &LuaKey, (input, output, ad): (String, String, Option<LuaString>)) -> Result<(), LuaError> {
+    let sealed = fs::read(&input).map_err(LuaError::external)?;
+    let msg = this.open(&sealed, ad.as_ref().map(LuaString::as_bytes)).map_err(LuaError::external)?;
+    fs::write(&output, msg).map_err(LuaError::external)
+}
+
+impl UserData for LuaKey {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("seal", seal);
+        methods.add_method("open", open);
+        methods.add_method("seal_file", seal_file);
+        methods.add_method("open_file", open_file);
+        methods.add_method("new_nonce", |_, this, _: ()| {
+            Ok(base64::encode(&this.new_nonce()))
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, this, _: ()| {
+            Ok(match this {
+                LuaKey::XChaCha(key) => base64::encode(&key.0),
+                LuaKey::Aes(_, key) => base64::encode(&key.0),
+            })
+        });
+    }
+}
+
+/// Returns a new random key for `algorithm`, either "xchacha20poly1305" (default) or "aes256gcm"
+pub fn new_key(_: LuaContext, algorithm: Option<String>) -> LuaResult<LuaKey> {
+    match algorithm.as_ref().map(String::as_str) {
+        None | Some("xchacha20poly1305") => Ok(LuaKey::XChaCha(xchacha::gen_key())),
+        Some("aes256gcm") => {
+            let aes = aes()?;
+            let key = aes.gen_key();
+            Ok(LuaKey::Aes(aes, key))
+        },
+        Some(other) => Err(LuaError::external(format_err!("unsupported AEAD algorithm: {}", other))),
+    }
+}
+
+/// Constructs and returns a LuaKey object from it's base64 string encoding
+pub fn load_key(_: LuaContext, (base64_key, algorithm): (String, Option<String>)) -> Result<LuaKey, LuaError> {
+    let bytes = base64::decode(&base64_key).map_err(LuaError::external)?;
+    let key = match algorithm.as_ref().map(String::as_str) {
+        None | Some("xchacha20poly1305") => xchacha::Key::from_slice(&bytes).map(LuaKey::XChaCha),
+        Some("aes256gcm") => {
+            let aes = aes()?;
+            aes256gcm::Key::from_slice(&bytes).map(|key| LuaKey::Aes(aes, key))
+        },
+        Some(other) => return Err(LuaError::external(format_err!("unsupported AEAD algorithm: {}", other))),
+    };
+    key.ok_or(LuaError::external(Error::InvalidKeys))
+}
@@ -1,18 +1,169 @@
-use rlua::{Error as LuaError, Context};
+use rlua::{prelude::*, Context, UserData, UserDataMethods};
 use blake2::*;
-use sodiumoxide::crypto::hash;
+use md5::Md5;
+use sha1::Sha1;
+use sodiumoxide::crypto::{auth::{hmacsha256, hmacsha512}, generichash, hash};
+use std::{fs::File, io::Read};
 use base64;
 
 /// Returns base64 encoded SHA-512 of `msg`
-pub fn hash(_lua: Context, msg: String) -> Result<String, LuaError> {
+pub fn hash(_lua: Context, msg: LuaString) -> Result<String, LuaError> {
     let digest = hash::hash(msg.as_bytes());
     Ok(base64::encode(&digest))
 }
 
 /// Returns base64 encoded BLAKE2B of `msg`
-pub fn blake2_hash(_lua: Context, msg: String) -> Result<String, LuaError> {
+pub fn blake2_hash(_lua: Context, msg: LuaString) -> Result<String, LuaError> {
     let mut hasher = Blake2b::new();
-    hasher.input(msg);
+    hasher.input(msg.as_bytes());
     let digest = hasher.result();
     Ok(base64::encode(&digest))
 }
+
+/// Incremental state of one of the supported hash or MAC algorithms
+enum Hasher {
+    Sha256(hash::sha256::State),
+    Sha512(hash::sha512::State),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake2b(generichash::State),
+    HmacSha256(hmacsha256::State),
+    HmacSha512(hmacsha512::State),
+}
+
+impl Hasher {
+    /// Creates a hasher for `algorithm`; `key` is required for HMAC and optional for BLAKE2b,
+    /// whose output is `length` bytes long (64 by default, the BLAKE2b-512 of `crypto.blake2b`)
+    fn new(algorithm: &str, key: Option<&[u8]>, length: Option<usize>) -> Result<Self, LuaError> {
+        let hasher = match (algorithm, key) {
+            ("sha256", None) => Hasher::Sha256(hash::sha256::State::new()),
+            ("sha512", None) => Hasher::Sha512(hash::sha512::State::new()),
+            ("sha1", None) => Hasher::Sha1(Sha1::new()),
+            ("md5", None) => Hasher::Md5(Md5::new()),
+            ("blake2b", key) => generichash::State::new(Some(length.unwrap_or(64)), key)
+                .map(Hasher::Blake2b)
+                .map_err(|_| LuaError::external(format_err!(
+                    "BLAKE2b needs a key of {} to {} bytes and a length of {} to {} bytes",
+                    generichash::KEY_MIN, generichash::KEY_MAX, generichash::DIGEST_MIN, generichash::DIGEST_MAX
+                )))?,
+            ("hmac-sha256", Some(key)) => Hasher::HmacSha256(hmacsha256::State::init(key)),
+            ("hmac-sha512", Some(key)) => Hasher::HmacSha512(hmacsha512::State::init(key)),
+            ("hmac-sha256", None) | ("hmac-sha512", None) => {
+                return Err(LuaError::external(format_err!("{} needs a key", algorithm)))
+            },
+            ("sha256", _) | ("sha512", _) | ("sha1", _) | ("md5", _) => {
+                return Err(LuaError::external(format_err!("{} does not take a key, use hmac-{} instead", algorithm, algorithm)))
+            },
+            _ => return Err(LuaError::external(format_err!("unsupported hash algorithm: {}", algorithm))),
+        };
+        Ok(hasher)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(state) => state.update(data),
+            Hasher::Sha512(state) => state.update(data),
+            Hasher::Sha1(state) => state.input(data),
+            Hasher::Md5(state) => state.input(data),
+            // Only fails once finalized, which consumes the state
+            Hasher::Blake2b(state) => state.update(data).unwrap_or(()),
+            Hasher::HmacSha256(state) => state.update(data),
+            Hasher::HmacSha512(state) => state.update(data),
+        }
+    }
+
+    fn finalize(self) -> Result<Vec<u8>, LuaError> {
+        Ok(match self {
+            Hasher::Sha256(state) => state.finalize().0.to_vec(),
+            Hasher::Sha512(state) => state.finalize().0.to_vec(),
+            Hasher::Sha1(state) => state.result().to_vec(),
+            Hasher::Md5(state) => state.result().to_vec(),
+            Hasher::Blake2b(state) => state.finalize()
+                .map(|digest| digest.as_ref().to_vec())
+                .map_err(|_| LuaError::external(format_err!("BLAKE2b failed to finalize")))?,
+            Hasher::HmacSha256(state) => state.finalize().0.to_vec(),
+            Hasher::HmacSha512(state) => state.finalize().0.to_vec(),
+        })
+    }
+}
+
+/// Returns the raw digest of `data`; also backs the keyed BLAKE2b of `crypto.kdf`
+pub(super) fn digest_bytes(algorithm: &str, key: Option<&[u8]>, length: Option<usize>, data: &[u8]) -> Result<Vec<u8>, LuaError> {
+    let mut hasher = Hasher::new(algorithm, key, length)?;
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Reads the `key`, `length` and `encoding` options shared by the hashing functions
+fn options(options: Option<LuaTable>) -> Result<(Option<Vec<u8>>, Option<usize>, Option<String>), LuaError> {
+    match options {
+        Some(options) => Ok((
+            options.get::<_, Option<LuaString>>("key")?.map(|key| key.as_bytes().to_vec()),
+            options.get("length")?,
+            options.get("encoding")?,
+        )),
+        None => Ok((None, None, None)),
+    }
+}
+
+/// Encodes `digest` as "hex" (default), "base64" or "raw" bytes
+fn encode<'lua>(lua: Context<'lua>, digest: &[u8], encoding: Option<&str>) -> Result<LuaString<'lua>, LuaError> {
+    match encoding.unwrap_or("hex") {
+        "hex" => lua.create_string(&digest.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        "base64" => lua.create_string(&base64::encode(digest)),
+        "raw" => lua.create_string(digest),
+        other => Err(LuaError::external(format_err!("unsupported encoding: {}", other))),
+    }
+}
+
+/// Incremental hasher fed with `update` and consumed by `finalize`
+pub struct LuaHasher(Option<Hasher>);
+
+impl UserData for LuaHasher {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("update", |_, this, data: LuaString| {
+            let hasher = this.0.as_mut().ok_or(LuaError::external(format_err!("hasher is already finalized")))?;
+            hasher.update(data.as_bytes());
+            Ok(())
+        });
+        methods.add_method_mut("finalize", |lua, this, encoding: Option<String>| {
+            let hasher = this.0.take().ok_or(LuaError::external(format_err!("hasher is already finalized")))?;
+            encode(lua, &hasher.finalize()?, encoding.as_ref().map(String::as_str))
+        });
+    }
+}
+
+/// Returns a new incremental hasher for `algorithm`: sha256, sha512, sha1, md5, blake2b,
+/// hmac-sha256 or hmac-sha512
+pub fn hasher(_lua: Context, (algorithm, opts): (String, Option<LuaTable>)) -> Result<LuaHasher, LuaError> {
+    let (key, length, _) = options(opts)?;
+    Hasher::new(&algorithm, key.as_ref().map(Vec::as_slice), length).map(|hasher| LuaHasher(Some(hasher)))
+}
+
+/// Returns the digest of `data` with `algorithm`, hex encoded unless another encoding is given
+pub fn digest<'lua>(lua: Context<'lua>, (algorithm, data, opts): (String, LuaString, Option<LuaTable>)) -> Result<LuaString<'lua>, LuaError> {
+    let (key, length, encoding) = options(opts)?;
+    let digest = digest_bytes(&algorithm, key.as_ref().map(Vec::as_slice), length, data.as_bytes())?;
+    encode(lua, &digest, encoding.as_ref().map(String::as_str))
+}
+
+/// Returns the HMAC of `data` under `key` with "sha256" or "sha512"
+pub fn hmac<'lua>(lua: Context<'lua>, (algorithm, key, data, encoding): (String, LuaString, LuaString, Option<String>)) -> Result<LuaString<'lua>, LuaError> {
+    let digest = digest_bytes(&format!("hmac-{}", algorithm), Some(key.as_bytes()), None, data.as_bytes())?;
+    encode(lua, &digest, encoding.as_ref().map(String::as_str))
+}
+
+/// Returns the digest of the file at `path`, read in chunks rather than all at once
+pub fn hash_file<'lua>(lua: Context<'lua>, (algorithm, path, opts): (String, String, Option<LuaTable>)) -> Result<LuaString<'lua>, LuaError> {
+    let (key, length, encoding) = options(opts)?;
+    let mut hasher = Hasher::new(&algorithm, key.as_ref().map(Vec::as_slice), length)?;
+    let mut file = File::open(&path).map_err(LuaError::external)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf).map_err(LuaError::external)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    encode(lua, &hasher.finalize()?, encoding.as_ref().map(String::as_str))
+}
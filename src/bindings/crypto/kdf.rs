@@ -0,0 +1,92 @@
+use rlua::prelude::*;
+use sodiumoxide::crypto::{auth::hmacsha256, kdf};
+use base64;
+use crate::error::Error;
+
+/// HKDF-SHA256 (RFC 5869) of `ikm` into `length` bytes
+fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+    if length > 255 * hmacsha256::TAGBYTES {
+        return Err(format_err!("HKDF output is limited to {} bytes", 255 * hmacsha256::TAGBYTES));
+    }
+
+    let mut extract = hmacsha256::State::init(salt);
+    extract.update(ikm);
+    let prk = extract.finalize();
+
+    let mut okm = Vec::with_capacity(length);
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while okm.len() < length {
+        let mut expand = hmacsha256::State::init(&prk.0);
+        expand.update(&block);
+        expand.update(info);
+        expand.update(&[counter]);
+        block = expand.finalize().0.to_vec();
+        okm.extend_from_slice(&block);
+        counter = counter.wrapping_add(1);
+    }
+    okm.truncate(length);
+    Ok(okm)
+}
+
+/// Derives a `length` byte key (32 by default) from the input keying material `ikm`
+/// with HKDF-SHA256, optionally bound to a `salt` and an `info` context. Returns it base64 encoded.
+pub fn hkdf(_: LuaContext, (ikm, options): (LuaString, Option<LuaTable>)) -> Result<String, LuaError> {
+    let (salt, info, length) = match options {
+        Some(options) => (
+            options.get::<_, Option<LuaString>>("salt")?.map(|s| s.as_bytes().to_vec()),
+            options.get::<_, Option<LuaString>>("info")?.map(|s| s.as_bytes().to_vec()),
+            options.get::<_, Option<usize>>("length")?,
+        ),
+        None => (None, None, None),
+    };
+    let salt = salt.unwrap_or_else(|| vec![0; hmacsha256::TAGBYTES]);
+    hkdf_sha256(ikm.as_bytes(), &salt, &info.unwrap_or_default(), length.unwrap_or(32))
+        .map(|key| base64::encode(&key))
+        .map_err(LuaError::external)
+}
+
+/// Returns base64 encoded BLAKE2b of `msg` keyed with `key`, `length` bytes long (32 by default).
+/// Same as `crypto.digest("blake2b", msg, { key = key, encoding = "base64" })`
+pub fn blake2b(_: LuaContext, (msg, key, length): (LuaString, LuaString, Option<usize>)) -> Result<String, LuaError> {
+    super::hash::digest_bytes("blake2b", Some(key.as_bytes()), Some(length.unwrap_or(32)), msg.as_bytes())
+        .map(|digest| base64::encode(&digest))
+}
+
+/// Returns a new random master key for `derive`, base64 encoded
+pub fn new_key(_: LuaContext, _: ()) -> LuaResult<String> {
+    Ok(base64::encode(&kdf::gen_key().0))
+}
+
+/// Derives subkey number `id` of `length` bytes (32 by default) from the base64 master `key`.
+/// `context` is an 8 character label separating keys derived for different purposes.
+pub fn derive(_: LuaContext, (key, id, context, length): (String, u64, String, Option<usize>)) -> Result<String, LuaError> {
+    let key = base64::decode(&key).map_err(Error::from)
+        .and_then(|bytes| kdf::Key::from_slice(&bytes).ok_or(Error::InvalidKeys))
+        .map_err(LuaError::external)?;
+    if context.len() != kdf::CONTEXTBYTES {
+        return Err(LuaError::external(format_err!("context must be {} bytes long", kdf::CONTEXTBYTES)));
+    }
+    let mut ctx = [0; kdf::CONTEXTBYTES];
+    ctx.copy_from_slice(context.as_bytes());
+
+    let mut subkey = vec![0; length.unwrap_or(32)];
+    kdf::derive_from_key(&mut subkey, id, ctx, &key)
+        .map_err(|_| LuaError::external(format_err!("subkeys must be {} to {} bytes long", kdf::BYTES_MIN, kdf::BYTES_MAX)))?;
+    Ok(base64::encode(&subkey))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hkdf_rfc5869_vector() {
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let okm = hkdf_sha256(&[0x0b; 22], &salt, &info, 42).unwrap();
+        let hex: String = okm.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865");
+        assert!(hkdf_sha256(b"ikm", b"", b"", 255 * 32 + 1).is_err());
+    }
+}
@@ -1,8 +1,13 @@
 mod hash;
-mod sign;
+pub(crate) mod sign;
 mod random;
 mod box_;
 mod checksumdir;
+mod secretbox;
+mod aead;
+mod secretstream;
+mod password;
+mod kdf;
 
 use rlua::{Error as LuaError, Lua};
 use sodiumoxide;
@@ -16,6 +21,10 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
         crypto.set("random_bytes", lua.create_function(random::random_bytes)?)?;
         crypto.set("hash", lua.create_function(hash::hash)?)?;
         crypto.set("blake2b", lua.create_function(hash::blake2_hash)?)?;
+        crypto.set("digest", lua.create_function(hash::digest)?)?;
+        crypto.set("hmac", lua.create_function(hash::hmac)?)?;
+        crypto.set("hasher", lua.create_function(hash::hasher)?)?;
+        crypto.set("hash_file", lua.create_function(hash::hash_file)?)?;
         crypto.set("checksumdir", lua.create_function(checksumdir::checksum)?)?;
 
         let sign = lua.create_table()?;
@@ -31,6 +40,36 @@ pub fn init(lua: &Lua) -> crate::Result<()> {
         box_.set("load_nonce", lua.create_function(box_::load_nonce)?)?;
         crypto.set("box", box_)?;
 
+        let secretbox = lua.create_table()?;
+        secretbox.set("new_key", lua.create_function(secretbox::new_key)?)?;
+        secretbox.set("new_nonce", lua.create_function(secretbox::new_nonce)?)?;
+        secretbox.set("load_key", lua.create_function(secretbox::load_key)?)?;
+        crypto.set("secretbox", secretbox)?;
+
+        let aead = lua.create_table()?;
+        aead.set("new_key", lua.create_function(aead::new_key)?)?;
+        aead.set("load_key", lua.create_function(aead::load_key)?)?;
+        crypto.set("aead", aead)?;
+
+        let secretstream = lua.create_table()?;
+        secretstream.set("new_key", lua.create_function(secretstream::new_key)?)?;
+        secretstream.set("load_key", lua.create_function(secretstream::load_key)?)?;
+        crypto.set("secretstream", secretstream)?;
+
+        let password = lua.create_table()?;
+        password.set("hash", lua.create_function(password::hash)?)?;
+        password.set("verify", lua.create_function(password::verify)?)?;
+        password.set("new_salt", lua.create_function(password::new_salt)?)?;
+        password.set("derive_key", lua.create_function(password::derive_key)?)?;
+        crypto.set("password", password)?;
+
+        let kdf = lua.create_table()?;
+        kdf.set("hkdf", lua.create_function(kdf::hkdf)?)?;
+        kdf.set("blake2b", lua.create_function(kdf::blake2b)?)?;
+        kdf.set("new_key", lua.create_function(kdf::new_key)?)?;
+        kdf.set("derive", lua.create_function(kdf::derive)?)?;
+        crypto.set("kdf", kdf)?;
+
         lua.globals().set("crypto", crypto)?;
 
         Ok(())
@@ -148,7 +187,168 @@ mod tests {
         })
 
     }
-}
 
+    #[test]
+    fn lua_secretbox_and_aead() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("plain.txt"), "top secret").unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local key = crypto.secretbox.load_key(tostring(crypto.secretbox.new_key()))
+                local sealed = key:seal("hello\0world")
+                assert(key:open(sealed) == "hello\0world")
+                assert(key:seal("same") ~= key:seal("same"))
+                assert(key:open(key:seal("fixed", crypto.secretbox.new_nonce())) == "fixed")
+                assert(not pcall(crypto.secretbox.new_key().open, crypto.secretbox.new_key(), sealed))
+
+                key:seal_file(dir .. "/plain.txt", dir .. "/plain.box")
+                key:open_file(dir .. "/plain.box", dir .. "/plain.out")
+
+                local aead = crypto.aead.new_key()
+                local sealed = aead:seal("payload", "header")
+                assert(aead:open(sealed, "header") == "payload")
+                assert(not pcall(aead.open, aead, sealed, "other header"))
+                assert(crypto.aead.load_key(tostring(aead)):open(sealed, "header") == "payload")
+                assert(not pcall(crypto.aead.new_key, "rot13"))
+            "#).exec().unwrap();
+        });
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("plain.out")).unwrap(), "top secret");
+    }
+
+    #[test]
+    fn lua_secretstream() {
+        let dir = tempfile::tempdir().unwrap();
+        let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.path().join("large.bin"), &large).unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                key = crypto.secretstream.new_key()
+                key:encrypt_file(dir .. "/large.bin", dir .. "/large.enc")
+                key:decrypt_file(dir .. "/large.enc", dir .. "/large.out")
+                assert(not pcall(crypto.secretstream.new_key().decrypt_file, crypto.secretstream.new_key(), dir .. "/large.enc", dir .. "/bad.out"))
+
+                local encryptor = key:encryptor()
+                local first = encryptor:push("one ")
+                local last = encryptor:finish("two")
+                assert(not pcall(encryptor.push, encryptor, "three"))
+
+                local decryptor = crypto.secretstream.load_key(tostring(key)):decryptor(encryptor:header())
+                local msg, done = decryptor:pull(first)
+                assert(msg == "one " and not done)
+                msg, done = decryptor:pull(last)
+                assert(msg == "two" and done)
+            "#).exec().unwrap();
+        });
+
+        assert_eq!(std::fs::read(dir.path().join("large.out")).unwrap(), large);
 
+        let mut encrypted = std::fs::read(dir.path().join("large.enc")).unwrap();
+        let truncated = encrypted.len() - 100;
+        encrypted.truncate(truncated);
+        std::fs::write(dir.path().join("large.enc"), &encrypted).unwrap();
+        lua.context(|lua| {
+            assert!(lua.load(r#"key:decrypt_file(dir .. "/large.enc", dir .. "/cut.out")"#).exec().is_err());
+            assert!(lua.load(r#"key:decrypt_file(dir .. "/large.enc", dir .. "/large.out")"#).exec().is_err());
+        });
+        // Failures leave no partial plaintext behind and the previous output untouched
+        assert!(!dir.path().join("cut.out").exists());
+        assert!(!dir.path().join(".cut.out.tmp").exists());
+        assert!(!dir.path().join("bad.out").exists());
+        assert_eq!(std::fs::read(dir.path().join("large.out")).unwrap(), large);
+    }
+
+    #[test]
+    fn lua_password_and_kdf() {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                local encoded = crypto.password.hash("correct horse")
+                assert(encoded:sub(1, 10) == "$argon2id$")
+                assert(crypto.password.verify(encoded, "correct horse"))
+                assert(not crypto.password.verify(encoded, "battery staple"))
+                assert(not crypto.password.verify("garbage", "correct horse"))
+                assert(not pcall(crypto.password.hash, "x", { ops_limit = "extreme" }))
+
+                local key, salt = crypto.password.derive_key("correct horse")
+                local again = crypto.password.derive_key("correct horse", salt)
+                assert(key == again)
+                assert(crypto.password.derive_key("correct horse", crypto.password.new_salt()) ~= key)
+                local box = crypto.secretbox.load_key(key)
+                assert(box:open(box:seal("data")) == "data")
+
+                local a = crypto.kdf.hkdf("master secret", { salt = "salt", info = "files" })
+                assert(a == crypto.kdf.hkdf("master secret", { salt = "salt", info = "files" }))
+                assert(a ~= crypto.kdf.hkdf("master secret", { salt = "salt", info = "tokens" }))
+                assert(#crypto.kdf.hkdf("master secret", { length = 64 }) == 88)
+
+                local mac = crypto.kdf.blake2b("message", "0123456789abcdef")
+                assert(mac ~= crypto.kdf.blake2b("message", "fedcba9876543210"))
+                assert(mac == crypto.digest("blake2b", "message", { key = "0123456789abcdef", length = 32, encoding = "base64" }))
+                assert(#crypto.kdf.blake2b("message", "0123456789abcdef", 64) == 88)
+                assert(not pcall(crypto.kdf.blake2b, "message", "short"))
+
+                local master = crypto.kdf.new_key()
+                local one = crypto.kdf.derive(master, 1, "sessions")
+                assert(one == crypto.kdf.derive(master, 1, "sessions"))
+                assert(one ~= crypto.kdf.derive(master, 2, "sessions"))
+                assert(not pcall(crypto.kdf.derive, master, 1, "too long context"))
+                crypto.aead.load_key(one)
+            "#).exec().unwrap();
+        });
+    }
 
+    #[test]
+    fn lua_digests() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("abc.txt"), "abc").unwrap();
+
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.globals().set("dir", dir.path().to_str().unwrap()).unwrap();
+            lua.load(r#"
+                local sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                assert(crypto.digest("sha256", "abc") == sha256)
+                assert(crypto.digest("sha1", "abc") == "a9993e364706816aba3e25717850c26c9cd0d89d")
+                assert(crypto.digest("md5", "abc") == "900150983cd24fb0d6963f7d28e17f72")
+                assert(#crypto.digest("sha512", "abc", { encoding = "raw" }) == 64)
+                assert(crypto.digest("sha256", "abc", { encoding = "base64" }) == "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=")
+                assert(crypto.hash_file("sha256", dir .. "/abc.txt") == sha256)
+
+                assert(crypto.hmac("sha256", "Jefe", "what do ya want for nothing?")
+                    == "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+                assert(crypto.hmac("sha512", "Jefe", "what do ya want for nothing?"):sub(1, 16) == "164b7a7bfcf819e2")
+                assert(not pcall(crypto.digest, "hmac-sha256", "data"))
+                assert(not pcall(crypto.digest, "sha256", "data", { key = "secret" }))
+
+                assert(#crypto.digest("blake2b", "abc", { length = 16, encoding = "raw" }) == 16)
+                assert(crypto.digest("blake2b", "abc", { encoding = "base64" }) == crypto.blake2b("abc"))
+                local keyed = crypto.digest("blake2b", "abc", { key = "0123456789abcdef" })
+                assert(keyed ~= crypto.digest("blake2b", "abc"))
+
+                local hasher = crypto.hasher("sha256")
+                hasher:update("a")
+                hasher:update("bc")
+                assert(hasher:finalize() == sha256)
+                assert(not pcall(hasher.update, hasher, "more"))
+
+                local mac = crypto.hasher("hmac-sha256", { key = "Jefe" })
+                mac:update("what do ya want ")
+                mac:update("for nothing?")
+                assert(mac:finalize("hex"):sub(1, 8) == "5bdcc146")
+
+                assert(crypto.hash("bin\0ary") ~= crypto.hash("bin"))
+            "#).exec().unwrap();
+        });
+    }
+}
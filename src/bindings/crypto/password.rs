@@ -0,0 +1,84 @@
+use rlua::prelude::*;
+use sodiumoxide::crypto::pwhash::argon2id13 as argon2;
+use base64;
+use crate::error::Error;
+
+/// Reads `ops_limit`/`mem_limit` as "interactive", "moderate", "sensitive" or a raw number
+fn limits(options: Option<LuaTable>) -> LuaResult<(argon2::OpsLimit, argon2::MemLimit)> {
+    let (ops, mem) = match options {
+        Some(options) => (options.get("ops_limit")?, options.get("mem_limit")?),
+        None => (LuaValue::Nil, LuaValue::Nil),
+    };
+
+    let ops = match ops {
+        LuaValue::Nil => argon2::OPSLIMIT_INTERACTIVE,
+        LuaValue::Integer(n) => argon2::OpsLimit(n as usize),
+        LuaValue::Number(n) => argon2::OpsLimit(n as usize),
+        LuaValue::String(s) => match s.to_str()? {
+            "interactive" => argon2::OPSLIMIT_INTERACTIVE,
+            "moderate" => argon2::OPSLIMIT_MODERATE,
+            "sensitive" => argon2::OPSLIMIT_SENSITIVE,
+            other => return Err(LuaError::external(format_err!("unknown ops_limit: {}", other))),
+        },
+        _ => return Err(LuaError::external(format_err!("ops_limit must be a name or a number"))),
+    };
+    let mem = match mem {
+        LuaValue::Nil => argon2::MEMLIMIT_INTERACTIVE,
+        LuaValue::Integer(n) => argon2::MemLimit(n as usize),
+        LuaValue::Number(n) => argon2::MemLimit(n as usize),
+        LuaValue::String(s) => match s.to_str()? {
+            "interactive" => argon2::MEMLIMIT_INTERACTIVE,
+            "moderate" => argon2::MEMLIMIT_MODERATE,
+            "sensitive" => argon2::MEMLIMIT_SENSITIVE,
+            other => return Err(LuaError::external(format_err!("unknown mem_limit: {}", other))),
+        },
+        _ => return Err(LuaError::external(format_err!("mem_limit must be a name or a number"))),
+    };
+    Ok((ops, mem))
+}
+
+/// Returns the Argon2id hash of `password` as an encoded `$argon2id$...` string
+pub fn hash(_: LuaContext, (password, options): (LuaString, Option<LuaTable>)) -> Result<String, LuaError> {
+    let (ops, mem) = limits(options)?;
+    let hashed = argon2::pwhash(password.as_bytes(), ops, mem)
+        .map_err(|_| LuaError::external(format_err!("failed to hash password")))?;
+    let len = hashed.0.iter().position(|&b| b == 0).unwrap_or(hashed.0.len());
+    String::from_utf8(hashed.0[..len].to_vec()).map_err(LuaError::external)
+}
+
+/// Returns true/false if `password` matches the encoded hash produced by `hash`
+pub fn verify(_: LuaContext, (encoded, password): (String, LuaString)) -> Result<bool, LuaError> {
+    if encoded.len() >= argon2::HASHEDPASSWORDBYTES {
+        return Ok(false);
+    }
+    let mut bytes = encoded.into_bytes();
+    bytes.resize(argon2::HASHEDPASSWORDBYTES, 0);
+    Ok(argon2::HashedPassword::from_slice(&bytes)
+        .map_or(false, |hashed| argon2::pwhash_verify(&hashed, password.as_bytes())))
+}
+
+/// Returns a new random salt for `derive_key`, base64 encoded
+pub fn new_salt(_: LuaContext, _: ()) -> LuaResult<String> {
+    Ok(base64::encode(&argon2::gen_salt().0))
+}
+
+/// Stretches `password` into a `length` byte key (32 by default) with Argon2id.
+/// Returns the base64 encoded key and salt; a new salt is generated when none is given.
+pub fn derive_key(_: LuaContext, (password, salt, options): (LuaString, Option<String>, Option<LuaTable>)) -> Result<(String, String), LuaError> {
+    let salt = match salt {
+        Some(salt) => base64::decode(&salt).map_err(Error::from)
+            .and_then(|bytes| argon2::Salt::from_slice(&bytes).ok_or(Error::InvalidKeys))
+            .map_err(LuaError::external)?,
+        None => argon2::gen_salt(),
+    };
+    let length = match &options {
+        Some(options) => options.get::<_, Option<usize>>("length")?.unwrap_or(32),
+        None => 32,
+    };
+    let (ops, mem) = limits(options)?;
+
+    let mut key = vec![0; length];
+    argon2::derive_key(&mut key, password.as_bytes(), &salt, ops, mem)
+        .map_err(|_| LuaError::external(format_err!("failed to derive key")))?;
+    Ok((base64::encode(&key), base64::encode(&salt.0)))
+}
@@ -0,0 +1,88 @@
+use rlua::{prelude::*, UserDataMethods, UserData, MetaMethod};
+use sodiumoxide::crypto::secretbox;
+use std::fs;
+use base64;
+use crate::error::Error;
+
+pub struct LuaKey (secretbox::Key);
+
+/// Encrypts `msg` under `nonce` and prepends the nonce to the ciphertext
+fn seal_bytes(msg: &[u8], nonce: &secretbox::Nonce, key: &secretbox::Key) -> Vec<u8> {
+    let mut sealed = nonce.0.to_vec();
+    sealed.extend(secretbox::seal(msg, nonce, key));
+    sealed
+}
+
+/// Splits the nonce off `sealed` and decrypts the rest
+fn open_bytes(sealed: &[u8], key: &secretbox::Key) -> Result<Vec<u8>, Error> {
+    if sealed.len() < secretbox::NONCEBYTES {
+        return Err(Error::FailedToDecrypt);
+    }
+    let (nonce, cipher) = sealed.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce).ok_or(Error::InvalidNonce)?;
+    secretbox::open(cipher, &nonce, key).map_err(|_| Error::FailedToDecrypt)
+}
+
+/// Reads a base64 nonce, generating a random one when none is given
+fn get_nonce(nonce: Option<String>) -> Result<secretbox::Nonce, LuaError> {
+    match nonce {
+        Some(nonce) => base64::decode(&nonce).map_err(LuaError::external)
+            .and_then(|bytes| secretbox::Nonce::from_slice(&bytes).ok_or(LuaError::external(Error::InvalidNonce))),
+        None => Ok(secretbox::gen_nonce()),
+    }
+}
+
+/// Returns `msg` encrypted and authenticated, base64 encoded with the nonce prepended
+pub fn seal(_: LuaContext, this: &LuaKey, (msg, nonce): (LuaString, Option<String>)) -> Result<String, LuaError> {
+    let nonce = get_nonce(nonce)?;
+    Ok(base64::encode(&seal_bytes(msg.as_bytes(), &nonce, &this.0)))
+}
+
+/// Returns the plaintext of a message produced by `seal`
+pub fn open<'lua>(lua: LuaContext<'lua>, this: &LuaKey, sealed: String) -> Result<LuaString<'lua>, LuaError> {
+    let sealed = base64::decode(&sealed).map_err(LuaError::external)?;
+    let msg = open_bytes(&sealed, &this.0).map_err(LuaError::external)?;
+    lua.create_string(&msg)
+}
+
+/// Encrypts the file at `input` into `output` as the nonce followed by the ciphertext
+pub fn seal_file(_: LuaContext, this: &LuaKey, (input, output): (String, String)) -> Result<(), LuaError> {
+    let msg = fs::read(&input).map_err(LuaError::external)?;
+    fs::write(&output, seal_bytes(&msg, &secretbox::gen_nonce(), &this.0)).map_err(LuaError::external)
+}
+
+/// Decrypts a file written by `seal_file`
+pub fn open_file(_: LuaContext, this: &LuaKey, (input, output): (String, String)) -> Result<(), LuaError> {
+    let sealed = fs::read(&input).map_err(LuaError::external)?;
+    let msg = open_bytes(&sealed, &this.0).map_err(LuaError::external)?;
+    fs::write(&output, msg).map_err(LuaError::external)
+}
+
+impl UserData for LuaKey {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("seal", seal);
+        methods.add_method("open", open);
+        methods.add_method("seal_file", seal_file);
+        methods.add_method("open_file", open_file);
+        methods.add_meta_method(MetaMethod::ToString, |_, this, _: ()| {
+            Ok(base64::encode(&(this.0).0))
+        });
+    }
+}
+
+/// Returns a new random key
+pub fn new_key(_: LuaContext, _: ()) -> LuaResult<LuaKey> {
+    Ok(LuaKey(secretbox::gen_key()))
+}
+
+/// Returns a new random nonce, base64 encoded
+pub fn new_nonce(_: LuaContext, _: ()) -> LuaResult<String> {
+    Ok(base64::encode(&secretbox::gen_nonce().0))
+}
+
+/// Constructs and returns a LuaKey object from it's base64 string encoding
+pub fn load_key(_: LuaContext, base64_key: String) -> Result<LuaKey, LuaError> {
+    base64::decode(&base64_key).map_err(Error::from)
+        .and_then(|vec| secretbox::Key::from_slice(&vec).ok_or(Error::InvalidKeys).map(LuaKey))
+        .map_err(LuaError::external)
+}
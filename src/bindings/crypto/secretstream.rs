@@ -0,0 +1,181 @@
+use rlua::{prelude::*, UserDataMethods, UserData, MetaMethod};
+use sodiumoxide::crypto::secretstream::{self, Header, Key, Pull, Push, Stream, Tag};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+};
+use base64;
+use crate::error::Error;
+
+/// Plaintext bytes encrypted per message by `encrypt_file`
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct LuaKey (Key);
+pub struct LuaEncryptor {
+    stream: Option<Stream<Push>>,
+    header: Header,
+}
+pub struct LuaDecryptor (Stream<Pull>);
+
+/// Reads until `buf` is full or the reader is exhausted, returning the bytes read
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn encrypt<R: Read, W: Write>(key: &Key, input: &mut R, output: &mut W) -> Result<(), Error> {
+    let (mut stream, header) = Stream::init_push(key).map_err(|_| Error::InvalidKeys)?;
+    output.write_all(&header.0)?;
+
+    let mut current = vec![0; CHUNK_SIZE];
+    let mut next = vec![0; CHUNK_SIZE];
+    let mut len = read_chunk(input, &mut current)?;
+    loop {
+        let next_len = read_chunk(input, &mut next)?;
+        let tag = if next_len == 0 { Tag::Final } else { Tag::Message };
+        let cipher = stream.push(&current[..len], None, tag).map_err(|_| format_err!("failed to encrypt stream"))?;
+        output.write_all(&cipher)?;
+        if tag == Tag::Final {
+            return Ok(());
+        }
+        std::mem::swap(&mut current, &mut next);
+        len = next_len;
+    }
+}
+
+fn decrypt<R: Read, W: Write>(key: &Key, input: &mut R, output: &mut W) -> Result<(), Error> {
+    let mut header = [0; secretstream::HEADERBYTES];
+    input.read_exact(&mut header)?;
+    let mut stream = Stream::init_pull(&Header(header), key).map_err(|_| Error::FailedToDecrypt)?;
+
+    let mut chunk = vec![0; CHUNK_SIZE + secretstream::ABYTES];
+    loop {
+        let len = read_chunk(input, &mut chunk)?;
+        if len == 0 {
+            // The stream ended before its final message, so it was truncated
+            return Err(Error::FailedToDecrypt);
+        }
+        let (msg, tag) = stream.pull(&chunk[..len], None).map_err(|_| Error::FailedToDecrypt)?;
+        output.write_all(&msg)?;
+        if tag == Tag::Final {
+            return match read_chunk(input, &mut chunk)? {
+                0 => Ok(()),
+                _ => Err(Error::FailedToDecrypt),
+            };
+        }
+    }
+}
+
+/// Encrypts the file at `input` into `output` in chunks, so large files never sit in memory
+pub fn encrypt_file(_: LuaContext, this: &LuaKey, (input, output): (String, String)) -> Result<(), LuaError> {
+    let mut input = File::open(&input).map_err(LuaError::external)?;
+    let mut output = File::create(&output).map_err(LuaError::external)?;
+    encrypt(&this.0, &mut input, &mut output).map_err(LuaError::external)
+}
+
+/// Decrypts `input` into a temporary file next to `output`, which only replaces
+/// `output` once the whole stream is authenticated
+fn decrypt_to_path(key: &Key, input: &Path, output: &Path) -> Result<(), Error> {
+    let mut input = File::open(input)?;
+    let name = output.file_name().and_then(|name| name.to_str())
+        .ok_or_else(|| format_err!("invalid output path {:?}", output))?;
+    let tmp = output.with_file_name(format!(".{}.tmp", name));
+
+    let result = File::create(&tmp).map_err(Error::from).and_then(|mut file| {
+        decrypt(key, &mut input, &mut file)?;
+        file.sync_all()?;
+        Ok(())
+    });
+    match result.and_then(|_| fs::rename(&tmp, output).map_err(Error::from)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            Err(err)
+        },
+    }
+}
+
+/// Decrypts a file written by `encrypt_file`, failing if it was altered or truncated
+/// and leaving `output` untouched in that case
+pub fn decrypt_file(_: LuaContext, this: &LuaKey, (input, output): (String, String)) -> Result<(), LuaError> {
+    decrypt_to_path(&this.0, Path::new(&input), Path::new(&output)).map_err(LuaError::external)
+}
+
+/// Returns a new encryptor; its header must be stored ahead of the encrypted messages
+pub fn encryptor(_: LuaContext, this: &LuaKey, _: ()) -> Result<LuaEncryptor, LuaError> {
+    let (stream, header) = Stream::init_push(&this.0).map_err(|_| LuaError::external(Error::InvalidKeys))?;
+    Ok(LuaEncryptor { stream: Some(stream), header })
+}
+
+/// Returns a decryptor for the stream started by the base64 encoded `header`
+pub fn decryptor(_: LuaContext, this: &LuaKey, header: String) -> Result<LuaDecryptor, LuaError> {
+    let header = base64::decode(&header).map_err(LuaError::external)?;
+    let header = Header::from_slice(&header).ok_or(LuaError::external(Error::InvalidNonce))?;
+    Stream::init_pull(&header, &this.0)
+        .map(LuaDecryptor)
+        .map_err(|_| LuaError::external(Error::FailedToDecrypt))
+}
+
+fn push<'lua>(lua: LuaContext<'lua>, this: &mut LuaEncryptor, msg: &[u8], tag: Tag) -> Result<LuaString<'lua>, LuaError> {
+    let stream = this.stream.as_mut().ok_or(LuaError::external(format_err!("stream is already finished")))?;
+    let cipher = stream.push(msg, None, tag).map_err(|_| LuaError::external(format_err!("failed to encrypt stream")))?;
+    if tag == Tag::Final {
+        this.stream = None;
+    }
+    lua.create_string(&cipher)
+}
+
+impl UserData for LuaKey {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("encryptor", encryptor);
+        methods.add_method("decryptor", decryptor);
+        methods.add_method("encrypt_file", encrypt_file);
+        methods.add_method("decrypt_file", decrypt_file);
+        methods.add_meta_method(MetaMethod::ToString, |_, this, _: ()| {
+            Ok(base64::encode(&(this.0).0))
+        });
+    }
+}
+
+impl UserData for LuaEncryptor {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("header", |_, this, _: ()| {
+            Ok(base64::encode(&this.header.0))
+        });
+        methods.add_method_mut("push", |lua, this, msg: LuaString| {
+            push(lua, this, msg.as_bytes(), Tag::Message)
+        });
+        methods.add_method_mut("finish", |lua, this, msg: Option<LuaString>| {
+            push(lua, this, msg.as_ref().map_or(&[][..], LuaString::as_bytes), Tag::Final)
+        });
+    }
+}
+
+impl UserData for LuaDecryptor {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("pull", |lua, this, cipher: LuaString| {
+            let (msg, tag) = this.0.pull(cipher.as_bytes(), None)
+                .map_err(|_| LuaError::external(Error::FailedToDecrypt))?;
+            Ok((lua.create_string(&msg)?, tag == Tag::Final))
+        });
+    }
+}
+
+/// Returns a new random key
+pub fn new_key(_: LuaContext, _: ()) -> LuaResult<LuaKey> {
+    Ok(LuaKey(secretstream::gen_key()))
+}
+
+/// Constructs and returns a LuaKey object from it's base64 string encoding
+pub fn load_key(_: LuaContext, base64_key: String) -> Result<LuaKey, LuaError> {
+    base64::decode(&base64_key).map_err(Error::from)
+        .and_then(|vec| Key::from_slice(&vec).ok_or(Error::InvalidKeys).map(LuaKey))
+        .map_err(LuaError::external)
+}
@@ -3,8 +3,8 @@ use sodiumoxide::crypto::sign;
 use base64;
 use crate::error::Error;
 
-pub struct LuaSecretKey (sign::SecretKey);
-pub struct LuaPublicKey (sign::PublicKey);
+pub struct LuaSecretKey (pub(crate) sign::SecretKey);
+pub struct LuaPublicKey (pub(crate) sign::PublicKey);
 
 /// Returns `msg` signed and base64 encoded
 pub fn sign(_: LuaContext, this: &LuaSecretKey, msg: String) -> Result<String, LuaError> {
@@ -9,7 +9,7 @@ pub mod memory;
 
 use rlua::prelude::*;
 use std::{
-    io::{SeekFrom, prelude::*},
+    io::{Cursor, SeekFrom, prelude::*},
     fs::{File, Metadata, Permissions},
     sync::{Mutex, Arc},
 };
@@ -35,6 +35,20 @@ pub struct LuaCommonIO {
 unsafe impl Send for LuaCommonIO {}
 unsafe impl Sync for LuaCommonIO {}
 
+impl LuaCommonIO {
+    /// Read-only, seekable handle over an in-memory buffer
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let cursor = Arc::new(Mutex::new(Cursor::new(bytes)));
+        LuaCommonIO {
+            inner: None,
+            stdin: None,
+            stdout: Some(cursor.clone()),
+            stderr: None,
+            seek: Some(cursor),
+        }
+    }
+}
+
 pub struct LuaMetadata(Metadata);
 pub struct LuaPermissions(Permissions);
 
@@ -0,0 +1,292 @@
+use std::{
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use actix_web::{
+    error, http::header::{self, HeaderValue},
+    middleware::{Finished, Middleware, Response, Started},
+    HttpRequest, HttpResponse,
+};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::{logger, AppState};
+
+/// Per request data set when the request starts, shared with the handler and the access log
+#[derive(Clone)]
+pub struct RequestInfo {
+    pub id: String,
+    pub start: Instant,
+}
+
+/// Returns the id of `req`, as set by the `RequestId` middleware
+pub fn request_id (req: &HttpRequest<AppState>) -> Option<String> {
+    req.extensions().get::<RequestInfo>().map(|info| info.id.clone())
+}
+
+/// Keeps the id sent by the client if it is short and made of `[A-Za-z0-9._-]`,
+/// so it can't break the log lines it ends up in, and makes up a new one otherwise
+fn choose_request_id (sent: Option<&str>) -> String {
+    sent
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-'))
+        .map(String::from)
+        .unwrap_or_else(|| Ulid::new().to_string())
+}
+
+/// Middleware giving every request an id, taken from the `X-Request-Id`
+/// header when the client sent one, and echoing it back in the response
+pub struct RequestId;
+
+impl Middleware<AppState> for RequestId {
+    fn start (&self, req: &HttpRequest<AppState>) -> error::Result<Started> {
+        let id = choose_request_id(req.headers().get("x-request-id").and_then(|id| id.to_str().ok()));
+
+        req.extensions_mut().insert(RequestInfo { id, start: Instant::now() });
+        Ok(Started::Done)
+    }
+
+    fn response (&self, req: &HttpRequest<AppState>, mut resp: HttpResponse) -> error::Result<Response> {
+        if let Some(id) = request_id(req) {
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                resp.headers_mut().insert("x-request-id", value);
+            }
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Common,
+    Combined,
+    Json,
+}
+
+impl Default for Format {
+    fn default () -> Self { Format::Combined }
+}
+
+/// The `access_log` section of the `web-server` settings
+#[derive(Debug, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    format: Format,
+    path: Option<String>,
+}
+
+fn default_enabled () -> bool { true }
+
+/// Reads the `access_log` settings, which can be a boolean or a table
+///
+/// The log goes to `path`, or to the general `log_path`, or to a `log` directory.
+pub fn access_log_from_settings (web: &Value, log_path: Option<&str>, rotation: logger::Rotation) -> crate::Result<Option<AccessLog>> {
+    let config = match web.get("access_log") {
+        None | Some(Value::Bool(false)) => return Ok(None),
+        Some(Value::Bool(true)) => AccessLogConfig { enabled: true, format: Format::default(), path: None },
+        Some(value) => serde_json::from_value(value.clone())?,
+    };
+
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let path = config.path.as_ref().map(String::as_str).or(log_path).unwrap_or("log");
+    let file = logger::RotatingFile::new(Path::new(path), "access_", rotation)?;
+
+    Ok(Some(AccessLog {
+        format: config.format,
+        out: Arc::new(Mutex::new(Box::new(LineWriter::new(file)))),
+    }))
+}
+
+/// Middleware writing a line per request to the access log
+#[derive(Clone)]
+pub struct AccessLog {
+    format: Format,
+    out: Arc<Mutex<Box<Write + Send>>>,
+}
+
+fn header_or_dash<'a> (req: &'a HttpRequest<AppState>, name: header::HeaderName) -> &'a str {
+    req.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or("-")
+}
+
+/// Escapes a client supplied value for the text formats like Apache and nginx do,
+/// `"` and `\` with a backslash and control characters as `\xHH`
+fn escape (value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl AccessLog {
+    fn line (&self, req: &HttpRequest<AppState>, resp: &HttpResponse) -> String {
+        self.line_at(req, resp, ::chrono::Local::now())
+    }
+
+    fn line_at (&self, req: &HttpRequest<AppState>, resp: &HttpResponse, now: ::chrono::DateTime<::chrono::Local>) -> String {
+        let info = req.extensions().get::<RequestInfo>().cloned();
+        let duration = info.as_ref()
+            .map(|info| {
+                let elapsed = info.start.elapsed();
+                elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+            })
+            .unwrap_or(0.0);
+        let id = info.map(|info| info.id).unwrap_or_else(|| String::from("-"));
+
+        let remote = req.connection_info().remote().unwrap_or("-").to_string();
+        let path = if req.query_string().is_empty() {
+            req.path().to_string()
+        } else {
+            format!("{}?{}", req.path(), req.query_string())
+        };
+        let status = resp.status().as_u16();
+        let bytes = resp.response_size();
+
+        match self.format {
+            Format::Json => serde_json::json!({
+                "time": now.to_rfc3339(),
+                "remote": remote,
+                "method": req.method().as_str(),
+                "path": path,
+                "version": format!("{:?}", req.version()),
+                "status": status,
+                "bytes": bytes,
+                "duration": duration,
+                "referer": header_or_dash(req, header::REFERER),
+                "user_agent": header_or_dash(req, header::USER_AGENT),
+                "request_id": id,
+            }).to_string(),
+            format => {
+                let bytes = if bytes == 0 { String::from("-") } else { bytes.to_string() };
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {}",
+                    remote,
+                    now.format("%d/%b/%Y:%H:%M:%S %z"),
+                    req.method(),
+                    escape(&path),
+                    req.version(),
+                    status,
+                    bytes
+                );
+                if format == Format::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        escape(header_or_dash(req, header::REFERER)),
+                        escape(header_or_dash(req, header::USER_AGENT))
+                    ));
+                }
+                // Not part of the standard formats, so they go last where parsers ignore them
+                line.push_str(&format!(" {:.6} {}", duration, id));
+                line
+            }
+        }
+    }
+}
+
+impl Middleware<AppState> for AccessLog {
+    fn finish (&self, req: &HttpRequest<AppState>, resp: &HttpResponse) -> Finished {
+        let line = self.line(req, resp);
+        let mut out = self.out.lock().unwrap();
+        if let Err(err) = writeln!(out, "{}", line) {
+            error!("could not write to the access log: {}", err);
+        }
+        Finished::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use actix_web::test::TestRequest;
+    use chrono::TimeZone;
+
+    fn log (format: Format) -> AccessLog {
+        AccessLog { format, out: Arc::new(Mutex::new(Box::new(Vec::new()))) }
+    }
+
+    fn request () -> HttpRequest<AppState> {
+        let state = AppState {
+            lua: None,
+            init_path: PathBuf::new(),
+            init_args: vec![],
+            package_path: None,
+            settings: Value::Null,
+            app_settings: None,
+            sites: Arc::new(vec![]),
+        };
+        let req = TestRequest::with_state(state)
+            .uri("/search?q=a")
+            .header("referer", "http://example.com/\" \"forged")
+            .header("user-agent", "agent\\\" 200 0")
+            .finish();
+        req.extensions_mut().insert(RequestInfo { id: "abc-1".to_owned(), start: Instant::now() });
+        req
+    }
+
+    fn line (format: Format) -> String {
+        let now = ::chrono::Local.ymd(2019, 6, 1).and_hms(12, 30, 0);
+        log(format).line_at(&request(), &HttpResponse::NotFound().finish(), now)
+    }
+
+    #[test]
+    fn request_ids () {
+        assert_eq!(choose_request_id(Some("abc-123_X.4")), "abc-123_X.4");
+        for sent in &[None, Some(""), Some("two words"), Some("a\"b"), Some("a\\b"), Some("é")] {
+            let id = choose_request_id(*sent);
+            assert_eq!(id.len(), 26);
+            assert!(id.chars().all(|c| c.is_ascii_alphanumeric()));
+        }
+        assert_eq!(choose_request_id(Some(&"a".repeat(128))), "a".repeat(128));
+        assert_eq!(choose_request_id(Some(&"a".repeat(129))).len(), 26);
+    }
+
+    #[test]
+    fn escaping () {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(escape("line\nbreak"), "line\\x0Abreak");
+    }
+
+    #[test]
+    fn common_lines () {
+        let line = line(Format::Common);
+        assert!(line.contains(" - - [01/Jun/2019:12:30:00 "), "{}", line);
+        assert!(line.contains("] \"GET /search?q=a HTTP/1.1\" 404 - "), "{}", line);
+        assert!(line.ends_with(" abc-1"), "{}", line);
+        assert!(!line.contains("forged"));
+    }
+
+    #[test]
+    fn combined_lines () {
+        let line = line(Format::Combined);
+        assert!(line.contains(" 404 - \"http://example.com/\\\" \\\"forged\" \"agent\\\\\\\" 200 0\" "), "{}", line);
+        assert!(line.ends_with(" abc-1"), "{}", line);
+    }
+
+    #[test]
+    fn json_lines () {
+        let line: Value = serde_json::from_str(&line(Format::Json)).unwrap();
+        assert_eq!(line["method"], "GET");
+        assert_eq!(line["path"], "/search?q=a");
+        assert_eq!(line["status"], 404);
+        assert_eq!(line["referer"], "http://example.com/\" \"forged");
+        assert_eq!(line["user_agent"], "agent\\\" 200 0");
+        assert_eq!(line["request_id"], "abc-1");
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use actix_lua::LuaMessage;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse};
+use futures::{future, Future};
+use serde_json::{json, Value};
+
+use crate::{hook_error, metrics::{self, REGISTRY}, AppState};
+use super::server;
+
+/// Set once `init.lua` and the bootstrap scripts have run and the server is listening
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_ready (ready: bool) {
+    READY.store(ready, Ordering::SeqCst);
+}
+
+pub fn is_ready () -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+/// The `health` section of the `web-server` settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthConfig {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default = "default_liveness")]
+    pub liveness: String,
+    #[serde(default = "default_readiness")]
+    pub readiness: String,
+    /// Whether readiness calls the `torchbear.ready` Lua hook
+    #[serde(default = "default_enabled")]
+    pub hook: bool,
+    /// Seconds to wait for the hook before reporting the app as not ready
+    #[serde(default = "default_timeout")]
+    pub hook_timeout: u64,
+}
+
+fn default_enabled () -> bool { true }
+
+fn default_liveness () -> String { String::from("/healthz") }
+
+fn default_readiness () -> String { String::from("/readyz") }
+
+fn default_timeout () -> u64 { 5 }
+
+/// Reads the `health` settings, which can be a boolean or a table
+pub fn config_from_settings (web: &Value) -> crate::Result<Option<HealthConfig>> {
+    let config: HealthConfig = match web.get("health") {
+        None | Some(Value::Bool(false)) => return Ok(None),
+        Some(Value::Bool(true)) => serde_json::from_value(json!({}))?,
+        Some(value) => serde_json::from_value(value.clone())?,
+    };
+
+    Ok(if config.enabled { Some(config) } else { None })
+}
+
+/// The state of the Lua VMs, reported along with readiness
+fn pool_status (state: &AppState) -> Value {
+    json!({
+        "single_actor": state.lua.is_some(),
+        "sites": state.sites.len(),
+        "vms": REGISTRY.get(metrics::LUA_VMS, &[]).unwrap_or(0.0),
+        "in_flight": REGISTRY.get(metrics::HTTP_IN_FLIGHT, &[]).unwrap_or(0.0),
+    })
+}
+
+fn status (ready: bool, reason: Option<String>, state: &AppState) -> HttpResponse {
+    let body = json!({
+        "status": if ready { "ready" } else { "not ready" },
+        "reason": reason,
+        "pool": pool_status(state),
+    });
+
+    let mut response = if ready { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    response.json(body)
+}
+
+/// Reads what `torchbear.ready` returned: nothing (the hook is not defined) or
+/// `true` means ready, `false` or a string with the reason means not ready,
+/// as does a table with `ready = false` and an optional `reason`. A hook that
+/// raised an error means not ready.
+fn hook_result (message: LuaMessage) -> (bool, Option<String>) {
+    if let Some(trace) = hook_error(&message) {
+        return (false, Some(format!("ready hook failed: {}", trace)));
+    }
+    match message {
+        LuaMessage::Boolean(ready) => (ready, None),
+        LuaMessage::String(reason) => (false, Some(reason)),
+        LuaMessage::Table(mut table) => {
+            let ready = match table.remove("ready") {
+                Some(LuaMessage::Boolean(ready)) => ready,
+                _ => true,
+            };
+            let reason = match table.remove("reason") {
+                Some(LuaMessage::String(reason)) => Some(reason),
+                _ => None,
+            };
+            (ready, reason)
+        },
+        _ => (true, None),
+    }
+}
+
+/// Answers as long as the process is able to handle requests at all
+pub fn liveness (_: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "alive" }))
+}
+
+/// Handler for the readiness endpoint of `config`
+pub fn readiness (config: &HealthConfig) -> impl Fn(&HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let hook = config.hook;
+    let timeout = Duration::from_secs(config.hook_timeout);
+
+    move |req: &HttpRequest<AppState>| {
+        // The app of the site the Host header selects, as for any other request
+        let state = server::site_state(req).clone();
+
+        if !is_ready() {
+            return Box::new(future::ok(status(false, Some(String::from("starting")), &state)));
+        }
+
+        if !hook {
+            return Box::new(future::ok(status(true, None, &state)));
+        }
+
+        Box::new(state.call_hook("torchbear.ready", LuaMessage::Nil)
+            .timeout(timeout)
+            .then(move |res| {
+                let (ready, reason) = match res {
+                    Ok(message) => hook_result(message),
+                    Err(err) => (false, Some(format!("ready hook failed: {}", err))),
+                };
+                Ok::<_, actix_web::Error>(status(ready, reason, &state))
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn ready_hook_results () {
+        assert_eq!(hook_result(LuaMessage::Nil), (true, None));
+        assert_eq!(hook_result(LuaMessage::Boolean(false)), (false, None));
+        assert_eq!(hook_result(LuaMessage::String("db down".to_owned())), (false, Some("db down".to_owned())));
+
+        let mut table = HashMap::new();
+        table.insert("ready".to_owned(), LuaMessage::Boolean(false));
+        table.insert("reason".to_owned(), LuaMessage::String("migrating".to_owned()));
+        assert_eq!(hook_result(LuaMessage::Table(table)), (false, Some("migrating".to_owned())));
+
+        let mut table = HashMap::new();
+        table.insert("hook_error".to_owned(), LuaMessage::String("ready.lua:3: db down".to_owned()));
+        assert_eq!(hook_result(LuaMessage::Table(table)), (false, Some("ready hook failed: ready.lua:3: db down".to_owned())));
+    }
+}
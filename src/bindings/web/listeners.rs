@@ -0,0 +1,139 @@
+use std::{
+    env, fs, io,
+    net::TcpListener,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{FromRawFd, RawFd},
+        net::UnixListener as StdUnixListener,
+    },
+    path::Path,
+};
+use tokio_reactor::Handle;
+use tokio_uds::UnixListener;
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Parses a mode such as "660" or "0o660" as octal
+pub fn parse_mode (mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8).ok()
+}
+
+/// Binds a Unix domain socket at `path`, replacing a stale socket file left
+/// by a previous run, and applies `mode` to it
+pub fn bind_unix (path: &str, mode: Option<u32>) -> io::Result<UnixListener> {
+    let path = Path::new(path);
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        } else {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} exists and is not a socket", path)));
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+/// Takes the sockets passed by systemd through `LISTEN_PID` and `LISTEN_FDS`
+///
+/// The variables are removed afterwards so child processes don't inherit them.
+pub fn activated_listeners () -> io::Result<Vec<ActivatedListener>> {
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_ref().map(String::as_str),
+        env::var("LISTEN_FDS").ok().as_ref().map(String::as_str),
+        std::process::id(),
+    );
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).map(|fd| {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        if is_unix_socket(fd)? {
+            let listener = unsafe { StdUnixListener::from_raw_fd(fd) };
+            UnixListener::from_std(listener, &Handle::default()).map(ActivatedListener::Unix)
+        } else {
+            Ok(ActivatedListener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+        }
+    }).collect()
+}
+
+/// The number of sockets passed to the process `own_pid`, none when
+/// the variables are missing, invalid or meant for another process
+fn listen_fds (pid: Option<&str>, count: Option<&str>, own_pid: u32) -> RawFd {
+    let pid = pid.and_then(|pid| pid.parse::<u32>().ok());
+    let count = count.and_then(|count| count.parse::<RawFd>().ok());
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid == own_pid && count > 0 => count,
+        _ => 0,
+    }
+}
+
+fn is_unix_socket (fd: RawFd) -> io::Result<bool> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let res = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(i32::from(addr.ss_family) == libc::AF_UNIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_mode () {
+        assert_eq!(parse_mode("660"), Some(0o660));
+        assert_eq!(parse_mode("0o600"), Some(0o600));
+        assert_eq!(parse_mode("0777"), Some(0o777));
+        assert_eq!(parse_mode("rw"), None);
+    }
+
+    #[test]
+    fn systemd_variables () {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("0"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("-1"), 42), 0);
+        assert_eq!(listen_fds(Some("pid"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+    }
+
+    #[test]
+    fn unix_sockets () {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("web.sock");
+
+        // A socket left by a previous run is replaced
+        drop(StdUnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let _listener = bind_unix(path.to_str().unwrap(), Some(0o600)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Anything else is left alone
+        let file = dir.path().join("data.txt");
+        fs::write(&file, "keep").unwrap();
+        let err = bind_unix(file.to_str().unwrap(), None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use actix_web::{
+    error,
+    middleware::{Finished, Middleware, Started},
+    HttpRequest, HttpResponse,
+};
+use serde_json::Value;
+
+use crate::{metrics::{self, REGISTRY}, AppState};
+use super::access_log::RequestInfo;
+
+/// The `metrics` section of the `web-server` settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Path prefixes requests are grouped by, the rest are counted as `other`
+    #[serde(default)]
+    routes: Vec<String>,
+}
+
+fn default_enabled () -> bool { true }
+
+fn default_path () -> String { String::from("/metrics") }
+
+/// Reads the `metrics` settings, which can be a boolean or a table
+///
+/// `routes` are added to the configured routes, such as the proxy paths.
+pub fn config_from_settings (web: &Value, routes: &[String]) -> crate::Result<Option<MetricsConfig>> {
+    let mut config: MetricsConfig = match web.get("metrics") {
+        None | Some(Value::Bool(false)) => return Ok(None),
+        Some(Value::Bool(true)) => MetricsConfig { enabled: true, path: default_path(), routes: vec![] },
+        Some(value) => serde_json::from_value(value.clone())?,
+    };
+
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    config.routes.extend(routes.iter().cloned());
+    config.routes.push(config.path.clone());
+    Ok(Some(config))
+}
+
+/// Middleware counting requests and timing them by route and status
+#[derive(Clone)]
+pub struct Metrics {
+    routes: Arc<Vec<String>>,
+}
+
+impl Metrics {
+    pub fn new (config: &MetricsConfig) -> Self {
+        Metrics { routes: Arc::new(config.routes.clone()) }
+    }
+
+    /// The longest configured prefix of `path`, keeping the number of label values bounded
+    fn route (&self, path: &str) -> String {
+        self.routes.iter()
+            .filter(|route| matches_route(route, path))
+            .max_by_key(|route| route.len())
+            .cloned()
+            .unwrap_or_else(|| String::from("other"))
+    }
+}
+
+/// Whether `path` is `route` or one of the paths below it, so `/api` doesn't take `/apiary`
+fn matches_route (route: &str, path: &str) -> bool {
+    path.starts_with(route) && (path.len() == route.len() || route.ends_with('/') || path[route.len()..].starts_with('/'))
+}
+
+impl Middleware<AppState> for Metrics {
+    fn start (&self, _: &HttpRequest<AppState>) -> error::Result<Started> {
+        let _ = REGISTRY.add(metrics::HTTP_IN_FLIGHT, &[], 1.0);
+        Ok(Started::Done)
+    }
+
+    fn finish (&self, req: &HttpRequest<AppState>, resp: &HttpResponse) -> Finished {
+        let _ = REGISTRY.add(metrics::HTTP_IN_FLIGHT, &[], -1.0);
+
+        let route = self.route(req.path());
+        let status = resp.status().as_u16().to_string();
+        let _ = REGISTRY.inc(metrics::HTTP_REQUESTS, &[req.method().to_string(), route.clone(), status.clone()], 1.0);
+
+        if let Some(info) = req.extensions().get::<RequestInfo>() {
+            let elapsed = info.start.elapsed();
+            let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            let _ = REGISTRY.observe(metrics::HTTP_DURATION, &[route, status], seconds);
+        }
+
+        Finished::Done
+    }
+}
+
+/// Serves every metric in the Prometheus text format
+pub fn handler (_: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(REGISTRY.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_boundaries () {
+        assert!(matches_route("/api", "/api"));
+        assert!(matches_route("/api", "/api/users"));
+        assert!(!matches_route("/api", "/apiary"));
+        assert!(matches_route("/api/", "/api/users"));
+        assert!(matches_route("/", "/anything"));
+    }
+}
@@ -1,4 +1,11 @@
+pub mod access_log;
 pub mod client;
+pub mod health;
+#[cfg(target_family = "unix")]
+pub mod listeners;
+pub mod metrics;
+pub mod proxy;
+pub mod ratelimit;
 pub mod server;
 
 use rlua::prelude::*;
@@ -6,6 +13,7 @@ use crate::Result;
 
 pub fn init(lua: &Lua) -> Result<()> {
     client::init(lua)?;
+    ratelimit::init(lua)?;
 
     Ok(())
 }
\ No newline at end of file
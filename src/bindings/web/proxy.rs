@@ -0,0 +1,547 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    time::Duration,
+};
+use actix::prelude::*;
+use actix_lua::LuaMessage;
+use actix_web::{
+    dev::Handler, error, http::{HeaderMap, Method, StatusCode},
+    client::{ClientRequest, ClientResponse},
+    AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::{future, Future};
+use serde_json::Value;
+
+use crate::{hook_error, AppState};
+use super::server;
+
+/// Headers that only make sense for a single connection and must not be forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_path")]
+    path: String,
+    #[serde(default = "default_health_interval")]
+    interval: u64,
+    #[serde(default = "default_health_timeout")]
+    timeout: u64,
+}
+
+fn default_health_path () -> String { String::from("/") }
+fn default_health_interval () -> u64 { 10 }
+fn default_health_timeout () -> u64 { 5 }
+fn default_timeout () -> u64 { 30 }
+
+/// A `proxy` entry in the `web-server` section of torchbear.scl
+#[derive(Debug, Deserialize)]
+pub struct ProxyConfig {
+    path: String,
+    upstreams: Vec<String>,
+    #[serde(default)]
+    strip_prefix: bool,
+    #[serde(default)]
+    preserve_host: bool,
+    #[serde(default)]
+    set_headers: HashMap<String, String>,
+    #[serde(default)]
+    remove_headers: Vec<String>,
+    request_hook: Option<String>,
+    response_hook: Option<String>,
+    health_check: Option<HealthCheckConfig>,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+pub struct Upstream {
+    url: String,
+    healthy: AtomicBool,
+}
+
+pub struct ProxyRoute {
+    pub path: String,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    strip_prefix: bool,
+    preserve_host: bool,
+    set_headers: Vec<(String, String)>,
+    remove_headers: Vec<String>,
+    request_hook: Option<String>,
+    response_hook: Option<String>,
+    health_check: Option<HealthCheckConfig>,
+    timeout: Duration,
+}
+
+impl ProxyRoute {
+    pub fn new (config: ProxyConfig) -> crate::Result<Self> {
+        if config.upstreams.is_empty() {
+            return Err(format_err!("proxy route {} has no upstreams", config.path));
+        }
+
+        Ok(ProxyRoute {
+            path: config.path,
+            upstreams: config.upstreams.into_iter().map(|url| Upstream {
+                url: url.trim_end_matches('/').to_string(),
+                healthy: AtomicBool::new(true),
+            }).collect(),
+            next: AtomicUsize::new(0),
+            strip_prefix: config.strip_prefix,
+            preserve_host: config.preserve_host,
+            set_headers: config.set_headers.into_iter().collect(),
+            remove_headers: config.remove_headers.into_iter().map(|h| h.to_lowercase()).collect(),
+            request_hook: config.request_hook,
+            response_hook: config.response_hook,
+            health_check: config.health_check,
+            timeout: Duration::from_secs(config.timeout),
+        })
+    }
+
+    pub fn has_health_check (&self) -> bool {
+        self.health_check.is_some()
+    }
+
+    /// Picks the next healthy upstream in round-robin order
+    fn select_upstream (&self) -> Option<&str> {
+        let len = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|i| &self.upstreams[(start + i) % len])
+            .find(|upstream| upstream.healthy.load(Ordering::Relaxed))
+            .map(|upstream| upstream.url.as_str())
+    }
+
+    fn upstream_path (&self, path: &str) -> String {
+        if self.strip_prefix {
+            let stripped = &path[self.path.trim_end_matches('/').len()..];
+            if stripped.starts_with('/') { stripped.to_string() } else { format!("/{}", stripped) }
+        } else {
+            path.to_string()
+        }
+    }
+}
+
+/// Reads the `proxy` routes from the `web-server` settings
+pub fn routes_from_settings (web: &Value) -> crate::Result<Vec<Arc<ProxyRoute>>> {
+    match web.get("proxy") {
+        Some(value) => {
+            let configs: Vec<ProxyConfig> = serde_json::from_value(value.clone())?;
+            configs.into_iter()
+                .map(|config| ProxyRoute::new(config).map(Arc::new))
+                .collect()
+        },
+        None => Ok(vec![]),
+    }
+}
+
+/// The request as it will be sent upstream, exposed to the Lua request hook
+struct ForwardedRequest {
+    method: Method,
+    upstream: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl ForwardedRequest {
+    fn from_request (req: &HttpRequest<AppState>, upstream: &str, path: String, preserve_host: bool) -> Self {
+        let mut headers: Vec<(String, String)> = req.headers().iter()
+            .filter(|(key, _)| !HOP_BY_HOP.contains(&key.as_str()))
+            .filter(|(key, _)| preserve_host || key.as_str() != "host")
+            .filter_map(|(key, value)| value.to_str().ok().map(|v| (key.as_str().to_string(), v.to_string())))
+            .collect();
+
+        let info = req.connection_info();
+        let forwarded_for = match (req.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok()), req.peer_addr()) {
+            (Some(prev), Some(peer)) => Some(format!("{}, {}", prev, peer.ip())),
+            (Some(prev), None) => Some(prev.to_string()),
+            (None, Some(peer)) => Some(peer.ip().to_string()),
+            (None, None) => None,
+        };
+        headers.retain(|(key, _)| !key.starts_with("x-forwarded-"));
+        if let Some(forwarded_for) = forwarded_for {
+            headers.push(("x-forwarded-for".to_string(), forwarded_for));
+        }
+        headers.push(("x-forwarded-proto".to_string(), info.scheme().to_string()));
+        headers.push(("x-forwarded-host".to_string(), info.host().to_string()));
+
+        ForwardedRequest {
+            method: req.method().clone(),
+            upstream: upstream.to_string(),
+            path,
+            query: req.query_string().to_string(),
+            headers,
+        }
+    }
+
+    fn uri (&self) -> String {
+        if self.query.is_empty() {
+            format!("{}{}", self.upstream, self.path)
+        } else {
+            format!("{}{}?{}", self.upstream, self.path, self.query)
+        }
+    }
+
+    fn to_message (&self) -> LuaMessage {
+        let mut table = HashMap::new();
+        table.insert("method".to_owned(), LuaMessage::String(self.method.to_string()));
+        table.insert("upstream".to_owned(), LuaMessage::String(self.upstream.clone()));
+        table.insert("path".to_owned(), LuaMessage::String(self.path.clone()));
+        table.insert("query".to_owned(), LuaMessage::String(self.query.clone()));
+        table.insert("headers".to_owned(), headers_to_message(&self.headers));
+        LuaMessage::Table(table)
+    }
+
+    /// Applies the fields returned by the Lua request hook
+    fn update (&mut self, msg: LuaMessage) {
+        if let LuaMessage::Table(table) = msg {
+            if let Some(LuaMessage::String(method)) = table.get("method") {
+                if let Ok(method) = Method::from_bytes(method.to_uppercase().as_bytes()) {
+                    self.method = method;
+                }
+            }
+            if let Some(LuaMessage::String(upstream)) = table.get("upstream") {
+                self.upstream = upstream.trim_end_matches('/').to_string();
+            }
+            if let Some(LuaMessage::String(path)) = table.get("path") {
+                self.path = path.clone();
+            }
+            if let Some(LuaMessage::String(query)) = table.get("query") {
+                self.query = query.clone();
+            }
+            if let Some(headers) = table.get("headers") {
+                self.headers = headers_from_message(headers);
+            }
+        }
+    }
+}
+
+fn headers_to_message (headers: &[(String, String)]) -> LuaMessage {
+    LuaMessage::Table(headers.iter()
+        .map(|(key, value)| (key.clone(), LuaMessage::String(value.clone())))
+        .collect())
+}
+
+fn headers_from_message (msg: &LuaMessage) -> Vec<(String, String)> {
+    match msg {
+        LuaMessage::Table(table) => table.iter().filter_map(|(key, value)| match value {
+            LuaMessage::String(value) => Some((key.clone(), value.clone())),
+            LuaMessage::Integer(number) => Some((key.clone(), number.to_string())),
+            LuaMessage::Number(number) => Some((key.clone(), number.to_string())),
+            _ => None,
+        }).collect(),
+        _ => vec![],
+    }
+}
+
+/// Calls a Lua hook on the app's actor, yielding `None` if it returned nothing
+fn call_hook (state: &AppState, hook: Option<&String>, args: LuaMessage) -> Box<Future<Item=Option<LuaMessage>, Error=error::Error>> {
+    match hook {
+        Some(hook) => Box::new(state.call_hook(hook, args)
+            .from_err()
+            .map(|res| match res {
+                // The error is already logged, the request goes on unchanged
+                LuaMessage::Nil => None,
+                ref res if hook_error(res).is_some() => None,
+                res => Some(res),
+            })),
+        None => Box::new(future::ok(None)),
+    }
+}
+
+/// Builds the downstream response out of the upstream one, streaming the body
+fn stream_response (res: ClientResponse, status: StatusCode, headers: Vec<(String, String)>) -> HttpResponse {
+    let mut response = HttpResponse::build(status);
+    for (key, value) in headers.iter() {
+        response.header(key.as_str(), value.as_str());
+    }
+    response.streaming(res.payload())
+}
+
+fn response_headers (headers: &HeaderMap, remove: &[String]) -> Vec<(String, String)> {
+    headers.iter()
+        .filter(|(key, _)| !HOP_BY_HOP.contains(&key.as_str()))
+        .filter(|(key, _)| !remove.iter().any(|r| r == key.as_str()))
+        .filter_map(|(key, value)| value.to_str().ok().map(|v| (key.as_str().to_string(), v.to_string())))
+        .collect()
+}
+
+/// Forwards requests matching a configured route to its upstreams
+pub struct ProxyHandler(Arc<ProxyRoute>);
+
+impl ProxyHandler {
+    pub fn new (route: Arc<ProxyRoute>) -> Self {
+        ProxyHandler(route)
+    }
+}
+
+impl Handler<AppState> for ProxyHandler {
+    type Result = FutureResponse<HttpResponse>;
+
+    fn handle (&self, req: &HttpRequest<AppState>) -> Self::Result {
+        let route = self.0.clone();
+
+        let upstream = match route.select_upstream() {
+            Some(upstream) => upstream.to_string(),
+            None => {
+                warn!("proxy route {} has no healthy upstream", route.path);
+                return Box::new(future::ok(HttpResponse::ServiceUnavailable().finish()));
+            }
+        };
+
+        let mut forwarded = ForwardedRequest::from_request(req, &upstream, route.upstream_path(req.path()), route.preserve_host);
+        forwarded.headers.retain(|(key, _)| !route.remove_headers.iter().any(|r| r == key));
+        for (key, value) in route.set_headers.iter() {
+            forwarded.headers.retain(|(k, _)| k != &key.to_lowercase());
+            forwarded.headers.push((key.to_lowercase(), value.clone()));
+        }
+
+        let req = req.clone();
+        let state = server::site_state(&req).clone();
+        let timeout = route.timeout;
+
+        call_hook(&state, route.request_hook.as_ref(), forwarded.to_message())
+            .and_then(move |changes| {
+                if let Some(changes) = changes {
+                    forwarded.update(changes);
+                }
+
+                let mut builder = ClientRequest::build();
+                builder
+                    .method(forwarded.method.clone())
+                    .uri(forwarded.uri())
+                    .no_default_headers()
+                    .disable_decompress()
+                    .timeout(timeout);
+                for (key, value) in forwarded.headers.iter() {
+                    builder.header(key.as_str(), value.as_str());
+                }
+
+                future::result(builder.streaming(req.payload()))
+                    .and_then(move |client_req| client_req.send().timeout(timeout).from_err())
+                    .and_then(move |res| {
+                        let status = res.status();
+                        let headers = response_headers(res.headers(), &route.remove_headers);
+
+                        if route.response_hook.is_none() {
+                            return future::Either::A(future::ok(stream_response(res, status, headers)));
+                        }
+
+                        let mut table = HashMap::new();
+                        table.insert("status".to_owned(), LuaMessage::Integer(status.as_u16() as i64));
+                        table.insert("headers".to_owned(), headers_to_message(&headers));
+
+                        future::Either::B(call_hook(&state, route.response_hook.as_ref(), LuaMessage::Table(table))
+                            .map(move |changes| {
+                                let (status, headers) = match changes {
+                                    Some(LuaMessage::Table(changes)) => {
+                                        let status = match changes.get("status") {
+                                            Some(value) => server::status_from_message(value).unwrap_or_else(|| {
+                                                error!("Invalid status from the response hook of {}: {:?}", route.path, value);
+                                                status
+                                            }),
+                                            None => status,
+                                        };
+                                        let headers = changes.get("headers").map(headers_from_message).unwrap_or(headers);
+                                        (status, headers)
+                                    },
+                                    _ => (status, headers),
+                                };
+                                stream_response(res, status, headers)
+                            }))
+                    })
+            })
+            .or_else(|err| {
+                error!("proxy request failed: {}", err);
+                Ok::<_, error::Error>(HttpResponse::BadGateway().finish())
+            })
+            .responder()
+    }
+}
+
+/// The timeout of the `proxy` route forwarding to `upstream`, the default one for other upstreams
+pub fn timeout_for (routes: &[Arc<ProxyRoute>], upstream: &str) -> Duration {
+    let upstream = upstream.trim_end_matches('/');
+    routes.iter()
+        .find(|route| route.upstreams.iter().any(|candidate| candidate.url == upstream))
+        .map(|route| route.timeout)
+        .unwrap_or_else(|| Duration::from_secs(default_timeout()))
+}
+
+/// Forwards a request whose Lua handler returned `{ proxy = "http://upstream" }`
+///
+/// The body has already been read by the handler, so it is sent as a whole.
+pub fn forward_buffered (req: &HttpRequest<AppState>, upstream: &str, body: String, timeout: Duration) -> FutureResponse<HttpResponse> {
+    let forwarded = ForwardedRequest::from_request(req, upstream.trim_end_matches('/'), req.path().to_string(), false);
+
+    let mut builder = ClientRequest::build();
+    builder
+        .method(forwarded.method.clone())
+        .uri(forwarded.uri())
+        .no_default_headers()
+        .disable_decompress()
+        .timeout(timeout);
+    for (key, value) in forwarded.headers.iter() {
+        builder.header(key.as_str(), value.as_str());
+    }
+
+    future::result(builder.body(body))
+        .and_then(move |client_req| client_req.send().timeout(timeout).from_err())
+        .map(|res| {
+            let status = res.status();
+            let headers = response_headers(res.headers(), &[]);
+            stream_response(res, status, headers)
+        })
+        .or_else(|err| {
+            error!("proxy request failed: {}", err);
+            Ok::<_, error::Error>(HttpResponse::BadGateway().finish())
+        })
+        .responder()
+}
+
+/// Periodically probes the upstreams of routes with a `health_check`
+pub struct HealthChecker {
+    routes: Vec<Arc<ProxyRoute>>,
+}
+
+impl HealthChecker {
+    pub fn new (routes: &[Arc<ProxyRoute>]) -> Self {
+        HealthChecker {
+            routes: routes.iter().filter(|route| route.health_check.is_some()).cloned().collect(),
+        }
+    }
+}
+
+impl Actor for HealthChecker {
+    type Context = Context<Self>;
+
+    fn started (&mut self, ctx: &mut Self::Context) {
+        for route in self.routes.iter() {
+            let route = route.clone();
+            let interval = route.health_check.as_ref().map(|check| check.interval).unwrap_or_else(default_health_interval);
+
+            ctx.run_interval(Duration::from_secs(interval), move |_, _| {
+                let check = route.health_check.as_ref().unwrap();
+                for index in 0..route.upstreams.len() {
+                    let route = route.clone();
+                    let uri = format!("{}{}", route.upstreams[index].url, check.path);
+                    let request = match ClientRequest::get(&uri).timeout(Duration::from_secs(check.timeout)).finish() {
+                        Ok(request) => request,
+                        Err(err) => {
+                            error!("invalid health check uri {}: {}", uri, err);
+                            continue;
+                        }
+                    };
+
+                    Arbiter::spawn(request.send().then(move |res| {
+                        let healthy = res.map(|res| res.status().is_success()).unwrap_or(false);
+                        let upstream = &route.upstreams[index];
+                        if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                            if healthy {
+                                info!("upstream {} is healthy again", upstream.url);
+                            } else {
+                                warn!("upstream {} failed its health check", upstream.url);
+                            }
+                        }
+                        Ok::<(), ()>(())
+                    }));
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn route (path: &str, upstreams: &[&str], strip_prefix: bool) -> ProxyRoute {
+        timed_route(path, upstreams, strip_prefix, default_timeout())
+    }
+
+    fn timed_route (path: &str, upstreams: &[&str], strip_prefix: bool, timeout: u64) -> ProxyRoute {
+        ProxyRoute::new(ProxyConfig {
+            path: path.to_string(),
+            upstreams: upstreams.iter().map(|url| url.to_string()).collect(),
+            strip_prefix,
+            preserve_host: false,
+            set_headers: HashMap::new(),
+            remove_headers: vec![],
+            request_hook: None,
+            response_hook: None,
+            health_check: None,
+            timeout,
+        }).unwrap()
+    }
+
+    #[test]
+    fn buffered_timeouts () {
+        let routes = vec![
+            Arc::new(timed_route("/slow", &["http://slow/"], false, 120)),
+            Arc::new(route("/api", &["http://api"], false)),
+        ];
+        assert_eq!(timeout_for(&routes, "http://slow"), Duration::from_secs(120));
+        assert_eq!(timeout_for(&routes, "http://slow/"), Duration::from_secs(120));
+        assert_eq!(timeout_for(&routes, "http://api"), Duration::from_secs(default_timeout()));
+        assert_eq!(timeout_for(&routes, "http://elsewhere"), Duration::from_secs(default_timeout()));
+    }
+
+    #[test]
+    fn upstream_path () {
+        let api = route("/api", &["http://a"], true);
+        assert_eq!(api.upstream_path("/api/users"), "/users");
+        assert_eq!(api.upstream_path("/api"), "/");
+
+        let api = route("/api/", &["http://a"], true);
+        assert_eq!(api.upstream_path("/api/users"), "/users");
+        assert_eq!(api.upstream_path("/api/"), "/");
+
+        let kept = route("/api/", &["http://a"], false);
+        assert_eq!(kept.upstream_path("/api/users"), "/api/users");
+    }
+
+    #[test]
+    fn select_upstream () {
+        let route = route("/", &["http://a/", "http://b", "http://c"], false);
+        let picks: Vec<_> = (0..3).map(|_| route.select_upstream().unwrap().to_string()).collect();
+        assert_eq!(picks, vec!["http://a", "http://b", "http://c"]);
+
+        route.upstreams[1].healthy.store(false, Ordering::Relaxed);
+        let picks: Vec<_> = (0..4).map(|_| route.select_upstream().unwrap().to_string()).collect();
+        assert!(picks.iter().all(|url| url != "http://b"));
+        assert!(picks.contains(&"http://a".to_string()) && picks.contains(&"http://c".to_string()));
+
+        for upstream in route.upstreams.iter() {
+            upstream.healthy.store(false, Ordering::Relaxed);
+        }
+        assert_eq!(route.select_upstream(), None);
+    }
+
+    #[test]
+    fn response_headers_strip_hop_by_hop () {
+        let mut headers = HeaderMap::new();
+        for (key, value) in &[
+            ("connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-internal", "1"),
+            ("content-type", "text/plain"),
+        ] {
+            headers.insert(HeaderName::from_static(*key), HeaderValue::from_static(*value));
+        }
+
+        let mut kept = response_headers(&headers, &["x-internal".to_string()]);
+        kept.sort();
+        assert_eq!(kept, vec![("content-type".to_string(), "text/plain".to_string())]);
+    }
+}
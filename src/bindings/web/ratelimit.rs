@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use actix_lua::LuaMessage;
+use actix_web::{
+    error, http::header,
+    middleware::{Middleware, Started},
+    HttpRequest, HttpResponse,
+};
+use futures::Future;
+use rlua::prelude::*;
+use serde_json::Value;
+
+use crate::AppState;
+use super::server;
+
+/// Buckets are only swept once there are more than this many of them
+const SWEEP_THRESHOLD: usize = 10_000;
+
+lazy_static! {
+    /// Buckets used by the `ratelimit` Lua module, shared by every VM
+    static ref LUA_BUCKETS: Mutex<HashMap<String, TokenBucket>> = Mutex::new(HashMap::new());
+}
+
+/// A bucket holding up to `capacity` tokens, refilled continuously at `rate` tokens per second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new (capacity: f64, rate: f64) -> Self {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill (&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Takes `cost` tokens, returning the remaining tokens on success or
+    /// the time to wait until enough tokens are available
+    pub fn consume (&mut self, cost: f64) -> Result<f64, Duration> {
+        self.refill();
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(self.tokens)
+        } else {
+            Err(self.wait_for(cost))
+        }
+    }
+
+    /// Like `consume` but leaves the bucket untouched
+    pub fn check (&mut self, cost: f64) -> Result<f64, Duration> {
+        self.refill();
+        if self.tokens >= cost {
+            Ok(self.tokens)
+        } else {
+            Err(self.wait_for(cost))
+        }
+    }
+
+    fn wait_for (&self, cost: f64) -> Duration {
+        if self.rate <= 0.0 || cost > self.capacity {
+            return Duration::from_secs(u64::from(u32::max_value()));
+        }
+        let secs = (cost - self.tokens) / self.rate;
+        Duration::from_millis((secs * 1000.0).ceil() as u64)
+    }
+
+    fn is_full (&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Rounds a wait up to whole seconds, as expected by `Retry-After`
+fn retry_after (wait: Duration) -> u64 {
+    wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    Remote,
+    Route,
+    Lua,
+}
+
+impl Default for KeyType {
+    fn default () -> Self { KeyType::Remote }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteLimitConfig {
+    path: String,
+    requests: u32,
+    per: u64,
+    burst: Option<u32>,
+    key: Option<KeyType>,
+}
+
+/// The `rate_limit` section of the `web-server` settings
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    requests: Option<u32>,
+    #[serde(default = "default_per")]
+    per: u64,
+    burst: Option<u32>,
+    #[serde(default)]
+    key: KeyType,
+    key_hook: Option<String>,
+    #[serde(default)]
+    routes: Vec<RouteLimitConfig>,
+}
+
+fn default_per () -> u64 { 60 }
+
+struct Limit {
+    path: String,
+    capacity: f64,
+    rate: f64,
+    key: KeyType,
+}
+
+impl Limit {
+    fn new (path: String, requests: u32, per: u64, burst: Option<u32>, key: KeyType) -> Self {
+        Limit {
+            path,
+            capacity: f64::from(burst.unwrap_or(requests)),
+            rate: f64::from(requests) / (per.max(1) as f64),
+            key,
+        }
+    }
+
+    fn matches (&self, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        path.starts_with(prefix) && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    }
+}
+
+pub struct RateLimiter {
+    default: Option<Limit>,
+    routes: Vec<Limit>,
+    key_hook: Option<String>,
+    /// Paths never limited, such as the health checks and metrics
+    exempt: Vec<String>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new (config: RateLimitConfig, exempt: Vec<String>) -> crate::Result<Self> {
+        let key = config.key;
+        let mut routes: Vec<Limit> = config.routes.into_iter()
+            .map(|route| Limit::new(route.path, route.requests, route.per, route.burst, route.key.unwrap_or(key)))
+            .collect();
+
+        let uses_lua = key == KeyType::Lua || routes.iter().any(|limit| limit.key == KeyType::Lua);
+        if uses_lua && config.key_hook.is_none() {
+            return Err(format_err!("rate_limit.key_hook is required when a rate limit key is \"lua\""));
+        }
+        // Longest prefix first, so the most specific route wins
+        routes.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+
+        Ok(RateLimiter {
+            default: config.requests.map(|requests| Limit::new(String::from("/"), requests, config.per, config.burst, key)),
+            routes,
+            key_hook: config.key_hook,
+            exempt,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn limit_for (&self, path: &str) -> Option<&Limit> {
+        if self.exempt.iter().any(|exempt| exempt == path) {
+            return None;
+        }
+        self.routes.iter().find(|limit| limit.matches(path)).or(self.default.as_ref())
+    }
+
+    fn consume (&self, limit: &Limit, key: &str) -> Result<f64, Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > SWEEP_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_full());
+        }
+        buckets.entry(format!("{}|{}", limit.path, key))
+            .or_insert_with(|| TokenBucket::new(limit.capacity, limit.rate))
+            .consume(1.0)
+    }
+}
+
+/// Reads the `rate_limit` settings, if any, leaving the `exempt` paths unlimited
+pub fn limiter_from_settings (web: &Value, exempt: Vec<String>) -> crate::Result<Option<Arc<RateLimiter>>> {
+    match web.get("rate_limit") {
+        Some(value) => {
+            let config: RateLimitConfig = serde_json::from_value(value.clone())?;
+            RateLimiter::new(config, exempt).map(Arc::new).map(Some)
+        },
+        None => Ok(None),
+    }
+}
+
+/// The client address without the port, so that every connection
+/// from the same client shares a bucket
+fn remote_key (req: &HttpRequest<AppState>) -> String {
+    let info = req.connection_info();
+    let remote = info.remote().unwrap_or("unknown");
+    remote.parse::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_| remote.to_string())
+}
+
+fn too_many_requests (wait: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .header(header::RETRY_AFTER, retry_after(wait).to_string())
+        .finish()
+}
+
+fn check (limiter: &RateLimiter, limit: &Limit, key: &str) -> Option<HttpResponse> {
+    match limiter.consume(limit, key) {
+        Ok(_) => None,
+        Err(wait) => {
+            debug!("rate limit exceeded for {} on {}", key, limit.path);
+            Some(too_many_requests(wait))
+        }
+    }
+}
+
+/// Middleware answering 429 Too Many Requests to clients over their limit
+pub struct RateLimit(Arc<RateLimiter>);
+
+impl RateLimit {
+    pub fn new (limiter: Arc<RateLimiter>) -> Self {
+        RateLimit(limiter)
+    }
+}
+
+impl Middleware<AppState> for RateLimit {
+    fn start (&self, req: &HttpRequest<AppState>) -> error::Result<Started> {
+        let limiter = self.0.clone();
+        let limit = match limiter.limit_for(req.path()) {
+            Some(limit) => limit,
+            None => return Ok(Started::Done),
+        };
+
+        let key = match limit.key {
+            KeyType::Remote => remote_key(req),
+            KeyType::Route => String::new(),
+            KeyType::Lua => {
+                let mut table = HashMap::new();
+                table.insert("method".to_owned(), LuaMessage::String(req.method().to_string()));
+                table.insert("path".to_owned(), LuaMessage::String(req.path().to_owned()));
+                table.insert("remote".to_owned(), LuaMessage::String(remote_key(req)));
+                table.insert("headers".to_owned(), LuaMessage::Table(req.headers().iter()
+                    .filter_map(|(key, value)| value.to_str().ok()
+                        .map(|value| (key.as_str().to_owned(), LuaMessage::String(value.to_owned()))))
+                    .collect()));
+
+                let hook = limiter.key_hook.clone().unwrap_or_default();
+                let path = req.path().to_owned();
+                return Ok(Started::Future(Box::new(server::site_state(req).call_hook(&hook, LuaMessage::Table(table))
+                    .from_err()
+                    .map(move |key| {
+                        // A nil key means the request is not limited
+                        let key = match key {
+                            LuaMessage::String(key) => key,
+                            LuaMessage::Integer(key) => key.to_string(),
+                            LuaMessage::Number(key) => key.to_string(),
+                            _ => return None,
+                        };
+                        limiter.limit_for(&path).and_then(|limit| check(&limiter, limit, &key))
+                    }))));
+            },
+        };
+
+        Ok(match check(&limiter, limit, &key) {
+            Some(response) => Started::Response(response),
+            None => Started::Done,
+        })
+    }
+}
+
+/// Runs `f` on the bucket named `key`, creating or resizing it as needed
+fn with_bucket<T, F> (key: &str, requests: u32, per: f64, f: F) -> T
+    where F: FnOnce(&mut TokenBucket) -> T
+{
+    let capacity = f64::from(requests);
+    let rate = capacity / per.max(0.001);
+    let mut buckets = LUA_BUCKETS.lock().unwrap();
+    if buckets.len() > SWEEP_THRESHOLD {
+        buckets.retain(|_, bucket| !bucket.is_full());
+    }
+    let bucket = buckets.entry(key.to_string())
+        .or_insert_with(|| TokenBucket::new(capacity, rate));
+    bucket.capacity = capacity;
+    bucket.rate = rate;
+    f(bucket)
+}
+
+/// Returns whether the request is allowed, the tokens left and the seconds to wait before retrying
+fn quota_result (result: Result<f64, Duration>) -> (bool, u64, f64) {
+    match result {
+        Ok(remaining) => (true, remaining.floor() as u64, 0.0),
+        Err(wait) => (false, 0, wait.as_secs() as f64 + f64::from(wait.subsec_nanos()) / 1e9),
+    }
+}
+
+pub fn init(lua: &Lua) -> crate::Result<()> {
+    lua.context(|lua| {
+        let module = lua.create_table()?;
+
+        // ratelimit.consume(key, requests, per_seconds, [cost])
+        module.set("consume", lua.create_function(|_, (key, requests, per, cost): (String, u32, f64, Option<f64>)| {
+            Ok(quota_result(with_bucket(&key, requests, per, |bucket| bucket.consume(cost.unwrap_or(1.0)))))
+        })?)?;
+
+        // ratelimit.check(key, requests, per_seconds, [cost])
+        module.set("check", lua.create_function(|_, (key, requests, per, cost): (String, u32, f64, Option<f64>)| {
+            Ok(quota_result(with_bucket(&key, requests, per, |bucket| bucket.check(cost.unwrap_or(1.0)))))
+        })?)?;
+
+        module.set("reset", lua.create_function(|_, key: String| {
+            Ok(LUA_BUCKETS.lock().unwrap().remove(&key).is_some())
+        })?)?;
+
+        lua.globals().set("ratelimit", module)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket () {
+        let mut bucket = TokenBucket::new(2.0, 1.0);
+        assert!(bucket.consume(1.0).is_ok());
+        assert!(bucket.consume(1.0).is_ok());
+        let wait = bucket.consume(1.0).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+        assert_eq!(retry_after(wait), 1);
+    }
+
+    #[test]
+    fn exempt_paths () {
+        let config: RateLimitConfig = serde_json::from_value(serde_json::json!({
+            "requests": 10,
+            "routes": [{ "path": "/api", "requests": 1, "per": 60 }],
+        })).unwrap();
+        let limiter = RateLimiter::new(config, vec![String::from("/healthz"), String::from("/api/metrics")]).unwrap();
+
+        assert!(limiter.limit_for("/healthz").is_none());
+        assert!(limiter.limit_for("/api/metrics").is_none());
+        assert_eq!(limiter.limit_for("/healthz/deep").map(|limit| limit.path.as_str()), Some("/"));
+        assert_eq!(limiter.limit_for("/api/users").map(|limit| limit.path.as_str()), Some("/api"));
+    }
+
+    #[test]
+    fn lua_ratelimit () {
+        let lua = Lua::new();
+        init(&lua).unwrap();
+        lua.context(|lua| {
+            lua.load(r#"
+                local allowed, remaining = ratelimit.consume("login:alice", 2, 60)
+                assert(allowed == true and remaining == 1)
+                assert(ratelimit.check("login:alice", 2, 60) == true)
+                assert(ratelimit.consume("login:alice", 2, 60) == true)
+
+                local allowed, remaining, retry_after = ratelimit.consume("login:alice", 2, 60)
+                assert(allowed == false and remaining == 0)
+                assert(retry_after > 0)
+
+                assert(ratelimit.reset("login:alice") == true)
+                assert(ratelimit.consume("login:alice", 2, 60) == true)
+            "#).exec().unwrap();
+        });
+    }
+}
@@ -1,14 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 use actix_lua::{LuaMessage};
 use actix_web::{
     http, AsyncResponder,
     FutureResponse, HttpResponse, HttpMessage, HttpRequest,
 };
-use futures::Future;
+use futures::{future::{self, Either}, Future};
 use serde_urlencoded;
 use serde_json;
 
 use crate::AppState;
+use super::{access_log, proxy::{self, ProxyRoute}};
 
 /// Creates a lua table from a HttpRequest
 fn extract_table_from_request(request: &HttpRequest<AppState>, body: String) -> HashMap<String, LuaMessage> {
@@ -97,6 +98,10 @@ fn extract_table_from_request(request: &HttpRequest<AppState>, body: String) ->
         }
     }
 
+    if let Some(id) = access_log::request_id(request) {
+        table.insert("request_id".to_owned(), LuaMessage::String(id));
+    }
+
     table.insert("fragment".to_owned(), fragment);
     table.insert("path".to_owned(), LuaMessage::String(path));
     table.insert("body_raw".to_owned(), LuaMessage::String(body));
@@ -104,64 +109,144 @@ fn extract_table_from_request(request: &HttpRequest<AppState>, body: String) ->
     table
 }
 
-pub fn handler((request, body): (HttpRequest<AppState>, String)) -> FutureResponse<HttpResponse> {
-    let table = extract_table_from_request(&request, body);
+/// Returns the app for the site the request was sent to
+pub fn site_state(request: &HttpRequest<AppState>) -> &AppState {
+    request.state().site(request.connection_info().host())
+}
 
-    let app_state = request.state();
+/// The handler of requests no other resource took, which passes them to the Lua app
+///
+/// `routes` are the `proxy` routes of the settings, whose timeouts also apply
+/// when the app forwards a request to one of their upstreams.
+pub fn handler(routes: Vec<Arc<ProxyRoute>>) -> impl Fn((HttpRequest<AppState>, String)) -> FutureResponse<HttpResponse> {
+    move |args| handle(args, &routes)
+}
 
-    let own_addr = if app_state.lua.is_none() {
-        Some(app_state.create_addr())
-    } else { None };
+fn handle((request, body): (HttpRequest<AppState>, String), routes: &[Arc<ProxyRoute>]) -> FutureResponse<HttpResponse> {
+    let body_raw = body.clone();
+    let table = extract_table_from_request(&request, body);
 
-    let addr = own_addr.as_ref().unwrap_or_else(|| { app_state.lua.as_ref().unwrap() });
+    let addr = site_state(&request).get_addr();
+    let routes = routes.to_vec();
 
     addr.send(LuaMessage::Table(table))
         .from_err()
-        .and_then(|res| match res {
-            LuaMessage::String(s) => Ok(HttpResponse::Ok().body(s)),
-            LuaMessage::Table(params) => {
-                let mut response = HttpResponse::Ok();
-
-                let body = match params.get("body") {
-                    Some(LuaMessage::String(body)) => body.to_owned(),
-                    Some(ref value @ _) => unimplemented!("Invalid body: {:?}", value),
-                    None => String::new(),
-                };
-
-                if let Some(LuaMessage::Table(headers)) = params.get("headers") {
-                    for (key, value) in headers.iter() {
-                        let value = match value {
-                            LuaMessage::String(value) => value.to_owned(),
-                            LuaMessage::Number(number) => number.to_string(),
-                            LuaMessage::Integer(number) => number.to_string(),
-                            ref value @ _ => unimplemented!("Header value is not supported: {:?}", value),
-                        };
-                        response.header(key as &str, value);
-                    }
-                }
-
-                match params.get("status") {
-                    Some(LuaMessage::String(string)) => {
-                        let number: u16 = string.parse()
-                            .expect("Invalid response status");
-                        let status = http::StatusCode::from_u16(number)
-                            .expect("Invalid response status");
-                        response.status(status);
-                    },
-                    Some(LuaMessage::Integer(number)) => {
-                        let status = http::StatusCode::from_u16(*number as u16)
-                            .expect("Invalid response status");
-                        response.status(status);
-                    },
-                    _ => (),
-                }
-
-                Ok(response.body(body))
-            },
-            LuaMessage::Nil => {
-                Ok(HttpResponse::NotFound().finish())
+        .and_then(move |res| match res {
+            // The handler asked for the request to be forwarded to another server
+            LuaMessage::Table(ref params) if params.contains_key("proxy") => match params.get("proxy") {
+                Some(LuaMessage::String(upstream)) => {
+                    let timeout = proxy::timeout_for(&routes, upstream);
+                    Either::A(proxy::forward_buffered(&request, upstream, body_raw, timeout))
+                },
+                value => {
+                    error!("Invalid proxy upstream: {:?}", value);
+                    Either::B(future::ok(HttpResponse::InternalServerError().finish()))
+                },
             },
-            _ => unimplemented!("Response {:?} is not supported", res),
+            res => Either::B(future::ok(response_from_message(res))),
         })
         .responder()
-}
\ No newline at end of file
+}
+
+/// Reads a status code given as a number or a string, `None` when it is not a valid one
+pub fn status_from_message(value: &LuaMessage) -> Option<http::StatusCode> {
+    let number = match *value {
+        LuaMessage::String(ref string) => string.trim().parse::<i64>().ok()?,
+        LuaMessage::Integer(number) => number,
+        LuaMessage::Number(number) if number.fract() == 0.0 => number as i64,
+        _ => return None,
+    };
+    if number < 0 || number > i64::from(u16::max_value()) {
+        return None;
+    }
+    http::StatusCode::from_u16(number as u16).ok()
+}
+
+/// Builds the response out of the value returned by the Lua handler
+///
+/// Values that can't make up a response are logged and answered with a 500.
+fn response_from_message(res: LuaMessage) -> HttpResponse {
+    let response = match res {
+        LuaMessage::String(s) => Ok(HttpResponse::Ok().body(s)),
+        LuaMessage::Table(params) => response_from_table(&params),
+        LuaMessage::Nil => Ok(HttpResponse::NotFound().finish()),
+        res => Err(format!("Response {:?} is not supported", res)),
+    };
+    response.unwrap_or_else(|err| {
+        error!("{}", err);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+fn response_from_table(params: &HashMap<String, LuaMessage>) -> Result<HttpResponse, String> {
+    let mut response = HttpResponse::Ok();
+
+    let body = match params.get("body") {
+        Some(LuaMessage::String(body)) => body.to_owned(),
+        Some(value) => return Err(format!("Invalid body: {:?}", value)),
+        None => String::new(),
+    };
+
+    if let Some(LuaMessage::Table(headers)) = params.get("headers") {
+        for (key, value) in headers.iter() {
+            let value = match value {
+                LuaMessage::String(value) => value.to_owned(),
+                LuaMessage::Number(number) => number.to_string(),
+                LuaMessage::Integer(number) => number.to_string(),
+                value => return Err(format!("Header value is not supported: {:?}", value)),
+            };
+            response.header(key as &str, value);
+        }
+    }
+
+    if let Some(value) = params.get("status") {
+        let status = status_from_message(value)
+            .ok_or_else(|| format!("Invalid response status: {:?}", value))?;
+        response.status(status);
+    }
+
+    Ok(response.body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(pairs: Vec<(&str, LuaMessage)>) -> LuaMessage {
+        LuaMessage::Table(pairs.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(status_from_message(&LuaMessage::Integer(201)), Some(http::StatusCode::CREATED));
+        assert_eq!(status_from_message(&LuaMessage::Number(404.0)), Some(http::StatusCode::NOT_FOUND));
+        assert_eq!(status_from_message(&LuaMessage::String("302".to_owned())), Some(http::StatusCode::FOUND));
+        assert_eq!(status_from_message(&LuaMessage::Number(200.5)), None);
+        assert_eq!(status_from_message(&LuaMessage::Integer(1000)), None);
+        assert_eq!(status_from_message(&LuaMessage::Integer(65736)), None);
+        assert_eq!(status_from_message(&LuaMessage::Integer(-1)), None);
+        assert_eq!(status_from_message(&LuaMessage::Boolean(true)), None);
+    }
+
+    #[test]
+    fn invalid_responses() {
+        let ok = response_from_message(table(vec![
+            ("body", LuaMessage::String("hi".to_owned())),
+            ("status", LuaMessage::Integer(201)),
+            ("headers", table(vec![("x-count", LuaMessage::Integer(2))])),
+        ]));
+        assert_eq!(ok.status(), http::StatusCode::CREATED);
+        assert_eq!(ok.headers().get("x-count").unwrap(), "2");
+
+        for res in vec![
+            table(vec![("body", table(vec![]))]),
+            table(vec![("headers", table(vec![("x-flag", LuaMessage::Boolean(true))]))]),
+            table(vec![("status", LuaMessage::Integer(1000))]),
+            LuaMessage::Boolean(true),
+        ] {
+            assert_eq!(response_from_message(res).status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        assert_eq!(response_from_message(LuaMessage::Nil).status(), http::StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,20 @@
+-- Messages sent to actors spawned from Lua arrive as { message = ... } and are
+-- handed to the function returned by the actor script, whatever they contain
+local envelope = ctx.msg
+
+if not torchbear.handler then
+  _log.error("Actor " .. tostring(actor.name()) .. " has no function handling messages")
+  return nil
+end
+
+local ok, reply = xpcall(torchbear.handler, function (msg)
+  msg = tostring(msg)
+  local trace = debug.traceback(msg, 3)
+  _log.error(trace)
+  return trace
+end, envelope.message)
+
+if ok then
+  return reply
+end
+return nil
@@ -4,6 +4,44 @@ torchbear.response = nil
 -- Declare the request
 local request = ctx.msg
 
+-- Hooks called from the Rust side, such as the proxy hooks, are sent as
+-- { hook = "path.to.function", args = ... } instead of a request
+if request.hook then
+  local hook = _G
+  for key in string.gmatch(request.hook, "[^%.]+") do
+    if type(hook) ~= "table" then
+      hook = nil
+      break
+    end
+    hook = hook[key]
+  end
+
+  if type(hook) ~= "function" then
+    _log.debug("Hook " .. request.hook .. " is not defined")
+    return nil
+  end
+
+  local ok, result = xpcall(hook, function (msg)
+    msg = tostring(msg)
+    local trace = debug.traceback(msg, 3)
+    _log.error(trace)
+    return trace
+  end, request.args)
+
+  if ok then
+    return result
+  end
+  -- Tells a failing hook apart from one that returned nothing
+  return { hook_error = result }
+end
+
+-- Every line logged while handling the request carries its id and route
+_log.set_context({
+  request_id = request.request_id,
+  method = request.method,
+  route = request.path
+})
+
 xpcall(function ()
 
   -- Returned response
@@ -26,5 +64,8 @@ end, function (msg)
   }
 end)
 
+_log.clear_context()
+metrics.record_vm_memory()
+
 -- The returned values from this handler is the response
 return torchbear.response
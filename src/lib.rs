@@ -1,18 +1,22 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde_derive;
+#[macro_use] extern crate lazy_static;
 #[cfg(feature = "tantivy_bindings")] extern crate tantivy;
 #[macro_use] pub mod error;
 pub mod bindings;
 pub mod logger;
+pub mod metrics;
 pub mod conf;
 
 use actix::prelude::*;
-use actix_lua::LuaActorBuilder;
+use actix_lua::{LuaActor, LuaActorBuilder, LuaMessage};
 use actix_web::{server as actix_server, App};
 use rlua::prelude::*;
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
     result,
+    sync::Arc,
     fs, io::prelude::*
 };
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
@@ -30,6 +34,26 @@ pub struct AppState {
     pub package_path: Option<String>,
     pub settings: Value,
     pub app_settings: Option<(String, Value)>,
+    pub sites: Arc<Vec<Site>>,
+}
+
+/// A virtual host, served by its own app when the `Host` header matches one of `hosts`
+#[derive(Clone)]
+pub struct Site {
+    pub hosts: Vec<String>,
+    pub state: AppState,
+}
+
+impl Site {
+    fn matches (&self, host: &str) -> bool {
+        self.hosts.iter().any(|pattern| {
+            if pattern.starts_with("*.") {
+                host.ends_with(&pattern[1..])
+            } else {
+                pattern == host
+            }
+        })
+    }
 }
 
 impl AppState {
@@ -48,6 +72,9 @@ impl AppState {
         bindings::web::init(&lua)?;
         bindings::number::init(&lua)?;
         bindings::net::init(&lua)?;
+        if let Some(namespace) = self.settings.get("cache_namespace").and_then(Value::as_str) {
+            bindings::app::cache::set_namespace(&lua, namespace)?;
+        }
         lua.context(|lua| -> result::Result<(), LuaError> {
             // torchbear global table 
             {
@@ -112,19 +139,95 @@ impl AppState {
     }
 
     pub fn create_addr (&self) -> LuaAddr {
-        let vm = self.create_vm().unwrap();
+        Self::start_actor(self.create_vm().unwrap())
+    }
+
+    /// Starts an actor handling requests with `vm`, which is set up by `create_vm`
+    pub fn start_actor (vm: Lua) -> LuaAddr {
+        Self::start_actor_with(vm, include_str!("handlers/web_server.lua"))
+    }
+
+    /// Starts an actor running `handler` on every message it gets, with `vm` set up by `create_vm`
+    pub fn start_actor_with (vm: Lua, handler: &'static str) -> LuaAddr {
         Arbiter::start(move |_| {
             let lua_actor = LuaActorBuilder::new()
-                .on_handle_with_lua(include_str!("handlers/web_server.lua"))
+                .on_handle_with_lua(handler)
                 .build_with_vm(vm)
                 .unwrap();
             lua_actor
         })
     }
+
+    /// Returns the app serving `host`, which is this one unless a site claims it
+    pub fn site (&self, host: &str) -> &AppState {
+        let host = strip_port(host).to_lowercase();
+        self.sites.iter()
+            .find(|site| site.matches(&host))
+            .map(|site| &site.state)
+            .unwrap_or(self)
+    }
+
+    /// Runs the bootstrap script at `path`, exiting the process if it fails
+    pub fn run_bootstrap (&self, path: &str) {
+        let vm = self.create_vm().unwrap();
+        vm.context(|vm| {
+            vm.globals().get::<_, LuaTable>("torchbear").unwrap().set("bootstrap", path).unwrap();
+
+//                    if !vm.exec::<_, bool>(include_str!("handlers/bootstrap.lua"), Some("bootstrap")).unwrap()
+            if !vm.load(include_str!("handlers/bootstrap.lua")).set_name("bootstrap").unwrap().eval::<bool>().unwrap()
+                { std::process::exit(1); }
+        });
+    }
+
+    /// Returns the shared actor when `single_actor` is set, or a new one otherwise
+    pub fn get_addr (&self) -> LuaAddr {
+        self.lua.clone().unwrap_or_else(|| self.create_addr())
+    }
+
+    /// Calls the Lua function at `hook` (a dotted path such as `torchbear.ready`)
+    /// on the app's actor, with `args` as its only argument
+    pub fn call_hook (&self, hook: &str, args: LuaMessage) -> Request<LuaActor, LuaMessage> {
+        self.get_addr().send(hook_message(hook, args))
+    }
+}
+
+/// Removes the `:port` suffix of a `Host` header, leaving bare and bracketed IPv6 hosts intact
+fn strip_port (host: &str) -> &str {
+    if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else if host.matches(':').count() == 1 {
+        &host[..host.find(':').unwrap()]
+    } else {
+        host
+    }
+}
+
+/// The trace of a hook that raised an error, which the dispatcher returns as `{ hook_error = trace }`
+pub fn hook_error (message: &LuaMessage) -> Option<&str> {
+    match message {
+        LuaMessage::Table(table) => match table.get("hook_error") {
+            Some(LuaMessage::String(trace)) => Some(trace),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The message calling `hook` on a Lua actor, see `AppState::call_hook`
+fn hook_message (hook: &str, args: LuaMessage) -> LuaMessage {
+    let mut table = HashMap::new();
+    table.insert("hook".to_owned(), LuaMessage::String(hook.to_owned()));
+    table.insert("args".to_owned(), args);
+    LuaMessage::Table(table)
 }
 
 pub struct ApplicationBuilder {
     log_settings: logger::Settings,
+    /// Whether the level was given explicitly, which takes precedence over torchbear.scl
+    log_level_set: bool,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -132,6 +235,21 @@ pub struct SettingConfig {
     general: Option<Value>,
     #[serde(rename = "web-server")]
     web_server: Option<Value>,
+    sites: Option<Vec<Value>>,
+    log: Option<Value>,
+}
+
+/// Loads the `<app-name>.scl` file next to the app, if `settings` name one
+fn load_app_settings (root_path: &Path, settings: &Value) -> Option<(String, Value)> {
+    settings.get("app-name").and_then(Value::as_str).map(PathBuf::from).and_then(|name| {
+        let mut config_path = root_path.join(&name);
+        config_path.set_extension("scl");
+        if config_path.exists() && config_path.is_file() {
+            conf::Conf::load_file(&config_path).map(|s| (name.to_string_lossy().to_string(), s)).ok()
+        } else {
+            None
+        }
+    })
 }
 
 impl ApplicationBuilder {
@@ -140,18 +258,31 @@ impl ApplicationBuilder {
             log_settings: logger::Settings{
                 level: logger::LevelFilter::Info,
                 everything: false,
-            }
+                rotation: logger::Rotation::default(),
+                format: logger::Format::Text,
+            },
+            log_level_set: false,
         }
     }
 
     pub fn log_level (&mut self, level: logger::Level) -> &mut Self {
-        self.log_settings.level = level.to_level_filter(); self
+        self.log_settings.level = level.to_level_filter();
+        self.log_level_set = true;
+        self
     }
 
     pub fn log_everything (&mut self, b: bool) -> &mut Self {
         self.log_settings.everything = b; self
     }
 
+    pub fn log_rotation (&mut self, rotation: logger::Rotation) -> &mut Self {
+        self.log_settings.rotation = rotation; self
+    }
+
+    pub fn log_format (&mut self, format: logger::Format) -> &mut Self {
+        self.log_settings.format = format; self
+    }
+
     pub fn start (&mut self, args: Option<Vec<String>>) -> Result<()> {
         openssl_probe::init_ssl_cert_env_vars();
         
@@ -213,27 +344,48 @@ impl ApplicationBuilder {
 
         let general = config.general.unwrap_or_default();
 
-        let app_config: Option<(String, Value)> = general.get("app-name").and_then(Value::as_str).map(PathBuf::from).and_then(|name| {
-            let mut config_path = root_path.join(&name);
-            config_path.set_extension("scl");
-            if config_path.exists() && config_path.is_file() {
-                conf::Conf::load_file(&config_path).map(|s| (name.to_string_lossy().to_string(), s)).ok()
-            } else {
-                None
-            }
-        });
+        let app_config = load_app_settings(root_path, &general);
 
         let init_path = init_path.unwrap_or(PathBuf::from(&get_or(&general, "init", "init.lua")));
         
-        if !init_path.exists() || !init_path.is_file() {
+        let site_configs = match config.web_server {
+            Some(_) => config.sites.unwrap_or_default(),
+            None => vec![],
+        };
+
+        // With sites declared, the main app is optional and requests for
+        // unknown hosts go to the first site instead
+        let has_main_app = init_path.exists() && init_path.is_file();
+
+        if !has_main_app && site_configs.is_empty() {
             println!("Error: Specified init.lua not found. You may have not completed installing your app");
             std::process::exit(1);
         }
 
         let log_path = general.get("log_path").and_then(|log| log.as_str());
 
+        if let Some(rotation) = general.get("log_rotation") {
+            self.log_settings.rotation = logger::Rotation::from_settings(rotation)?;
+        }
+
+        if let Some(format) = general.get("log_format").and_then(Value::as_str) {
+            self.log_settings.format = logger::Format::from_name(format)?;
+        }
+
+        if let Some(log) = config.log.as_ref() {
+            match log.get("level").and_then(Value::as_str) {
+                Some(level) if !self.log_level_set => self.log_settings.level = logger::parse_level(level)?,
+                _ => (),
+            }
+            logger::configure_targets(log)?;
+        }
+
         logger::init(log_path, self.log_settings.clone());
 
+        if let Some(size) = general.get("cache_size").and_then(Value::as_u64) {
+            bindings::app::cache::set_capacity(size as usize);
+        }
+
         let sys = actix::System::new("torchbear");
 
         let mut app_state = AppState {
@@ -241,21 +393,17 @@ impl ApplicationBuilder {
             init_path: init_path,
             init_args: init_args,
             package_path: package_path,
-            settings: general,
+            settings: general.clone(),
             app_settings: app_config,
+            sites: Arc::new(vec![]),
         };
 
         if let Some(web) = config.web_server {
 
-            if let Some(Some(bootstrap)) = web.get("bootstrap_path").map(|s| { s.as_str() }) {
-                let vm = app_state.create_vm().unwrap();
-                vm.context(|vm| {
-                    vm.globals().get::<_, LuaTable>("torchbear").unwrap().set("bootstrap", bootstrap).unwrap();
-
-//                    if !vm.exec::<_, bool>(include_str!("handlers/bootstrap.lua"), Some("bootstrap")).unwrap()
-                    if !vm.load(include_str!("handlers/bootstrap.lua")).set_name("bootstrap").unwrap().eval::<bool>().unwrap()
-                        { std::process::exit(1); }
-                });
+            if has_main_app {
+                if let Some(Some(bootstrap)) = web.get("bootstrap_path").map(|s| { s.as_str() }) {
+                    app_state.run_bootstrap(bootstrap);
+                }
             }
 
             let single_actor = match web.get("single_actor").map(|s| { s.as_bool() }) {
@@ -267,8 +415,91 @@ impl ApplicationBuilder {
                 },
             };
 
-            if single_actor {
-                app_state.lua = Some(app_state.create_addr());
+            // The single actor of the app is the one reachable as "web", see `actor::register_app`
+            if single_actor && has_main_app {
+                let vm = app_state.create_vm()?;
+                bindings::app::actor::set_name(&vm, "web")?;
+                app_state.lua = Some(AppState::start_actor(vm));
+            }
+
+            let mut sites = vec![];
+            for site in site_configs.iter() {
+                let hosts: Vec<String> = match site.get("hosts").map(|hosts| serde_json::from_value::<Vec<String>>(hosts.clone())) {
+                    Some(Ok(ref hosts)) if !hosts.is_empty() => hosts.iter().map(|host| host.to_lowercase()).collect(),
+                    _ => {
+                        println!("Error: Every site needs a non-empty hosts list");
+                        std::process::exit(1);
+                    }
+                };
+
+                let site_init = root_path.join(get_or(site, "init", "init.lua"));
+                if !site_init.exists() || !site_init.is_file() {
+                    println!("Error: Init script {:?} for site {} not found", site_init, hosts[0]);
+                    std::process::exit(1);
+                }
+                let site_root = site_init.parent().unwrap_or(root_path).to_path_buf();
+
+                // Site settings override the general ones
+                let mut settings = general.clone();
+                if let (Some(settings), Some(site)) = (settings.as_object_mut(), site.as_object()) {
+                    for (key, value) in site.iter() {
+                        settings.insert(key.clone(), value.clone());
+                    }
+                    if !site.contains_key("queue_path") {
+                        let dir = bindings::app::queue::site_dir(&general, &hosts[0]);
+                        settings.insert("queue_path".to_owned(), Value::String(dir.to_string_lossy().into_owned()));
+                    }
+                    if !site.contains_key("cache_namespace") {
+                        settings.insert("cache_namespace".to_owned(), Value::String(hosts[0].clone()));
+                    }
+                }
+
+                let mut state = AppState {
+                    lua: None,
+                    init_path: site_init,
+                    init_args: vec![],
+                    package_path: site_root.to_str().map(|p| format!("{}/?.lua", p)),
+                    app_settings: load_app_settings(&site_root, &settings),
+                    settings: settings,
+                    sites: Arc::new(vec![]),
+                };
+
+                if let Some(bootstrap) = site.get("bootstrap_path").and_then(Value::as_str) {
+                    state.run_bootstrap(bootstrap);
+                }
+
+                if site.get("single_actor").and_then(Value::as_bool).unwrap_or(false) {
+                    let vm = state.create_vm()?;
+                    // Without a main app, the first site takes its place
+                    if !has_main_app && sites.is_empty() {
+                        bindings::app::actor::set_name(&vm, "web")?;
+                    }
+                    state.lua = Some(AppState::start_actor(vm));
+                }
+
+                log::debug!("site {} served from {:?}", hosts.join(", "), state.init_path);
+                sites.push(Site { hosts, state });
+            }
+
+            if !has_main_app {
+                let mut first = sites[0].state.clone();
+                first.sites = Arc::new(vec![]);
+                app_state = first;
+            }
+            app_state.sites = Arc::new(sites);
+
+            bindings::app::actor::set_template(&app_state);
+            if let Some(addr) = app_state.lua.clone() {
+                bindings::app::actor::register_app("web", addr);
+            }
+
+            if has_main_app {
+                bindings::app::scheduler::start(&app_state)?;
+                bindings::app::queue::start(&app_state)?;
+            }
+            for site in app_state.sites.iter() {
+                bindings::app::scheduler::start(&site.state)?;
+                bindings::app::queue::start(&site.state)?;
             }
 
             log::debug!("web server section in settings, starting seting up web server");
@@ -289,22 +520,122 @@ impl ApplicationBuilder {
                 }
             };
 
-            let mut server = actix_server::new(move || {
-                App::with_state(app_state.clone())
-                    .default_resource(|r| r.with(bindings::web::server::handler))
-            });
+            let proxy_routes = bindings::web::proxy::routes_from_settings(&web)?;
+            if proxy_routes.iter().any(|route| route.has_health_check()) {
+                bindings::web::proxy::HealthChecker::new(&proxy_routes).start();
+            }
+
+            let access_log = bindings::web::access_log::access_log_from_settings(&web, general.get("log_path").and_then(Value::as_str), self.log_settings.rotation)?;
 
-            server = server.bind((host.as_str(), port))?;
-            log::debug!("web server listening on port {}:{}", &host, port);
+            let proxy_paths: Vec<String> = proxy_routes.iter().map(|route| route.path.clone()).collect();
+            let metrics = bindings::web::metrics::config_from_settings(&web, &proxy_paths)?;
+            let health = bindings::web::health::config_from_settings(&web)?;
+
+            // Probes and scrapes must keep working while clients are limited
+            let mut exempt_paths: Vec<String> = metrics.iter().map(|metrics| metrics.path.clone()).collect();
+            if let Some(health) = health.as_ref() {
+                exempt_paths.push(health.liveness.clone());
+                exempt_paths.push(health.readiness.clone());
+            }
+            let rate_limiter = bindings::web::ratelimit::limiter_from_settings(&web, exempt_paths)?;
+
+            let factory = move || {
+                let mut app = App::with_state(app_state.clone())
+                    .middleware(bindings::web::access_log::RequestId);
+                if let Some(access_log) = access_log.clone() {
+                    app = app.middleware(access_log);
+                }
+                if let Some(metrics) = metrics.as_ref() {
+                    app = app
+                        .middleware(bindings::web::metrics::Metrics::new(metrics))
+                        .resource(&metrics.path, |r| r.f(bindings::web::metrics::handler));
+                }
+                if let Some(health) = health.as_ref() {
+                    let readiness = bindings::web::health::readiness(health);
+                    app = app
+                        .resource(&health.liveness, |r| r.f(bindings::web::health::liveness))
+                        .resource(&health.readiness, move |r| r.f(readiness));
+                }
+                if let Some(limiter) = rate_limiter.clone() {
+                    app = app.middleware(bindings::web::ratelimit::RateLimit::new(limiter));
+                }
+                for route in proxy_routes.iter() {
+                    app = app.handler(&route.path, bindings::web::proxy::ProxyHandler::new(route.clone()));
+                }
+                let handler = bindings::web::server::handler(proxy_routes.clone());
+                app.default_resource(move |r| r.with(handler))
+            };
+
+            let mut server = actix_server::new(factory.clone());
+            let mut has_listeners = false;
+
+            let unix_socket = web.get("unix_socket").and_then(Value::as_str);
+            let socket_activation = web.get("socket_activation").and_then(Value::as_bool).unwrap_or(false);
+
+            // Only listen on TCP next to other listeners if asked to explicitly
+            if (unix_socket.is_none() && !socket_activation) || web.get("address").is_some() || web.get("port").is_some() {
+                server = server.bind((host.as_str(), port))?;
+                has_listeners = true;
+                log::debug!("web server listening on port {}:{}", &host, port);
+            }
+
+            #[cfg(target_family = "unix")]
+            {
+                use bindings::web::listeners::{self, ActivatedListener};
+
+                if let Some(path) = unix_socket {
+                    let mode = match web.get("unix_socket_mode").map(|mode| mode.as_str().and_then(listeners::parse_mode)) {
+                        None => None,
+                        Some(Some(mode)) => Some(mode),
+                        Some(None) => {
+                            println!("Error: Setting web_server.unix_socket_mode must be an octal string such as \"660\"");
+                            std::process::exit(1);
+                        },
+                    };
+                    let listener = listeners::bind_unix(path, mode)?;
+                    actix_server::new(factory.clone()).start_incoming(listener.incoming(), false);
+                    log::debug!("web server listening on unix socket {}", path);
+                }
+
+                if socket_activation {
+                    for listener in listeners::activated_listeners()? {
+                        match listener {
+                            ActivatedListener::Tcp(listener) => {
+                                log::debug!("web server listening on activated socket {:?}", listener.local_addr());
+                                server = server.listen(listener);
+                                has_listeners = true;
+                            },
+                            ActivatedListener::Unix(listener) => {
+                                log::debug!("web server listening on an activated unix socket");
+                                actix_server::new(factory.clone()).start_incoming(listener.incoming(), false);
+                            },
+                        }
+                    }
+                }
+            }
+
+            #[cfg(not(target_family = "unix"))]
+            {
+                if unix_socket.is_some() || socket_activation {
+                    println!("Error: Unix sockets and socket activation are only supported on unix");
+                    std::process::exit(1);
+                }
+            }
 
             if let Some(ssl_builder) = some_ssl {
                 let host = get_or(&web, "tls_address", "0.0.0.0");
                 let port = get_or(&web, "tls_port", "3001").parse().unwrap_or(3001);
                 server = server.bind_ssl((host.as_str(), port), ssl_builder)?;
+                has_listeners = true;
                 log::debug!("tls server listening on port {}:{}", &host, port);
             }
 
-            server.start();
+            if has_listeners {
+                server.start();
+            }
+
+            // Every init and bootstrap script has run by now
+            bindings::web::health::set_ready(true);
 
             let _ = sys.run();
         } else {
@@ -317,3 +648,95 @@ impl ApplicationBuilder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state (sites: Vec<Site>) -> AppState {
+        AppState {
+            lua: None,
+            init_path: PathBuf::new(),
+            init_args: vec![],
+            package_path: None,
+            settings: Value::Null,
+            app_settings: None,
+            sites: Arc::new(sites),
+        }
+    }
+
+    fn site (hosts: &[&str], name: &str) -> Site {
+        let mut state = state(vec![]);
+        state.package_path = Some(name.to_string());
+        Site { hosts: hosts.iter().map(|host| host.to_string()).collect(), state }
+    }
+
+    fn selected (app: &AppState, host: &str) -> Option<String> {
+        app.site(host).package_path.clone()
+    }
+
+    /// Runs the hook dispatcher of web_server.lua on `msg` outside of an actor
+    fn dispatch (msg: &str) -> LuaMessage {
+        let lua = unsafe { Lua::new_with_debug() };
+        lua.context(|lua| {
+            lua.load(r#"
+                torchbear = {}
+                _log = { error = function () end, debug = function () end }
+                function ok_hook (args) return args.value end
+                function failing_hook () error("boom") end
+            "#).exec().unwrap();
+            lua.load(&format!("ctx = {{ msg = {} }}", msg)).exec().unwrap();
+            lua.load(include_str!("handlers/web_server.lua")).eval::<LuaMessage>().unwrap()
+        })
+    }
+
+    #[test]
+    fn hook_dispatch () {
+        let result = dispatch(r#"{ hook = "ok_hook", args = { value = "done" } }"#);
+        assert_eq!(result, LuaMessage::String("done".to_owned()));
+        assert_eq!(hook_error(&result), None);
+
+        let result = dispatch(r#"{ hook = "failing_hook" }"#);
+        assert!(hook_error(&result).unwrap().contains("boom"));
+
+        assert_eq!(dispatch(r#"{ hook = "missing.hook" }"#), LuaMessage::Nil);
+    }
+
+    #[test]
+    fn strip_ports () {
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("[::1]:3000"), "[::1]");
+        assert_eq!(strip_port("::1"), "::1");
+    }
+
+    #[test]
+    fn site_matches () {
+        let exact = site(&["example.com"], "exact");
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+
+        let wildcard = site(&["*.example.com"], "wildcard");
+        assert!(wildcard.matches("www.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+    }
+
+    #[test]
+    fn site_selection () {
+        let app = state(vec![
+            site(&["example.com"], "exact"),
+            site(&["*.example.com"], "wildcard"),
+            site(&["[::1]"], "ipv6"),
+        ]);
+
+        assert_eq!(selected(&app, "example.com"), Some("exact".to_string()));
+        assert_eq!(selected(&app, "Example.COM:8080"), Some("exact".to_string()));
+        assert_eq!(selected(&app, "api.example.com:443"), Some("wildcard".to_string()));
+        assert_eq!(selected(&app, "[::1]:3000"), Some("ipv6".to_string()));
+        assert_eq!(selected(&app, "other.org"), None);
+        assert_eq!(selected(&app, "[::2]:3000"), None);
+    }
+}
@@ -0,0 +1,61 @@
+use std::cell::RefCell;
+use serde_json::{Map, Value};
+
+thread_local! {
+    /// Fields attached to every line logged from this thread, such as the request id
+    static CONTEXT: RefCell<Map<String, Value>> = RefCell::new(Map::new());
+    /// Fields of the line being logged right now
+    static FIELDS: RefCell<Map<String, Value>> = RefCell::new(Map::new());
+}
+
+/// Replaces the context of the current thread
+pub fn set_context (fields: Map<String, Value>) {
+    CONTEXT.with(|context| *context.borrow_mut() = fields);
+}
+
+pub fn clear_context () {
+    CONTEXT.with(|context| context.borrow_mut().clear());
+}
+
+/// Runs `f` with `fields` added to whatever it logs
+pub fn with_fields<F, R> (fields: Map<String, Value>, f: F) -> R where F: FnOnce() -> R {
+    FIELDS.with(|current| *current.borrow_mut() = fields);
+    let result = f();
+    FIELDS.with(|current| current.borrow_mut().clear());
+    result
+}
+
+/// The context and the fields of the line being logged, fields taking precedence
+pub fn current () -> Map<String, Value> {
+    let mut all = CONTEXT.with(|context| context.borrow().clone());
+    FIELDS.with(|fields| {
+        for (key, value) in fields.borrow().iter() {
+            all.insert(key.clone(), value.clone());
+        }
+    });
+    all
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map (value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fields_over_context () {
+        set_context(map(json!({"request_id": "abc", "user": 1})));
+        let all = with_fields(map(json!({"user": 2})), current);
+        assert_eq!(Value::Object(all), json!({"request_id": "abc", "user": 2}));
+
+        assert_eq!(Value::Object(current()), json!({"request_id": "abc", "user": 1}));
+        clear_context();
+        assert!(current().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::RwLock;
+use log::{LevelFilter, Metadata};
+use serde_json::Value;
+use crate::Result;
+
+/// Which levels are logged for which targets, changeable while running
+struct Filters {
+    default: LevelFilter,
+    everything: bool,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+lazy_static! {
+    static ref FILTERS: RwLock<Filters> = RwLock::new(Filters {
+        default: LevelFilter::Info,
+        everything: false,
+        targets: vec![],
+    });
+}
+
+/// Whether `target` is `prefix` or one of its submodules
+fn is_under (target: &str, prefix: &str) -> bool {
+    target.starts_with(prefix) && (target.len() == prefix.len() || target[prefix.len()..].starts_with("::"))
+}
+
+/// Targets logged without `everything`, the rest of the dependency tree has to be enabled one by one
+fn is_own (target: &str) -> bool {
+    target.starts_with("torchbear") || is_under(target, "lua")
+}
+
+impl Filters {
+    fn level_for (&self, target: &str) -> LevelFilter {
+        let configured = self.targets.iter()
+            .filter(|(prefix, _)| is_under(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level);
+
+        match configured {
+            Some(level) => level,
+            None if self.everything || is_own(target) => self.default,
+            None => LevelFilter::Off,
+        }
+    }
+
+    fn max_level (&self) -> LevelFilter {
+        self.targets.iter().map(|(_, level)| *level).fold(self.default, ::std::cmp::max)
+    }
+
+    fn set (&mut self, target: &str, level: LevelFilter) {
+        match self.targets.iter_mut().find(|(prefix, _)| prefix == target) {
+            Some(entry) => entry.1 = level,
+            None => self.targets.push((target.to_owned(), level)),
+        }
+    }
+}
+
+pub fn parse_level (name: &str) -> Result<LevelFilter> {
+    name.parse().map_err(|_| format_err!("invalid log level {:?}", name))
+}
+
+/// Reads the `targets` and `loggers` of the `log` section of torchbear.scl,
+/// `loggers` being the named Lua loggers
+pub fn configure_targets (log: &Value) -> Result<()> {
+    let mut filters = FILTERS.write().unwrap();
+
+    for (key, prefix) in &[("targets", ""), ("loggers", "lua::")] {
+        if let Some(targets) = log.get(key) {
+            let targets = targets.as_object()
+                .ok_or_else(|| format_err!("log.{} must be a table of levels", key))?;
+            for (target, level) in targets {
+                let level = level.as_str()
+                    .ok_or_else(|| format_err!("log.{}.{} must be a level name", key, target))?;
+                filters.set(&format!("{}{}", prefix, target), parse_level(level)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn configure (default: LevelFilter, everything: bool) {
+    let mut filters = FILTERS.write().unwrap();
+    filters.default = default;
+    filters.everything = everything;
+}
+
+pub fn enabled (metadata: &Metadata) -> bool {
+    metadata.level() <= FILTERS.read().unwrap().level_for(metadata.target())
+}
+
+/// The highest level any target logs at, so the `log` macros can skip the rest early
+pub fn max_level () -> LevelFilter {
+    FILTERS.read().unwrap().max_level()
+}
+
+/// Sets the level of `target`, or the default level without one
+pub fn set_level (target: Option<&str>, level: LevelFilter) {
+    let max = {
+        let mut filters = FILTERS.write().unwrap();
+        match target {
+            Some(target) => filters.set(target, level),
+            None => filters.default = level,
+        }
+        filters.max_level()
+    };
+    log::set_max_level(max);
+}
+
+/// The level `target` logs at, or the default level without one
+pub fn level (target: Option<&str>) -> LevelFilter {
+    let filters = FILTERS.read().unwrap();
+    match target {
+        Some(target) => filters.level_for(target),
+        None => filters.default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_target () {
+        let mut filters = Filters { default: LevelFilter::Info, everything: false, targets: vec![] };
+        filters.set("actix_web", LevelFilter::Warn);
+        filters.set("lua::billing", LevelFilter::Debug);
+        filters.set("torchbear_lib::bindings", LevelFilter::Error);
+
+        assert_eq!(filters.level_for("actix_web::server"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("actix_webs"), LevelFilter::Off);
+        assert_eq!(filters.level_for("lua::billing"), LevelFilter::Debug);
+        assert_eq!(filters.level_for("lua"), LevelFilter::Info);
+        assert_eq!(filters.level_for("torchbear_lib::bindings::web"), LevelFilter::Error);
+        assert_eq!(filters.level_for("torchbear_lib"), LevelFilter::Info);
+        assert_eq!(filters.level_for("tokio"), LevelFilter::Off);
+        assert_eq!(filters.max_level(), LevelFilter::Debug);
+
+        filters.everything = true;
+        assert_eq!(filters.level_for("tokio"), LevelFilter::Info);
+    }
+}
@@ -0,0 +1,155 @@
+
+mod context;
+mod filter;
+mod rotate;
+
+use fern::Dispatch;
+use std::path::Path;
+use std::fs::create_dir;
+use std::fmt::{self, Display};
+use colored::*;
+use serde_json::Value;
+use crate::{error::Error, Result};
+pub use log::{Level, LevelFilter};
+pub use self::context::{clear_context, set_context, with_fields};
+pub use self::filter::{configure_targets, level, parse_level, set_level};
+pub use self::rotate::{Interval, Rotation, RotatingFile};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    /// Reads the `log_format` general setting
+    pub fn from_name (name: &str) -> Result<Self> {
+        match name {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format_err!("invalid log_format {:?}, expected \"text\" or \"json\"", name)),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Settings {
+    pub level: LevelFilter,
+    pub everything: bool,
+    pub rotation: Rotation,
+    pub format: Format,
+}
+
+/// The context and fields of the current line as ` key=value` pairs
+fn text_fields () -> String {
+    context::current().iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => format!(" {}={}", key, value),
+            value => format!(" {}={}", key, value),
+        })
+        .collect()
+}
+
+/// The current line as a JSON object, with its context and fields
+fn json_line (message: &fmt::Arguments, record: &log::Record) -> String {
+    let mut line = context::current();
+    line.insert("time".to_owned(), Value::String(::chrono::Local::now().to_rfc3339()));
+    line.insert("level".to_owned(), Value::String(record.level().to_string()));
+    line.insert("target".to_owned(), Value::String(record.target().to_owned()));
+    line.insert("message".to_owned(), Value::String(message.to_string()));
+    Value::Object(line).to_string()
+}
+
+/// Creates the log directory `path` if needed, failing if it is something else
+pub fn ensure_log_dir (path: &Path) -> Result<()> {
+    if path.exists() {
+        if !path.is_dir() {
+            return Err(Error::from(format!("{:?} is not a directory", path)));
+        }
+    } else {
+        create_dir(path).map_err(|e| Error::from(format!("could not create directory {:?}: {}", path, e)))?;
+    }
+    Ok(())
+}
+
+pub fn init<P: AsRef<Path>>(path: Option<P>, settings: Settings) {
+
+    let colors = ::fern::colors::ColoredLevelConfig::new()
+        .trace(Color::Blue)
+        .debug(Color::BrightBlue)
+        .info(Color::BrightGreen)
+        .warn(Color::BrightYellow)
+        .error(Color::BrightRed);
+
+    fn white <T: Display> (msg: T, colored: bool) -> String {
+        if colored {
+            format!("{}", format!("{}", msg).white())
+        } else {
+            format!("{}", msg)
+        }   
+    }
+
+    macro_rules! format_msg {
+        ($colored:expr) => {
+            move |out, message, record| {
+                if settings.format == Format::Json {
+                    return out.finish(format_args!("{}", json_line(message, record)));
+                }
+                out.finish(format_args!(
+                    "{} {}:{} {}{}",
+                    white(::chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), $colored),
+                    if $colored {
+                        format!("{}", colors.color(record.level()))
+                    } else {
+                        format!("{}", record.level())
+                    },
+                    if settings.everything {
+                        white(format!(" {}", record.target()), $colored)
+                    } else { "".to_owned() },
+                    message,
+                    text_fields()
+                ))
+            }
+        }
+    }
+
+    filter::configure(settings.level, settings.everything);
+
+    // CMD Logging (colored, with user specified level and the per target levels)
+    let mut dispatch = Dispatch::new()
+        .filter(filter::enabled)
+        .level(LevelFilter::Trace)
+        .chain(Dispatch::new()
+            .format( format_msg!(true) )
+            .chain(::std::io::stdout())
+        );
+
+    // File Logging (uncolored, only info or worse)
+    if let Some(path) = path {
+        let path = path.as_ref();
+        match ::std::fs::create_dir_all(path) {
+            Err(err) => error!("{}", err),
+            _ => match RotatingFile::new(path, "", settings.rotation) {
+                Ok(file) => {
+                    dispatch = dispatch.chain(Dispatch::new()
+                        .format(format_msg!(false))
+                        .chain(Box::new(file) as Box<::std::io::Write + Send>)
+                    );
+                },
+                Err(err) => error!("{}", err)
+            }
+        };
+    }
+
+
+    if let Err(err) = rotate::reopen_on_sighup() {
+        error!("could not listen for SIGHUP to reopen log files: {}", err);
+    }
+
+    if dispatch.apply().is_err() {
+        panic!("A logger instance was already set");
+    }
+
+    // The levels can be raised from Lua later on, see `set_level`
+    log::set_max_level(filter::max_level());
+}
\ No newline at end of file
@@ -0,0 +1,406 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, atomic::{AtomicUsize, Ordering}},
+    thread,
+};
+use chrono::{DateTime, Local, Timelike};
+use xz2::read::XzEncoder;
+use crate::{error::Error, Result};
+
+/// Bumped on every SIGHUP, so that each open log file notices it has to be reopened
+static REOPEN_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Held while compressing and pruning, so a prune never removes a file being compressed
+    static ref COMPRESSING: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Hourly,
+    Daily,
+}
+
+/// When and how log files are rotated, from the `log_rotation` general setting
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub interval: Option<Interval>,
+    pub max_files: Option<usize>,
+    pub compress: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+struct RotationConfig {
+    max_size: Option<Size>,
+    interval: Option<Interval>,
+    max_files: Option<usize>,
+    #[serde(default)]
+    compress: bool,
+}
+
+/// Parses sizes such as `1048576`, `"512K"`, `"10MB"` or `"1G"`
+fn parse_size (size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let size = size.trim_end_matches('B');
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, ""),
+    };
+    let multiplier = match unit.trim() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().map(|number| number * multiplier)
+}
+
+impl Rotation {
+    pub fn from_settings (value: &serde_json::Value) -> Result<Self> {
+        let config: RotationConfig = serde_json::from_value(value.clone())?;
+        let max_size = match config.max_size {
+            Some(Size::Bytes(bytes)) => Some(bytes),
+            Some(Size::Text(text)) => Some(parse_size(&text)
+                .ok_or_else(|| format_err!("invalid log_rotation.max_size {:?}", text))?),
+            None => None,
+        };
+
+        Ok(Rotation {
+            max_size,
+            interval: config.interval,
+            max_files: config.max_files,
+            compress: config.compress,
+        })
+    }
+
+    fn is_enabled (&self) -> bool {
+        self.max_size.is_some() || self.interval.is_some()
+    }
+}
+
+/// Makes every `RotatingFile` reopen its file after a SIGHUP, so that
+/// an external logrotate can move the files away
+#[cfg(target_family = "unix")]
+pub fn reopen_on_sighup () -> Result<()> {
+    unsafe {
+        signal_hook::register(signal_hook::SIGHUP, || {
+            REOPEN_GENERATION.fetch_add(1, Ordering::SeqCst);
+        })
+    }.map(|_| ()).map_err(Error::from)
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn reopen_on_sighup () -> Result<()> {
+    Ok(())
+}
+
+/// A log file in `dir` that moves on to a new file once it grows past
+/// `max_size` or the `interval` ends, keeping at most `max_files` of them
+pub struct RotatingFile {
+    dir: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: DateTime<Local>,
+    generation: usize,
+    /// Whether the last write ended a record, the only place where the file may change
+    at_boundary: bool,
+}
+
+impl RotatingFile {
+    pub fn new (dir: &Path, prefix: &str, rotation: Rotation) -> Result<Self> {
+        super::ensure_log_dir(dir)?;
+        let (path, file) = open_new(dir, prefix)?;
+        let rotating = RotatingFile {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_string(),
+            rotation,
+            path,
+            file,
+            size: 0,
+            opened: Local::now(),
+            generation: REOPEN_GENERATION.load(Ordering::SeqCst),
+            at_boundary: true,
+        };
+        if let Some(max_files) = rotating.rotation.max_files {
+            let _lock = COMPRESSING.lock();
+            prune(&rotating.dir, &rotating.prefix, max_files)
+                .map_err(|e| format_err!("could not remove old log files in {:?}: {}", rotating.dir, e))?;
+        }
+        Ok(rotating)
+    }
+
+    fn interval_ended (&self, now: &DateTime<Local>) -> bool {
+        match self.rotation.interval {
+            Some(Interval::Hourly) => now.date() != self.opened.date() || now.hour() != self.opened.hour(),
+            Some(Interval::Daily) => now.date() != self.opened.date(),
+            None => false,
+        }
+    }
+
+    fn should_rotate (&self, incoming: usize) -> bool {
+        let too_big = match self.rotation.max_size {
+            Some(max_size) => self.size > 0 && self.size + incoming as u64 > max_size,
+            None => false,
+        };
+        too_big || self.interval_ended(&Local::now())
+    }
+
+    fn rotate (&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let (path, file) = open_new(&self.dir, &self.prefix).map_err(to_io_error)?;
+        let old = ::std::mem::replace(&mut self.path, path);
+        self.file = file;
+        self.size = 0;
+        self.opened = Local::now();
+
+        let (dir, prefix, max_files) = (self.dir.clone(), self.prefix.clone(), self.rotation.max_files);
+        if self.rotation.compress {
+            // Pruning waits for the compression, which would otherwise lose its file
+            thread::spawn(move || {
+                let _lock = COMPRESSING.lock();
+                if old.exists() {
+                    if let Err(err) = compress(&old) {
+                        report(&format!("could not compress log file {:?}", old), err);
+                    }
+                }
+                if let Some(max_files) = max_files {
+                    if let Err(err) = prune(&dir, &prefix, max_files) {
+                        report(&format!("could not remove old log files in {:?}", dir), err);
+                    }
+                }
+            });
+        } else if let Some(max_files) = max_files {
+            let _lock = COMPRESSING.lock();
+            if let Err(err) = prune(&dir, &prefix, max_files) {
+                report(&format!("could not remove old log files in {:?}", dir), err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reopens the current path, which an external tool may have moved away
+    fn reopen (&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = self.file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write (&mut self, buf: &[u8]) -> io::Result<usize> {
+        let generation = REOPEN_GENERATION.load(Ordering::SeqCst);
+        if generation != self.generation {
+            self.generation = generation;
+            self.reopen()?;
+        }
+
+        // A record may take several writes, which must all go to the same file
+        if self.at_boundary && self.rotation.is_enabled() && self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_boundary = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush (&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The timestamp and counter in the name of one of the log files of `prefix`, which order them
+/// chronologically. Compressed files keep the key of the file they were made of.
+fn file_key (prefix: &str, path: &Path) -> Option<(String, u32)> {
+    let name = path.file_name()?.to_str()?;
+    if !name.starts_with(prefix) {
+        return None;
+    }
+    let rest = &name[prefix.len()..];
+    let stem = if rest.ends_with(".log.xz") {
+        &rest[..rest.len() - ".log.xz".len()]
+    } else if rest.ends_with(".log") {
+        &rest[..rest.len() - ".log".len()]
+    } else {
+        return None;
+    };
+
+    // Log files of other prefixes (such as the access log) start with a letter
+    const TIMESTAMP_LEN: usize = 20;
+    if stem.len() < TIMESTAMP_LEN || !stem.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let (timestamp, counter) = stem.split_at(TIMESTAMP_LEN);
+    let counter = match counter {
+        "" => 0,
+        counter if counter.starts_with('_') => counter[1..].parse().ok()?,
+        _ => return None,
+    };
+    Some((timestamp.to_string(), counter))
+}
+
+/// The one place where rotation failures go to stderr: they happen while the logger
+/// is writing, or on a thread of their own, so they can't be logged to the file itself
+fn report (what: &str, err: io::Error) {
+    eprintln!("{}: {}", what, err);
+}
+
+/// Removes the oldest log files of `prefix` in `dir`, keeping `max_files` including the current one.
+/// Goes on past files that can't be removed and returns the last error.
+fn prune (dir: &Path, prefix: &str, max_files: usize) -> io::Result<()> {
+    let mut files: Vec<((String, u32), PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| file_key(prefix, &path).map(|key| (key, path)))
+        .collect();
+    files.sort();
+
+    let mut keys: Vec<&(String, u32)> = files.iter().map(|(key, _)| key).collect();
+    keys.dedup();
+    if keys.len() <= max_files {
+        return Ok(());
+    }
+    let oldest_kept = keys[keys.len() - max_files].clone();
+
+    let mut result = Ok(());
+    for (key, path) in files.iter() {
+        if key < &oldest_kept {
+            if let Err(err) = fs::remove_file(path) {
+                result = Err(io::Error::new(err.kind(), format!("{:?}: {}", path, err)));
+            }
+        }
+    }
+    result
+}
+
+fn to_io_error (err: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// Opens a new timestamped file, adding a counter if one with that name already exists
+fn open_new (dir: &Path, prefix: &str) -> Result<(PathBuf, File)> {
+    let now = Local::now().format("%Y_%m_%d__%H_%M_%S").to_string();
+
+    let mut path = dir.join(format!("{}{}.log", prefix, now));
+    let mut counter = 1;
+    while path.exists() {
+        path = dir.join(format!("{}{}_{}.log", prefix, now, counter));
+        counter += 1;
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map(|file| (path.clone(), file))
+        .map_err(|e| Error::from(format!("could not log to {:?}: {}", &path, e)))
+}
+
+/// Compresses `path` into `path.xz` and removes the original
+fn compress (path: &Path) -> io::Result<()> {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".xz");
+
+    let input = File::open(path)?;
+    let mut output = File::create(&target)?;
+    io::copy(&mut XzEncoder::new(input, 6), &mut output)?;
+    output.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes () {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("512K"), Some(512 * 1024));
+        assert_eq!(parse_size("10MB"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("lots"), None);
+    }
+
+    #[test]
+    fn rotates_by_size () {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = Rotation { max_size: Some(10), interval: None, max_files: Some(2), compress: false };
+        let mut file = RotatingFile::new(dir.path(), "", rotation).unwrap();
+
+        for _ in 0..4 {
+            file.write_all(b"012345678\n").unwrap();
+        }
+        file.flush().unwrap();
+
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn rotates_between_records () {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = Rotation { max_size: Some(10), interval: None, max_files: None, compress: false };
+        let mut file = RotatingFile::new(dir.path(), "", rotation).unwrap();
+
+        // Written in pieces, as `write!` does, each record must stay whole
+        for _ in 0..3 {
+            file.write_all(b"0123").unwrap();
+            file.write_all(b"456789").unwrap();
+            file.write_all(b"\n").unwrap();
+        }
+        file.flush().unwrap();
+
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 3);
+        for path in files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "0123456789\n");
+        }
+    }
+
+    #[test]
+    fn prunes_by_timestamp_and_counter () {
+        let dir = tempfile::tempdir().unwrap();
+        let names = [
+            "2019_01_01__00_00_00.log.xz",
+            "2019_01_01__00_00_00_2.log",
+            "2019_01_01__00_00_00_10.log",
+            "2019_01_01__00_00_00_10.log.xz",
+            "2019_01_02__00_00_00.log",
+            "access2019_01_01__00_00_00.log",
+        ];
+        for name in names.iter() {
+            File::create(dir.path().join(name)).unwrap();
+        }
+
+        prune(dir.path(), "", 2).unwrap();
+
+        let mut left: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec![
+            "2019_01_01__00_00_00_10.log",
+            "2019_01_01__00_00_00_10.log.xz",
+            "2019_01_02__00_00_00.log",
+            "access2019_01_01__00_00_00.log",
+        ]);
+    }
+}
@@ -41,9 +41,15 @@ fn main() {
             .multiple(true))
         .get_matches();
 
-    match torchbear_lib::ApplicationBuilder::new()
-        .log_level(*matches.value_of("log").map(|l| levels.get(&l).unwrap()).unwrap())
-        .log_everything(matches.value_of("log scope").unwrap() == "everything")
+    let mut builder = torchbear_lib::ApplicationBuilder::new();
+    builder.log_everything(matches.value_of("log scope").unwrap() == "everything");
+
+    // Without --log, the level from torchbear.scl or the default applies
+    if matches.occurrences_of("log") > 0 {
+        builder.log_level(*matches.value_of("log").map(|l| levels.get(&l).unwrap()).unwrap());
+    }
+
+    match builder
         .start(matches.values_of("interpreter").map(|val| val.map(|s| s.to_string()).collect())) {
         Ok(_) => {},
         Err(e) => {
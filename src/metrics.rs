@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Mutex,
+};
+use crate::Result;
+
+/// Histogram buckets in seconds, fit for request latencies
+pub const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name (self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+enum Series {
+    Value(f64),
+    Histogram { counts: Vec<u64>, sum: f64, count: u64 },
+}
+
+/// A metric with all its label combinations
+struct Family {
+    help: String,
+    kind: Kind,
+    labels: Vec<String>,
+    buckets: Vec<f64>,
+    series: HashMap<Vec<String>, Series>,
+}
+
+/// The metrics of the process, shared by every Lua VM
+pub struct Registry {
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+pub const HTTP_REQUESTS: &str = "torchbear_http_requests_total";
+pub const HTTP_DURATION: &str = "torchbear_http_request_duration_seconds";
+pub const HTTP_IN_FLIGHT: &str = "torchbear_http_requests_in_flight";
+pub const LUA_VMS: &str = "torchbear_lua_vms";
+pub const LUA_VMS_CREATED: &str = "torchbear_lua_vms_created_total";
+pub const LUA_MEMORY: &str = "torchbear_lua_memory_bytes";
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = {
+        let registry = Registry::new();
+        registry.register_builtin().expect("invalid built-in metrics");
+        registry
+    };
+}
+
+fn is_valid_name (name: &str, colons: bool) -> bool {
+    let mut chars = name.chars();
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':');
+    match chars.next() {
+        Some(first) if valid(first) && !first.is_ascii_digit() => chars.all(valid),
+        _ => false,
+    }
+}
+
+fn format_value (value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_owned() } else { "-Inf".to_owned() }
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape (value: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_labels (names: &[String], values: &[String], extra: Option<(&str, &str)>) -> String {
+    let mut pairs: Vec<String> = names.iter().zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value, true)))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", name, value));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+impl Registry {
+    pub fn new () -> Self {
+        Registry { families: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn register_builtin (&self) -> Result<()> {
+        let labels = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        self.register(HTTP_REQUESTS, "HTTP requests handled", Kind::Counter, &labels(&["method", "route", "status"]), None)?;
+        self.register(HTTP_DURATION, "Time to handle HTTP requests", Kind::Histogram, &labels(&["route", "status"]), None)?;
+        self.register(HTTP_IN_FLIGHT, "HTTP requests being handled", Kind::Gauge, &[], None)?;
+        self.register(LUA_VMS, "Lua VMs alive", Kind::Gauge, &[], None)?;
+        self.register(LUA_VMS_CREATED, "Lua VMs created", Kind::Counter, &[], None)?;
+        self.register(LUA_MEMORY, "Memory used by each Lua VM, as of its last request", Kind::Gauge, &labels(&["vm"]), None)?;
+        Ok(())
+    }
+
+    /// Declares a metric, which may be declared again with the same kind and labels,
+    /// as every VM runs the same code
+    pub fn register (&self, name: &str, help: &str, kind: Kind, labels: &[String], buckets: Option<&[f64]>) -> Result<()> {
+        if !is_valid_name(name, true) {
+            return Err(format_err!("invalid metric name {:?}", name));
+        }
+        if let Some(label) = labels.iter().find(|label| !is_valid_name(label, false) || label.as_str() == "le") {
+            return Err(format_err!("invalid label name {:?} for metric {}", label, name));
+        }
+
+        let mut buckets = buckets.unwrap_or(DEFAULT_BUCKETS).to_vec();
+        buckets.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+        buckets.dedup();
+
+        let mut families = self.families.lock().unwrap();
+        if let Some(family) = families.get(name) {
+            if family.kind != kind || family.labels != labels {
+                return Err(format_err!("metric {} is already registered as a {} with labels {:?}", name, family.kind.name(), family.labels));
+            }
+            return Ok(());
+        }
+
+        families.insert(name.to_owned(), Family {
+            help: help.to_owned(),
+            kind,
+            labels: labels.to_vec(),
+            buckets: if kind == Kind::Histogram { buckets } else { vec![] },
+            series: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    fn update<F> (&self, name: &str, kind: Kind, labels: &[String], f: F) -> Result<()>
+        where F: FnOnce(&mut Series, &[f64])
+    {
+        let mut families = self.families.lock().unwrap();
+        let family = families.get_mut(name).ok_or_else(|| format_err!("metric {} is not registered", name))?;
+        if family.kind != kind {
+            return Err(format_err!("metric {} is a {}", name, family.kind.name()));
+        }
+        if family.labels.len() != labels.len() {
+            return Err(format_err!("metric {} takes the labels {:?}", name, family.labels));
+        }
+
+        let buckets = &family.buckets;
+        let series = family.series.entry(labels.to_vec()).or_insert_with(|| match kind {
+            Kind::Histogram => Series::Histogram { counts: vec![0; buckets.len()], sum: 0.0, count: 0 },
+            _ => Series::Value(0.0),
+        });
+        f(series, buckets);
+        Ok(())
+    }
+
+    pub fn inc (&self, name: &str, labels: &[String], value: f64) -> Result<()> {
+        if value < 0.0 {
+            return Err(format_err!("counter {} can only be increased", name));
+        }
+        self.update(name, Kind::Counter, labels, |series, _| {
+            if let Series::Value(current) = series { *current += value }
+        })
+    }
+
+    pub fn add (&self, name: &str, labels: &[String], value: f64) -> Result<()> {
+        self.update(name, Kind::Gauge, labels, |series, _| {
+            if let Series::Value(current) = series { *current += value }
+        })
+    }
+
+    pub fn set (&self, name: &str, labels: &[String], value: f64) -> Result<()> {
+        self.update(name, Kind::Gauge, labels, |series, _| {
+            if let Series::Value(current) = series { *current = value }
+        })
+    }
+
+    pub fn observe (&self, name: &str, labels: &[String], value: f64) -> Result<()> {
+        self.update(name, Kind::Histogram, labels, |series, buckets| {
+            if let Series::Histogram { counts, sum, count } = series {
+                for (bucket, bucket_count) in buckets.iter().zip(counts.iter_mut()) {
+                    if value <= *bucket {
+                        *bucket_count += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        })
+    }
+
+    /// Drops one label combination, such as the gauge of a VM that was closed
+    pub fn remove (&self, name: &str, labels: &[String]) {
+        if let Some(family) = self.families.lock().unwrap().get_mut(name) {
+            family.series.remove(labels);
+        }
+    }
+
+    /// The current value of a counter or gauge
+    pub fn get (&self, name: &str, labels: &[String]) -> Option<f64> {
+        let families = self.families.lock().unwrap();
+        match families.get(name)?.series.get(labels)? {
+            Series::Value(value) => Some(*value),
+            Series::Histogram { .. } => None,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render (&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, escape(&family.help, false));
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind.name());
+
+            let mut series: Vec<_> = family.series.iter().collect();
+            series.sort_by(|a, b| a.0.cmp(b.0));
+
+            for (values, series) in series {
+                match series {
+                    Series::Value(value) => {
+                        let _ = writeln!(out, "{}{} {}", name, format_labels(&family.labels, values, None), format_value(*value));
+                    },
+                    Series::Histogram { counts, sum, count } => {
+                        for (bucket, bucket_count) in family.buckets.iter().zip(counts) {
+                            let le = format_value(*bucket);
+                            let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(&family.labels, values, Some(("le", &le))), bucket_count);
+                        }
+                        let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(&family.labels, values, Some(("le", "+Inf"))), count);
+                        let _ = writeln!(out, "{}_sum{} {}", name, format_labels(&family.labels, values, None), format_value(*sum));
+                        let _ = writeln!(out, "{}_count{} {}", name, format_labels(&family.labels, values, None), count);
+                    },
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels (values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn text_format () {
+        let registry = Registry::new();
+        registry.register("requests_total", "Requests", Kind::Counter, &labels(&["status"]), None).unwrap();
+        registry.register("latency_seconds", "Latency", Kind::Histogram, &[], Some(&[0.1, 1.0])).unwrap();
+
+        registry.inc("requests_total", &labels(&["200"]), 1.0).unwrap();
+        registry.inc("requests_total", &labels(&["200"]), 2.0).unwrap();
+        registry.observe("latency_seconds", &[], 0.5).unwrap();
+        registry.observe("latency_seconds", &[], 0.05).unwrap();
+
+        assert!(registry.inc("requests_total", &[], 1.0).is_err());
+        assert!(registry.set("requests_total", &labels(&["200"]), 1.0).is_err());
+        assert!(registry.register("requests_total", "Requests", Kind::Gauge, &[], None).is_err());
+
+        assert_eq!(registry.render(), "\
+# HELP latency_seconds Latency
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"1\"} 2
+latency_seconds_bucket{le=\"+Inf\"} 2
+latency_seconds_sum 0.55
+latency_seconds_count 2
+# HELP requests_total Requests
+# TYPE requests_total counter
+requests_total{status=\"200\"} 3
+");
+    }
+}